@@ -0,0 +1,328 @@
+use serde_json::{json, Value};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use ::sun2000::device::connect_modbus;
+use ::sun2000::retry::ModbusConfig;
+use ::sun2000::settings::*;
+
+use super::Result;
+
+const MODEL_NAME_REGISTER: (u16, u16) = (30000, 15);
+const SERIAL_NUMBER_REGISTER: (u16, u16) = (30015, 10);
+const GRID_CODE_SETTING: &str = "grid_code";
+
+/// Settings of a device, as saved in the backup file
+#[derive(Clone, Debug, PartialEq)]
+struct SettingsBackup {
+    created: String,
+    model: String,
+    serial_number: String,
+    /// raw registers by setting name
+    settings: Vec<(String, Vec<u16>)>,
+}
+
+impl SettingsBackup {
+    fn get(&self, name: &str) -> Option<&Vec<u16>> {
+        self.settings.iter().find(|(n, _)| n == name).map(|(_, r)| r)
+    }
+
+    fn to_json(&self) -> Value {
+        let settings: Vec<Value> = self
+            .settings
+            .iter()
+            .map(|(name, registers)| {
+                let setting = find_setting(name);
+                json!({
+                    "name": name,
+                    "address": setting.map(|s| s.addr),
+                    "registers": registers,
+                    "value": setting.and_then(|s| s.value(registers)),
+                    "unit": setting.and_then(|s| s.unit),
+                })
+            })
+            .collect();
+        json!({
+            "version": SETTINGS_BACKUP_VERSION,
+            "created": self.created,
+            "model": self.model,
+            "serial_number": self.serial_number,
+            "settings": settings,
+        })
+    }
+
+    fn from_json(v: &Value) -> Result<SettingsBackup> {
+        let version = v["version"].as_u64().ok_or("not a settings backup: no version")?;
+        if version > SETTINGS_BACKUP_VERSION as u64 {
+            return Err(format!("unsupported backup version {} (max {})", version, SETTINGS_BACKUP_VERSION).into());
+        }
+        let text = |key: &str| v[key].as_str().unwrap_or_default().to_string();
+        let mut settings = vec![];
+        for s in v["settings"].as_array().ok_or("no settings in the backup")? {
+            let name = s["name"].as_str().ok_or("setting without a name")?;
+            let registers = s["registers"]
+                .as_array()
+                .ok_or_else(|| format!("{}: no registers", name))?
+                .iter()
+                .map(|r| r.as_u64().filter(|r| *r <= u16::MAX as u64).map(|r| r as u16))
+                .collect::<Option<Vec<u16>>>()
+                .ok_or_else(|| format!("{}: invalid registers", name))?;
+            settings.push((name.to_string(), registers));
+        }
+        Ok(SettingsBackup {
+            created: text("created"),
+            model: text("model"),
+            serial_number: text("serial_number"),
+            settings,
+        })
+    }
+
+    fn load(path: &str) -> Result<SettingsBackup> {
+        let data = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let v: Value = serde_json::from_str(&data).map_err(|e| format!("{}: {}", path, e))?;
+        SettingsBackup::from_json(&v)
+    }
+}
+
+fn registers_to_text(data: &[u16]) -> String {
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_be_bytes()).filter(|b| *b != 0).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+async fn read_registers(ctx: &mut Context, (addr, len): (u16, u16), read_timeout: Duration) -> Result<Vec<u16>> {
+    match timeout(read_timeout, ctx.read_holding_registers(addr, len)).await {
+        Ok(res) => Ok(res?),
+        Err(_) => Err(format!("reading registers {}-{} timed out", addr, addr + len - 1).into()),
+    }
+}
+
+/// Reads all the settings the inverter has
+async fn read_settings(ctx: &mut Context, read_timeout: Duration) -> Vec<(String, Vec<u16>)> {
+    let mut settings = vec![];
+    for setting in SETTINGS {
+        match read_setting(ctx, setting, read_timeout).await {
+            Ok(registers) => settings.push((setting.name.to_string(), registers)),
+            //eg. the battery settings without a battery
+            Err(e) => println!("{}: not readable, skipped: {}", setting.name, e),
+        }
+    }
+    settings
+}
+
+/// Connects to the inverter and reads its identification and all the settings it has
+async fn read_device(host: SocketAddr, slave: u8, modbus: &ModbusConfig) -> Result<(Context, SettingsBackup)> {
+    let mut ctx = connect_modbus(host, Slave(slave), modbus.connect_timeout).await?;
+    let model = read_registers(&mut ctx, MODEL_NAME_REGISTER, modbus.read_timeout).await?;
+    let serial_number = read_registers(&mut ctx, SERIAL_NUMBER_REGISTER, modbus.read_timeout).await?;
+    let backup = SettingsBackup {
+        created: chrono::Local::now().to_rfc3339(),
+        model: registers_to_text(&model),
+        serial_number: registers_to_text(&serial_number),
+        settings: read_settings(&mut ctx, modbus.read_timeout).await,
+    };
+    Ok((ctx, backup))
+}
+
+/// Saves the settings of the inverter to `output` (defaults to a name with the serial number and time)
+pub async fn backup(host: SocketAddr, slave: u8, modbus: &ModbusConfig, output: Option<String>) -> Result<()> {
+    let (_, device) = read_device(host, slave, modbus).await?;
+    let path = output.unwrap_or_else(|| {
+        format!("sun2000_settings_{}_{}.json", device.serial_number, chrono::Local::now().format("%Y-%m-%d_%H%M%S"))
+    });
+    let data = serde_json::to_string_pretty(&device.to_json())?;
+    std::fs::write(&path, data).map_err(|e| format!("{}: {}", path, e))?;
+    println!("saved {} settings of {} ({}) to {}", device.settings.len(), device.model, device.serial_number, path);
+    Ok(())
+}
+
+/// Setting with its registers in the backup and on the device
+type Difference<'a> = (&'static Setting, Option<&'a Vec<u16>>, Option<&'a Vec<u16>>);
+
+/// Settings which differ between the backup and the device
+fn differences<'a>(saved: &'a SettingsBackup, current: &'a SettingsBackup) -> Vec<Difference<'a>> {
+    SETTINGS
+        .iter()
+        .map(|s| (s, saved.get(s.name), current.get(s.name)))
+        .filter(|(_, saved, current)| saved != current)
+        .collect()
+}
+
+/// Differences to write: the saved settings, only the `only` ones when given
+fn restorable<'a>(saved: &'a SettingsBackup, current: &'a SettingsBackup, only: &Option<Vec<String>>) -> Vec<Difference<'a>> {
+    differences(saved, current)
+        .into_iter()
+        .filter(|(s, saved, _)| saved.is_some() && only.iter().all(|names| names.iter().any(|n| n == s.name)))
+        .collect()
+}
+
+fn print_identity(saved: &SettingsBackup, current: &SettingsBackup) {
+    println!("backup:  {} ({}), created {}", saved.model, saved.serial_number, saved.created);
+    println!("device:  {} ({})", current.model, current.serial_number);
+    if saved.serial_number != current.serial_number {
+        println!("warning: the backup was made on another device");
+    }
+}
+
+fn display(setting: &Setting, registers: Option<&Vec<u16>>) -> String {
+    match registers {
+        Some(r) => setting.display(r),
+        None => "-".to_string(),
+    }
+}
+
+/// Shows the settings which differ between the backup `file` and the inverter
+pub async fn diff(host: SocketAddr, slave: u8, modbus: &ModbusConfig, file: &str) -> Result<()> {
+    let saved = SettingsBackup::load(file)?;
+    let (_, current) = read_device(host, slave, modbus).await?;
+    print_identity(&saved, &current);
+    let changes = differences(&saved, &current);
+    if changes.is_empty() {
+        println!("no differences");
+        return Ok(());
+    }
+    println!("{:<48} {:<24} device", "setting", "backup");
+    for (setting, saved, current) in &changes {
+        println!("{:<48} {:<24} {}", setting.name, display(setting, *saved), display(setting, *current));
+    }
+    Ok(())
+}
+
+/// Asks for a confirmation on the terminal
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    let _ = std::io::stdout().flush();
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer).is_ok() && matches!(answer.trim(), "y" | "Y" | "yes")
+}
+
+/// Writes the settings of the backup `file` which differ on the inverter, only the `only` ones
+/// (comma separated names) when given
+pub async fn restore(
+    host: SocketAddr,
+    slave: u8,
+    modbus: &ModbusConfig,
+    file: &str,
+    only: Option<String>,
+    yes: bool,
+    force: bool,
+) -> Result<()> {
+    let saved = SettingsBackup::load(file)?;
+    let only: Option<Vec<String>> = only.map(|o| o.split(',').map(|n| n.trim().to_string()).filter(|n| !n.is_empty()).collect());
+    if let Some(names) = &only {
+        if let Some(unknown) = names.iter().find(|n| find_setting(n).is_none()) {
+            return Err(format!("unknown setting: {:?}", unknown).into());
+        }
+    }
+    let (mut ctx, current) = read_device(host, slave, modbus).await?;
+    print_identity(&saved, &current);
+    if saved.serial_number != current.serial_number && !force {
+        return Err("refusing to restore a backup of another device (use --force)".into());
+    }
+
+    let writes = restorable(&saved, &current, &only);
+    if writes.is_empty() {
+        println!("nothing to restore, the device settings match the backup");
+        return Ok(());
+    }
+    for (setting, saved, current) in &writes {
+        println!("{:<48} {:<24} -> {}", setting.name, display(setting, *current), display(setting, *saved));
+    }
+    if !yes && !confirm(&format!("write {} settings to {}?", writes.len(), current.serial_number)) {
+        println!("aborted");
+        return Ok(());
+    }
+
+    //the grid code is the first one
+    let mut failed = write_settings(&mut ctx, &writes[..1], modbus.read_timeout).await;
+    let mut total = writes.len();
+    if writes[0].0.name == GRID_CODE_SETTING && failed == 0 {
+        //the new grid code has reset the grid dependent settings, they are compared again
+        println!("reading the settings again after the grid code change");
+        let device = SettingsBackup {
+            settings: read_settings(&mut ctx, modbus.read_timeout).await,
+            ..current.clone()
+        };
+        let rest: Vec<Difference> = restorable(&saved, &device, &only)
+            .into_iter()
+            .filter(|(s, _, _)| s.name != GRID_CODE_SETTING)
+            .collect();
+        for (setting, saved, current) in &rest {
+            println!("{:<48} {:<24} -> {}", setting.name, display(setting, *current), display(setting, *saved));
+        }
+        total = 1 + rest.len();
+        failed += write_settings(&mut ctx, &rest, modbus.read_timeout).await;
+    } else {
+        failed += write_settings(&mut ctx, &writes[1..], modbus.read_timeout).await;
+    }
+    if failed > 0 {
+        return Err(format!("{} of {} settings were not restored", failed, total).into());
+    }
+    Ok(())
+}
+
+/// Writes the saved registers of the settings, returns the number of the failed ones
+async fn write_settings(ctx: &mut Context, writes: &[Difference<'_>], write_timeout: Duration) -> usize {
+    let mut failed = 0;
+    for (setting, saved, _) in writes {
+        let saved = saved.map(|r| r.as_slice()).unwrap_or_default();
+        match write_setting(ctx, setting, saved, write_timeout).await {
+            Ok(()) => println!("{}: restored", setting.name),
+            Err(e) => {
+                println!("{}: write error: {}", setting.name, e);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backup(settings: &[(&str, &[u16])]) -> SettingsBackup {
+        SettingsBackup {
+            created: "2024-05-01T12:00:00+02:00".to_string(),
+            model: "SUN2000-10KTL-M1".to_string(),
+            serial_number: "HV2150012345".to_string(),
+            settings: settings.iter().map(|(n, r)| (n.to_string(), r.to_vec())).collect(),
+        }
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let saved = backup(&[("grid_code", &[42]), ("storage_power_limit_grid_tied_point", &[0xffff, 0xfc18])]);
+        let json = saved.to_json();
+        assert_eq!(json["version"], SETTINGS_BACKUP_VERSION);
+        //the value is informative, only the registers are restored
+        assert_eq!(json["settings"][1]["value"], -1000.0);
+        assert_eq!(json["settings"][1]["unit"], "W");
+        assert_eq!(SettingsBackup::from_json(&json).unwrap(), saved);
+    }
+
+    #[test]
+    fn invalid_backups() {
+        let mut json = backup(&[("grid_code", &[42])]).to_json();
+        json["version"] = json!(SETTINGS_BACKUP_VERSION + 1);
+        assert!(SettingsBackup::from_json(&json).unwrap_err().to_string().contains("unsupported backup version"));
+        assert!(SettingsBackup::from_json(&json!({"settings": []})).is_err());
+        let registers = json!({"version": 1, "settings": [{"name": "grid_code", "registers": [65536]}]});
+        assert!(SettingsBackup::from_json(&registers).is_err());
+    }
+
+    #[test]
+    fn setting_differences() {
+        let saved = backup(&[("grid_code", &[42]), ("power_factor", &[1000]), ("time_zone", &[60])]);
+        let current = backup(&[("grid_code", &[42]), ("power_factor", &[950]), ("storage_working_mode", &[2])]);
+        let names = |d: Vec<Difference>| d.iter().map(|(s, _, _)| s.name).collect::<Vec<_>>();
+        assert_eq!(names(differences(&saved, &current)), ["power_factor", "time_zone", "storage_working_mode"]);
+        //a setting not in the backup is not restored
+        assert_eq!(names(restorable(&saved, &current, &None)), ["power_factor", "time_zone"]);
+        assert_eq!(names(restorable(&saved, &current, &Some(vec!["time_zone".to_string()]))), ["time_zone"]);
+        assert!(differences(&saved, &saved).is_empty());
+    }
+}
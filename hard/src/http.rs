@@ -0,0 +1,622 @@
+use simplelog::*;
+
+use hyper::body::Bytes;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_util::sync::CancellationToken;
+
+use ::sun2000::dump::*;
+use ::sun2000::energy::*;
+use ::sun2000::ephemeris::*;
+use ::sun2000::identity::*;
+use ::sun2000::params::*;
+use ::sun2000::shared::*;
+use ::sun2000::*;
+
+use super::Result;
+
+pub const HTTP_SSE_KEEPALIVE_SECS: u64 = 15; //secs between keep-alive comments on idle event streams
+pub const HTTP_CURVE_RESOLUTION_SECS: i64 = 60; //power curve points are averaged over this period
+
+const DASHBOARD_HTML: &str = include_str!("dashboard.html");
+
+/// Embedded HTTP server exposing the live inverter state as JSON
+pub struct HttpServer {
+    pub name: String,
+    pub listen: SocketAddr,
+    pub shared: Shared,
+    pub events: broadcast::Sender<Sun2000Event>,
+}
+
+#[derive(Clone)]
+struct HttpContext {
+    shared: Shared,
+    events: broadcast::Sender<Sun2000Event>,
+    cancel: CancellationToken,
+}
+
+impl HttpServer {
+    pub async fn worker(&self, cancel: CancellationToken) -> Result<()> {
+        info!("<i>{}</>: listening on <u>http://{}/</>", self.name, self.listen);
+        let ctx = HttpContext {
+            shared: self.shared.clone(),
+            events: self.events.clone(),
+            //event streams never end by themselves, they have to be closed on shutdown
+            cancel: cancel.child_token(),
+        };
+        let make_svc = make_service_fn(move |_conn| {
+            let ctx = ctx.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req| handle(req, ctx.clone())))
+            }
+        });
+
+        Server::try_bind(&self.listen)?
+            .serve(make_svc)
+            .with_graceful_shutdown(async move { cancel.cancelled().await })
+            .await?;
+
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+async fn handle(req: Request<Body>, ctx: HttpContext) -> std::result::Result<Response<Body>, Infallible> {
+    debug!("http: {} {}", req.method(), req.uri());
+    if req.method() != Method::GET {
+        return Ok(error_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+    let shared = ctx.shared;
+    let body = match req.uri().path() {
+        "/" | "/index.html" => {
+            return Ok(Response::builder()
+                .header("Content-Type", "text/html; charset=utf-8")
+                .body(Body::from(DASHBOARD_HTML))
+                .unwrap())
+        }
+        "/api/today" => match tokio::task::spawn_blocking(today_curve_json).await {
+            Ok(v) => v,
+            Err(_) => return Ok(error_response(StatusCode::INTERNAL_SERVER_ERROR)),
+        },
+        "/api/events" => return Ok(event_stream(ctx.events.subscribe(), ctx.cancel)),
+        "/api/status" => status_json(&shared.read().unwrap()),
+        "/api/energy" => energy_json(&shared.read().unwrap()),
+        "/api/sun" => sun_json(&shared.read().unwrap()),
+        "/api/clock" => clock_json(&shared.read().unwrap()),
+        "/api/parameters" => parameters_json(&shared.read().unwrap()),
+        "/api/state" => state_json(&shared.read().unwrap().state),
+        "/api/alarms" => alarms_json(&shared.read().unwrap().state),
+        "/api/identity" => identity_json(&shared.read().unwrap()),
+        "/api/stats" => stats_json(&shared.read().unwrap()),
+        "/api/spans" => spans_json(&shared.read().unwrap()),
+        "/api/optimizers" => optimizers_json(&shared.read().unwrap()),
+        _ => return Ok(error_response(StatusCode::NOT_FOUND)),
+    };
+    Ok(json_response(&body))
+}
+
+/// Server-sent events stream of poll snapshots and state changes
+fn event_stream(mut rx: broadcast::Receiver<Sun2000Event>, cancel: CancellationToken) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut keepalive = tokio::time::interval(Duration::from_secs(HTTP_SSE_KEEPALIVE_SECS));
+        loop {
+            let chunk = tokio::select! {
+                ev = rx.recv() => match ev {
+                    Ok(ev) => event_json(&ev),
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("http: event stream client is too slow, skipped {} events", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => ":\n\n".to_string(),
+                _ = cancel.cancelled() => break,
+            };
+            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                //client disconnected
+                break;
+            }
+        }
+    });
+
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(body)
+        .unwrap()
+}
+
+fn event_json(ev: &Sun2000Event) -> String {
+    let (name, data) = match ev {
+        Sun2000Event::Snapshot(snapshot) => ("snapshot", snapshot_json(snapshot)),
+        Sun2000Event::StateChange { time, changes } => {
+            let mut map = serde_json::Map::new();
+            for (k, v) in changes {
+                map.insert(k.clone(), json!(strip_markup(v)));
+            }
+            ("state", json!({ "time": time.to_rfc3339(), "changes": map }))
+        }
+        Sun2000Event::Battery { time, action, minutes, soc, reason } => (
+            "battery",
+            json!({
+                "time": time.to_rfc3339(),
+                "action": action.to_string(),
+                "minutes": minutes,
+                "soc": soc,
+                "reason": reason,
+            }),
+        ),
+    };
+    format!("event: {}\ndata: {}\n\n", name, data)
+}
+
+/// Removes the paris log markup tags (like `<b><cyan>`) from descriptions
+fn strip_markup(s: &str) -> String {
+    let mut res = String::new();
+    let mut in_tag = false;
+    for c in s.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => res.push(c),
+            _ => {}
+        }
+    }
+    res
+}
+
+/// Parameters read at a time, as in the dump files
+type Sample = (chrono::DateTime<chrono::Utc>, Vec<Parameter>);
+
+/// Samples since the midnight of `now` in `tz`: the dump files (read by `read`) are per UTC day,
+/// so the local day can span two of them
+fn read_local_today<Tz: chrono::TimeZone>(now: chrono::DateTime<chrono::Utc>, tz: &Tz, read: impl Fn(i32, u32, u32) -> Vec<Sample>) -> Vec<Sample> {
+    use chrono::Datelike;
+
+    let midnight = now.with_timezone(tz).date().naive_local().and_hms(0, 0, 0);
+    let since = match tz.from_local_datetime(&midnight).earliest() {
+        Some(t) => t.with_timezone(&chrono::Utc),
+        //no midnight on a DST change day
+        None => now.date().and_hms(0, 0, 0),
+    };
+    let mut res = vec![];
+    let mut day = since.date();
+    while day <= now.date() {
+        res.extend(read(day.year(), day.month(), day.day()).into_iter().filter(|(time, _)| *time >= since));
+        day = day.succ();
+    }
+    res
+}
+
+/// Today's power curve rebuilt from the dump files
+fn today_curve_json() -> Value {
+    let now = chrono::Utc::now();
+    let date = now.with_timezone(&chrono::Local).format("%Y-%m-%d").to_string();
+    curve_json(&date, read_local_today(now, &chrono::Local, read_day))
+}
+
+/// Power curve of the `samples`, averaged over `HTTP_CURVE_RESOLUTION_SECS`
+fn curve_json(date: &str, samples: Vec<Sample>) -> Value {
+    const CURVE_PARAMS: [&str; 3] = ["active_power", "input_power", "power_meter_active_power"];
+
+    //(bucket start, number of samples, sums of CURVE_PARAMS)
+    let mut buckets: Vec<(i64, [u32; 3], [f64; 3])> = vec![];
+    for (time, params) in samples {
+        let bucket = time.timestamp() / HTTP_CURVE_RESOLUTION_SECS * HTTP_CURVE_RESOLUTION_SECS;
+        if buckets.last().map(|b| b.0) != Some(bucket) {
+            buckets.push((bucket, [0; 3], [0.0; 3]));
+        }
+        let b = buckets.last_mut().unwrap();
+        for p in params {
+            if let Some(i) = CURVE_PARAMS.iter().position(|&n| n == p.name) {
+                if let Some(v) = p.get_scaled_value() {
+                    b.1[i] += 1;
+                    b.2[i] += v;
+                }
+            }
+        }
+    }
+
+    let points: Vec<Value> = buckets
+        .iter()
+        .map(|(t, count, sum)| {
+            let mut point = serde_json::Map::new();
+            point.insert("time".into(), json!(t * 1000));
+            for (i, name) in CURVE_PARAMS.iter().enumerate() {
+                if count[i] > 0 {
+                    point.insert(name.to_string(), json!(sum[i] / count[i] as f64));
+                }
+            }
+            Value::Object(point)
+        })
+        .collect();
+    json!({ "date": date, "points": points })
+}
+
+fn json_response(body: &Value) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode) -> Response<Body> {
+    let body = json!({ "error": status.canonical_reason().unwrap_or_default() });
+    let mut response = json_response(&body);
+    *response.status_mut() = status;
+    response
+}
+
+pub fn parameter_json(p: &Parameter) -> Value {
+    let value = match &p.value {
+        ParamKind::Text(v) => json!(v),
+        _ => json!(p.get_scaled_value()),
+    };
+    json!({
+        "name": p.name,
+        "value": value,
+        "unit": p.unit,
+        "desc": p.desc,
+        "register": p.reg_address,
+    })
+}
+
+fn snapshot_json(snapshot: &PollSnapshot) -> Value {
+    json!({
+        "time": snapshot.time.to_rfc3339(),
+        "parameters": snapshot.params.iter().map(parameter_json).collect::<Vec<Value>>(),
+        "stale": snapshot
+            .stale
+            .iter()
+            .map(|p| json!({ "name": p.name, "age_secs": p.age(snapshot.time).map(|a| a.num_seconds()) }))
+            .collect::<Vec<Value>>(),
+    })
+}
+
+fn status_json(shared: &SharedState) -> Value {
+    json!({
+        "parameters": parameters_json(shared),
+        "state": state_json(&shared.state),
+        "alarms": alarms_json(&shared.state),
+        "identity": identity_json(shared),
+        "stats": stats_json(shared),
+        "spans": spans_json(shared),
+        "energy": energy_json(shared),
+        "sun": sun_json(shared),
+        "clock": clock_json(shared),
+    })
+}
+
+fn parameters_json(shared: &SharedState) -> Value {
+    match &shared.snapshot {
+        Some(snapshot) => snapshot_json(snapshot),
+        None => Value::Null,
+    }
+}
+
+fn code_json<T: Into<u64> + Copy>(code: Option<T>, description: fn(T) -> String) -> Value {
+    match code {
+        Some(c) => json!({ "code": c.into(), "description": description(c) }),
+        None => Value::Null,
+    }
+}
+
+pub fn state_json(state: &Sun2000State) -> Value {
+    let grid_code = state.grid_code.map(|c| {
+        let (standard, country) = Sun2000State::get_grid_code(c);
+        json!({ "code": c, "standard": standard, "country": country })
+    });
+    json!({
+        "device_status": code_json(state.device_status, |c| Sun2000State::get_device_status_description(c).into()),
+        "storage_status": state.storage_status.map(|c| json!({
+            "code": c,
+            "description": Sun2000State::get_storage_status_description(c),
+        })),
+        "grid_code": grid_code,
+        "state_1": code_json(state.state_1, Sun2000State::get_state1_description),
+        "state_2": code_json(state.state_2, Sun2000State::get_state2_description),
+        "state_3": code_json(state.state_3, Sun2000State::get_state3_description),
+        "fault_code": state.fault_code,
+    })
+}
+
+pub fn alarms_json(state: &Sun2000State) -> Value {
+    let alarms: Vec<Value> = state
+        .get_active_alarms()
+        .iter()
+        .map(|a| json!({ "name": a.name, "code": a.code, "severity": a.severity }))
+        .collect();
+    json!(alarms)
+}
+
+fn identity_json(shared: &SharedState) -> Value {
+    let mut device = serde_json::Map::new();
+    for p in &shared.device_info {
+        device.insert(p.name.into(), parameter_json(p)["value"].clone());
+    }
+    let devices: Vec<Value> = shared.devices.iter().map(device_identity_json).collect();
+    //attributes of the inverter itself, kept for the existing consumers
+    let attributes: Vec<Value> = inverter_identity(&shared.devices, shared.slave)
+        .map(|d| d.attributes.iter().map(|(id, value)| json!({ "id": id, "name": get_attribute_name(id), "value": value })).collect())
+        .unwrap_or_default();
+    json!({ "device": device, "attributes": attributes, "devices": devices })
+}
+
+fn device_identity_json(d: &DeviceIdentity) -> Value {
+    json!({
+        "kind": d.kind(),
+        "model": d.model,
+        "software_version": d.software_version,
+        "protocol_version": d.protocol_version,
+        "esn": d.esn,
+        "device_id": d.device_id,
+        "feature_version": d.feature_version,
+    })
+}
+
+fn totals_json(totals: &EnergyTotals) -> Value {
+    json!({
+        "production": totals.production,
+        "consumption": totals.consumption,
+        "import": totals.import,
+        "export": totals.export,
+        "self_consumed": totals.self_consumed,
+        "import_cost": totals.import_cost,
+        "export_revenue": totals.export_revenue,
+        "savings": totals.savings,
+        "self_consumption_ratio": totals.self_consumption_ratio(),
+        "autarky": totals.autarky(),
+    })
+}
+
+fn energy_json(shared: &SharedState) -> Value {
+    json!({
+        "house_power": shared.house_power,
+        "today": totals_json(&shared.energy_today),
+        "month": totals_json(&shared.energy_month),
+    })
+}
+
+fn sun_json(shared: &SharedState) -> Value {
+    let times = match shared.sun_times {
+        Some(SunTimes::Regular { sunrise, sunset }) => json!({
+            "sunrise": sunrise.to_rfc3339(),
+            "sunset": sunset.to_rfc3339(),
+        }),
+        Some(SunTimes::PolarDay) => json!({ "polar": "day" }),
+        Some(SunTimes::PolarNight) => json!({ "polar": "night" }),
+        None => Value::Null,
+    };
+    json!({ "night": shared.night, "times": times })
+}
+
+fn clock_json(shared: &SharedState) -> Value {
+    match &shared.clock {
+        Some(clock) => json!({
+            "time": clock.time.to_rfc3339(),
+            "drift_secs": clock.drift,
+            "time_zone": clock.inverter_time_zone,
+            "host_time_zone": clock.host_time_zone,
+            "host_synchronized": clock.host_synchronized,
+            "last_sync": clock.last_sync.map(|t| t.to_rfc3339()),
+        }),
+        None => Value::Null,
+    }
+}
+
+fn optimizers_json(shared: &SharedState) -> Value {
+    let optimizers: Vec<Value> = match &shared.optimizers {
+        Some(sample) => sample
+            .optimizers
+            .iter()
+            .map(|o| {
+                let info = shared.optimizer_info.iter().find(|i| i.address == o.address);
+                json!({
+                    "address": o.address,
+                    "serial_number": info.map(|i| i.serial_number.clone()),
+                    "string": info.map(|i| i.string),
+                    "position": info.map(|i| i.position),
+                    "output_power": o.output_power,
+                    "voltage_to_ground": o.voltage_to_ground,
+                    "output_voltage": o.output_voltage,
+                    "output_current": o.output_current,
+                    "input_voltage": o.input_voltage,
+                    "input_current": o.input_current,
+                    "temperature": o.temperature,
+                    "running_status": o.running_status,
+                    "energy_yield": o.energy_yield,
+                })
+            })
+            .collect(),
+        None => vec![],
+    };
+    json!({
+        "time": shared.optimizers.as_ref().map(|s| s.time.to_rfc3339()),
+        "optimizers": optimizers,
+    })
+}
+
+fn spans_json(shared: &SharedState) -> Value {
+    let spans: Vec<Value> = shared
+        .spans
+        .iter()
+        .map(|s| {
+            json!({
+                "start": s.start,
+                "len": s.len,
+                "ok": s.ok,
+                "errors": s.errors,
+                "consecutive_errors": s.consecutive_errors,
+                "last_ok": s.last_ok.map(|t| t.to_rfc3339()),
+                "last_error": s.last_error,
+            })
+        })
+        .collect();
+    json!(spans)
+}
+
+fn stats_json(shared: &SharedState) -> Value {
+    let stats = &shared.stats;
+    json!({
+        "poll_ok": stats.poll_ok,
+        "poll_errors": stats.poll_errors,
+        "poll_partial": stats.poll_partial,
+        "last_poll_ok_secs_ago": stats.last_poll_ok.map(|t| t.elapsed().as_secs_f64()),
+        "last_query_ms": stats.last_query_ms,
+        "consecutive_failures": shared.worker.failures,
+        "breaker_open": shared.worker.breaker_open,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::sun2000::battery::BatteryAction;
+    use chrono::TimeZone;
+
+    fn param(name: &str, value: i32) -> Parameter {
+        let p = find_parameter(name).unwrap();
+        Parameter { value: ParamKind::NumberI32(Some(value)), ..p.clone() }
+    }
+
+    #[test]
+    fn status() {
+        let time = chrono::Utc.ymd(2024, 6, 21).and_hms(12, 0, 0);
+        let mut shared = SharedState::default();
+        let status = status_json(&shared);
+        //nothing polled yet
+        assert_eq!(status["parameters"], Value::Null);
+        assert_eq!(status["clock"], Value::Null);
+        assert_eq!(status["alarms"], json!([]));
+        assert_eq!(status["sun"], json!({ "night": false, "times": null }));
+
+        shared.snapshot = Some(PollSnapshot {
+            time,
+            params: vec![param("active_power", 3500)],
+            stale: vec![StaleParam { name: "input_power".into(), last_read: Some(time - chrono::Duration::seconds(30)) }],
+        });
+        shared.state.device_status = Some(0x0200);
+        shared.stats.poll_ok = 12;
+        shared.worker.failures = 1;
+        shared.house_power = Some(800.0);
+        shared.sun_times = Some(SunTimes::PolarDay);
+        let status = status_json(&shared);
+        let parameters = &status["parameters"];
+        assert_eq!(parameters["time"], "2024-06-21T12:00:00+00:00");
+        assert_eq!(parameters["parameters"][0]["name"], "active_power");
+        assert_eq!(parameters["parameters"][0]["value"], 3500.0);
+        assert_eq!(parameters["parameters"][0]["unit"], "W");
+        assert_eq!(parameters["stale"], json!([{ "name": "input_power", "age_secs": 30 }]));
+        assert_eq!(status["state"]["device_status"]["code"], 0x0200);
+        assert_eq!(status["state"]["device_status"]["description"], "On-grid");
+        assert_eq!(status["stats"]["poll_ok"], 12);
+        assert_eq!(status["stats"]["consecutive_failures"], 1);
+        assert_eq!(status["stats"]["last_poll_ok_secs_ago"], Value::Null);
+        assert_eq!(status["energy"]["house_power"], 800.0);
+        assert_eq!(status["energy"]["today"]["production"], 0.0);
+        assert_eq!(status["sun"]["times"], json!({ "polar": "day" }));
+        assert_eq!(status["identity"], json!({ "device": {}, "attributes": [], "devices": [] }));
+    }
+
+    /// Event name and data of the server-sent event
+    fn parse_event(ev: &Sun2000Event) -> (String, Value) {
+        let event = event_json(ev);
+        let (name, data) = event.strip_suffix("\n\n").unwrap().split_once('\n').unwrap();
+        (name.strip_prefix("event: ").unwrap().to_string(), serde_json::from_str(data.strip_prefix("data: ").unwrap()).unwrap())
+    }
+
+    #[test]
+    fn events() {
+        let time = chrono::Utc.ymd(2024, 6, 21).and_hms(12, 0, 0);
+        let snapshot = PollSnapshot { time, params: vec![param("active_power", 3500)], stale: vec![] };
+        let (name, data) = parse_event(&Sun2000Event::Snapshot(std::sync::Arc::new(snapshot)));
+        assert_eq!(name, "snapshot");
+        assert_eq!(data["parameters"][0]["value"], 3500.0);
+
+        let changes = vec![("device_status".to_string(), "<b><green>On-grid</>".to_string())];
+        let (name, data) = parse_event(&Sun2000Event::StateChange { time, changes });
+        assert_eq!(name, "state");
+        assert_eq!(data, json!({ "time": "2024-06-21T12:00:00+00:00", "changes": { "device_status": "On-grid" } }));
+
+        let battery = Sun2000Event::Battery {
+            time,
+            action: BatteryAction::Charge(2500),
+            minutes: 60,
+            soc: Some(35.5),
+            reason: "cheap period".to_string(),
+        };
+        let (name, data) = parse_event(&battery);
+        assert_eq!(name, "battery");
+        assert_eq!(
+            data,
+            json!({ "time": "2024-06-21T12:00:00+00:00", "action": "charge 2500 W", "minutes": 60, "soc": 35.5, "reason": "cheap period" })
+        );
+    }
+
+    #[test]
+    fn markup() {
+        assert_eq!(strip_markup("<i>PV3</>: <b><cyan>underperforming</>"), "PV3: underperforming");
+        assert_eq!(strip_markup("power > 0"), "power > 0");
+        assert_eq!(strip_markup(""), "");
+    }
+
+    /// Hourly samples of the UTC day up to `now`, as a dump file reader
+    fn hourly(now: chrono::DateTime<chrono::Utc>) -> impl Fn(i32, u32, u32) -> Vec<Sample> {
+        move |y, m, d| {
+            (0..24)
+                .map(|h| chrono::Utc.ymd(y, m, d).and_hms(h, 0, 0))
+                .filter(|t| *t <= now)
+                .map(|t| (t, vec![]))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn local_day_across_utc_files() {
+        let times = |samples: Vec<Sample>| samples.into_iter().map(|(t, _)| t).collect::<Vec<_>>();
+        //UTC+2: the local day started at 22:00 UTC the day before
+        let now = chrono::Utc.ymd(2024, 6, 21).and_hms(10, 30, 0);
+        let east = chrono::FixedOffset::east(2 * 3600);
+        let samples = times(read_local_today(now, &east, hourly(now)));
+        assert_eq!(samples.len(), 13);
+        assert_eq!(samples[0], chrono::Utc.ymd(2024, 6, 20).and_hms(22, 0, 0));
+        assert_eq!(samples[12], chrono::Utc.ymd(2024, 6, 21).and_hms(10, 0, 0));
+        //UTC-5: still the 20th locally
+        let now = chrono::Utc.ymd(2024, 6, 21).and_hms(3, 30, 0);
+        let west = chrono::FixedOffset::west(5 * 3600);
+        let samples = times(read_local_today(now, &west, hourly(now)));
+        assert_eq!(samples.len(), 23);
+        assert_eq!(samples[0], chrono::Utc.ymd(2024, 6, 20).and_hms(5, 0, 0));
+        //UTC
+        let samples = times(read_local_today(now, &chrono::Utc, hourly(now)));
+        assert_eq!(samples, (0..4).map(|h| chrono::Utc.ymd(2024, 6, 21).and_hms(h, 0, 0)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn curve() {
+        let time = chrono::Utc.ymd(2024, 6, 21).and_hms(12, 0, 0);
+        let at = |secs: i64| time + chrono::Duration::seconds(secs);
+        let samples = vec![
+            (at(10), vec![param("active_power", 1000), param("power_meter_active_power", -200)]),
+            (at(40), vec![param("active_power", 2000)]),
+            (at(65), vec![param("input_power", 2500)]),
+        ];
+        let curve = curve_json("2024-06-21", samples);
+        assert_eq!(curve["date"], "2024-06-21");
+        assert_eq!(
+            curve["points"],
+            json!([
+                { "time": time.timestamp_millis(), "active_power": 1500.0, "power_meter_active_power": -200.0 },
+                { "time": at(60).timestamp_millis(), "input_power": 2500.0 },
+            ])
+        );
+        assert_eq!(curve_json("2024-06-21", vec![])["points"], json!([]));
+    }
+}
@@ -1,21 +1,155 @@
 
+mod backup;
+mod http;
+mod probe;
+mod proxy;
+mod surplus;
+mod supervisor;
+mod systemd;
+
 use simplelog::*;
 
 use ::sun2000::*;
+use ::sun2000::shared::*;
+use supervisor::*;
 
 extern crate ini;
 use self::ini::Ini;
 
-use futures::future::join_all;
+use clap::{Parser, Subcommand};
 use humantime::format_duration;
 use std::env;
 
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc};
 use std::time::{Duration, Instant};
-use tokio::task;
+use tokio::signal::unix::{signal, SignalKind};
 //use tokio_compat_02::FutureExt;
 
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const STATUS_UPDATE_INTERVAL_SECS: u64 = 30; //secs between systemd STATUS updates (without watchdog)
+pub const WATCHDOG_POLL_MAX_AGE_SECS: u64 = 1200; //age of the last successful poll, beyond the reconnects before the breaker opens
+
+
+/// hard (home automation rust-daemon), runs the daemon when no command is given
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// List the devices at the sun2000 host
+    Probe {
+        /// Probe all slave IDs of --slaves, not only the configured one
+        #[clap(long)]
+        scan: bool,
+
+        /// Host and port (defaults to the [sun2000] host)
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Range of slave IDs to scan, eg: 1-16
+        #[clap(long)]
+        slaves: Option<String>,
+    },
+    /// Backup, compare and restore the inverter settings
+    Settings {
+        #[clap(subcommand)]
+        action: SettingsAction,
+
+        /// Host and port (defaults to the [sun2000] host)
+        #[clap(long)]
+        host: Option<String>,
+
+        /// Modbus slave ID (defaults to the [sun2000] slave)
+        #[clap(long)]
+        slave: Option<u8>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SettingsAction {
+    /// Save the settings to a JSON file
+    Backup {
+        /// Output file (defaults to a name with the serial number and time)
+        #[clap(long, short)]
+        output: Option<String>,
+    },
+    /// Show the settings differing between a backup and the inverter
+    Diff { file: String },
+    /// Write the settings of a backup differing on the inverter
+    Restore {
+        file: String,
+
+        /// Restore only these settings (comma separated names)
+        #[clap(long)]
+        only: Option<String>,
+
+        /// Don't ask for a confirmation
+        #[clap(long)]
+        yes: bool,
+
+        /// Restore a backup of another device
+        #[clap(long)]
+        force: bool,
+    },
+}
+
+/// Slave ID of the inverter as configured in [sun2000]
+fn get_config_slave() -> u8 {
+    match get_config_string("slave_id", Some("sun2000")).map(|v| v.trim().parse()) {
+        Some(Ok(id)) => id,
+        Some(Err(e)) => {
+            error!("sun2000: invalid slave_id, ignoring: {}", e);
+            get_config_bool("dongle_connection", Some("sun2000")) as u8
+        }
+        None => get_config_bool("dongle_connection", Some("sun2000")) as u8,
+    }
+}
+
+async fn run_probe(scan: bool, host: Option<String>, slaves: Option<String>) -> Result<()> {
+    let host = host
+        .or_else(|| get_config_string("host", Some("sun2000")))
+        .ok_or("no host given and no host in [sun2000]")?;
+    let (first, last) = match (slaves, scan) {
+        (Some(slaves), _) => ::sun2000::discovery::parse_slave_range(&slaves)?,
+        (None, true) => (0, ::sun2000::discovery::DISCOVERY_MAX_SLAVE),
+        (None, false) => (get_config_slave(), get_config_slave()),
+    };
+    probe::probe(host.trim().parse()?, first, last).await
+}
+
+/// true when there is a config file, the one-shot commands also work without it
+fn has_config() -> bool {
+    std::path::Path::new("hard.conf").exists()
+}
+
+async fn run_settings(action: SettingsAction, host: Option<String>, slave: Option<u8>) -> Result<()> {
+    let config = has_config();
+    let host = host
+        .or_else(|| if config { get_config_string("host", Some("sun2000")) } else { None })
+        .ok_or("no --host given and no host in [sun2000]")?;
+    let host = host.trim().parse()?;
+    let slave = match slave {
+        Some(slave) => slave,
+        None if config => get_config_slave(),
+        None => 0,
+    };
+    let modbus = if config {
+        ::sun2000::retry::ModbusConfig::from_options(|name| get_config_string(name, Some("sun2000")))?
+    } else {
+        ::sun2000::retry::ModbusConfig::default()
+    };
+    match action {
+        SettingsAction::Backup { output } => backup::backup(host, slave, &modbus, output).await,
+        SettingsAction::Diff { file } => backup::diff(host, slave, &modbus, &file).await,
+        SettingsAction::Restore { file, only, yes, force } => backup::restore(host, slave, &modbus, &file, only, yes, force).await,
+    }
+}
 
 fn get_config_string(option_name: &str, section: Option<&str>) -> Option<String> {
     let conf = Ini::load_from_file("hard.conf").expect("Cannot open config file");
@@ -44,64 +178,340 @@ fn get_config_bool(option_name: &str, section: Option<&str>) -> bool {
 #[tokio::main]
 async fn main() {
     env::set_var("RUST_BACKTRACE", "full");
+    let args = Args::parse();
     let started = Instant::now();
+    match args.command {
+        Some(Command::Probe { scan, host, slaves }) => {
+            logging::init(None);
+            if let Err(e) = run_probe(scan, host, slaves).await {
+                error!("probe failed: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::Settings { action, host, slave }) => {
+            logging::init(None);
+            if let Err(e) = run_settings(action, host, slave).await {
+                error!("settings: {}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        None => {}
+    }
     logging::init(get_config_string("log", None));
 
     info!("🛡️ Welcome to hard (home automation rust-daemon)");
 
     //Ctrl-C / SIGTERM support
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    ctrlc::set_handler(move || {
-        r.store(false, Ordering::SeqCst);
-    })
-    .expect("Error setting Ctrl-C handler");
+    let mut sigterm = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
 
     //common thread stuff
-    let mut futures = vec![];
-    let cancel_flag = Arc::new(AtomicBool::new(false));
-    
+    let mut supervisor = Supervisor::new();
+
     //sun2000 async task
+    let mut sun2000_shared = None;
+    let events = new_events();
+    let tariff = match ::sun2000::tariff::Tariff::from_options(|name| get_config_string(name, Some("tariff"))) {
+        Ok(tariff) => tariff,
+        Err(e) => {
+            error!("tariff: invalid configuration, costs won't be calculated: {}", e);
+            None
+        }
+    };
+    let export_limit = match ::sun2000::export_limit::ExportLimitConfig::from_options(|name| get_config_string(name, Some("sun2000"))) {
+        Ok(export_limit) => export_limit,
+        Err(e) => {
+            error!("sun2000: invalid export limit configuration, export limiting disabled: {}", e);
+            None
+        }
+    };
+    let battery_schedule = match ::sun2000::battery::BatteryScheduleConfig::from_options(|name| get_config_string(name, Some("sun2000"))) {
+        Ok(battery_schedule) => battery_schedule,
+        Err(e) => {
+            error!("sun2000: invalid battery schedule configuration, scheduler disabled: {}", e);
+            None
+        }
+    };
+    let location = match (get_config_string("lat", None), get_config_string("lon", None)) {
+        (Some(lat), Some(lon)) => match (lat.trim().parse(), lon.trim().parse()) {
+            (Ok(lat), Ok(lon)) => Some(::sun2000::ephemeris::Location { lat, lon }),
+            _ => {
+                error!("invalid lat/lon configuration: {:?}/{:?}", lat, lon);
+                None
+            }
+        },
+        _ => None,
+    };
+    let night_mode = match location.map(|l| ::sun2000::ephemeris::NightMode::from_options(l, |name| get_config_string(name, Some("sun2000")))) {
+        Some(Ok(night_mode)) => night_mode,
+        Some(Err(e)) => {
+            error!("sun2000: invalid night mode configuration, night mode disabled: {}", e);
+            None
+        }
+        None => None,
+    };
+    let poll_intervals = match get_config_string("poll_intervals", Some("sun2000")).map(|v| ::sun2000::params::parse_poll_intervals(&v)) {
+        Some(Ok(intervals)) => intervals,
+        Some(Err(e)) => {
+            error!("sun2000: invalid poll_intervals, using the defaults: {}", e);
+            Default::default()
+        }
+        None => Default::default(),
+    };
+    let backend = match get_config_string("device", Some("sun2000")).map(|v| ::sun2000::device::Backend::parse(&v)) {
+        Some(Ok(backend)) => backend,
+        Some(Err(e)) => {
+            error!("sun2000: invalid device, using the inverter: {}", e);
+            Default::default()
+        }
+        None => Default::default(),
+    };
+    let clock = match ::sun2000::clock::ClockConfig::from_options(|name| get_config_string(name, Some("sun2000"))) {
+        Ok(clock) => clock,
+        Err(e) => {
+            error!("sun2000: invalid clock configuration, using the defaults: {}", e);
+            Default::default()
+        }
+    };
+    let string_analysis = match ::sun2000::strings::StringAnalysisConfig::from_options(|name| get_config_string(name, Some("sun2000"))) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("sun2000: invalid string analysis configuration, using the defaults: {}", e);
+            Default::default()
+        }
+    };
+    let modbus = match ::sun2000::retry::ModbusConfig::from_options(|name| get_config_string(name, Some("sun2000"))) {
+        Ok(modbus) => modbus,
+        Err(e) => {
+            error!("sun2000: invalid modbus/reconnect configuration, using the defaults: {}", e);
+            Default::default()
+        }
+    };
+    //Modbus TCP proxy sharing the inverter connection
+    let proxy_listen = match get_config_string("listen", Some("proxy")).map(|l| l.trim().parse()) {
+        Some(Ok(listen)) => Some(listen),
+        Some(Err(e)) => {
+            error!("proxy: invalid listen address: {}", e);
+            None
+        }
+        None => None,
+    };
+    let proxy_rules = match get_config_string("rules", Some("proxy")).map(|r| proxy::parse_proxy_rules(&r)) {
+        Some(Ok(rules)) => rules,
+        Some(Err(e)) => {
+            error!("proxy: invalid rules, the proxy is disabled: {}", e);
+            vec![]
+        }
+        None => vec![],
+    };
+    let mut proxy_channel = None;
+    if proxy_listen.is_some() && proxy_rules.is_empty() {
+        warn!("proxy: no client rules configured, the proxy is disabled");
+    }
+    let upload_files = match get_config_string("upload_files", Some("sun2000")).map(|v| ::sun2000::upload::parse_file_types(&v)) {
+        Some(Ok(files)) => files,
+        Some(Err(e)) => {
+            error!("sun2000: invalid upload_files, no files will be uploaded: {}", e);
+            vec![]
+        }
+        None => vec![],
+    };
     match get_config_string("host", Some("sun2000")) {
         Some(host) => {
-            let worker_cancel_flag = cancel_flag.clone();
-            let mut sun2000 = sun2000::Sun2000 {
-                name: "sun2000".to_string(),
-                host_port: host,
-                poll_ok: 0,
-                poll_errors: 0,
-                influxdb_url: get_config_string("influxdb_url", None),
-                influxdb_org: get_config_string("influxdb_org", None),
-                influxdb_token: get_config_string("influxdb_token", None),
-                influxdb_bucket: get_config_string("influxdb_bucket", None),
-                mode_change_script: get_config_string("mode_change_script", Some("sun2000")),
-                optimizers: get_config_bool("optimizers", Some("sun2000")),
-                battery_installed: get_config_bool("battery_installed", Some("sun2000")),
-                dongle_connection: get_config_bool("dongle_connection", Some("sun2000")),
+            let shared = new_shared();
+            sun2000_shared = Some(shared.clone());
+            let events = events.clone();
+            let proxy_receiver = match (&proxy_listen, proxy_rules.is_empty()) {
+                (Some(_), false) => {
+                    let (tx, rx) = new_proxy_channel();
+                    proxy_channel = Some(tx);
+                    Some(rx)
+                }
+                _ => None,
             };
-            let sun2000_future =
-                task::spawn(async move { sun2000.worker(worker_cancel_flag).await });
-            futures.push(sun2000_future);
+            supervisor.spawn("sun2000", move |cancel| {
+                let mut sun2000 = sun2000::Sun2000 {
+                    name: "sun2000".to_string(),
+                    host_port: host.clone(),
+                    shared: shared.clone(),
+                    events: events.clone(),
+                    influxdb_url: get_config_string("influxdb_url", None),
+                    influxdb_org: get_config_string("influxdb_org", None),
+                    influxdb_token: get_config_string("influxdb_token", None),
+                    influxdb_bucket: get_config_string("influxdb_bucket", None),
+                    mode_change_script: get_config_string("mode_change_script", Some("sun2000")),
+                    optimizers: get_config_bool("optimizers", Some("sun2000")),
+                    battery_installed: get_config_bool("battery_installed", Some("sun2000")),
+                    dongle_connection: get_config_bool("dongle_connection", Some("sun2000")),
+                    slave_id: get_config_string("slave_id", Some("sun2000")).map(|_| get_config_slave()),
+                    backend,
+                    tariff: tariff.clone(),
+                    export_limit: export_limit.clone(),
+                    battery_schedule: battery_schedule.clone(),
+                    night_mode: night_mode.clone(),
+                    poll_intervals: poll_intervals.clone(),
+                    modbus: modbus.clone(),
+                    proxy: proxy_receiver.clone(),
+                    upload_files: upload_files.clone(),
+                    identities: vec![],
+                    clock: clock.clone(),
+                    string_analysis: string_analysis.clone(),
+                };
+                async move { sun2000.worker(cancel).await }
+            });
         }
         _ => {}
     }
 
 
+    //embedded http server
+    if let (Some(listen), Some(shared)) = (get_config_string("http_listen", None), &sun2000_shared) {
+        match listen.parse() {
+            Ok(listen) => {
+                let shared = shared.clone();
+                let events = events.clone();
+                supervisor.spawn("http", move |cancel| {
+                    let http = http::HttpServer {
+                        name: "http".to_string(),
+                        listen,
+                        shared: shared.clone(),
+                        events: events.clone(),
+                    };
+                    async move { http.worker(cancel).await }
+                });
+            }
+            Err(e) => error!("http: invalid http_listen address {:?}: {}", listen, e),
+        }
+    }
+
+    if let (Some(listen), Some(requests), Some(shared)) = (proxy_listen, proxy_channel, &sun2000_shared) {
+        let max_age = match get_config_string("max_age", Some("proxy")).map(|v| v.trim().parse::<f64>()) {
+            Some(Ok(v)) if v.is_finite() && v > 0.0 => v,
+            Some(_) => {
+                error!("proxy: invalid max_age, using the default: {} s", proxy::PROXY_MAX_AGE_SECS);
+                proxy::PROXY_MAX_AGE_SECS
+            }
+            None => proxy::PROXY_MAX_AGE_SECS,
+        };
+        let slave = get_config_slave();
+        let shared = shared.clone();
+        supervisor.spawn("proxy", move |cancel| {
+            let proxy = proxy::ProxyServer {
+                name: "proxy".to_string(),
+                listen,
+                slave,
+                rules: proxy_rules.clone(),
+                max_age: Duration::from_secs_f64(max_age),
+                shared: shared.clone(),
+                requests: requests.clone(),
+            };
+            async move { proxy.worker(cancel).await }
+        });
+    }
+
+    //PV surplus driven load control
+    if let (Some(names), true) = (get_config_string("loads", Some("surplus")), sun2000_shared.is_some()) {
+        let mut loads = vec![];
+        for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let section = format!("surplus.{}", name);
+            match surplus::SurplusLoad::from_options(name, |option| get_config_string(option, Some(&section))) {
+                Ok(load) => loads.push(load),
+                Err(e) => error!("surplus: invalid configuration of load {:?}, ignoring: {}", name, e),
+            }
+        }
+        let hysteresis = get_config_string("hysteresis", Some("surplus"))
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(surplus::SURPLUS_HYSTERESIS_W);
+        if !loads.is_empty() {
+            let events = events.clone();
+            supervisor.spawn("surplus", move |cancel| {
+                let mut surplus = surplus::SurplusManager {
+                    name: "surplus".to_string(),
+                    hysteresis,
+                    loads: loads.clone(),
+                    events: events.clone(),
+                };
+                async move { surplus.worker(cancel).await }
+            });
+        }
+    }
+
+    //systemd readiness and watchdog
+    systemd::notify_ready();
+    let watchdog = systemd::watchdog_interval();
+    if let Some(interval) = watchdog {
+        info!("systemd watchdog enabled, ping interval: {}", format_duration(interval));
+    }
+    let mut status_interval = tokio::time::interval(
+        watchdog.unwrap_or(Duration::from_secs(STATUS_UPDATE_INTERVAL_SECS)),
+    );
+
     debug!("Entering main loop...");
     loop {
-        if !running.load(Ordering::SeqCst) {
-            info!("🛑 Ctrl-C or SIGTERM signal detected, exiting...");
-            break;
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => break,
+            _ = sigterm.recv() => break,
+            _ = status_interval.tick() => {}
         }
 
-        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut status = vec![];
+        let workers = supervisor.statuses();
+        //the watchdog is fed while the inverter is polled successfully; at night or with the breaker
+        //open the attempts are only made rarely, then it is enough that the worker loop makes them
+        let mut healthy = true;
+        if let Some(shared) = &sun2000_shared {
+            let shared = shared.read().unwrap();
+            let restarting = workers.iter().any(|w| w.name == "sun2000" && w.state == WorkerState::Backoff);
+            let max_age = Duration::from_secs(WATCHDOG_POLL_MAX_AGE_SECS);
+            healthy = shared.stats.is_polling(max_age)
+                || ((shared.night || shared.worker.breaker_open) && shared.worker.is_alive())
+                || restarting
+                //no poll yet, the same time is given from the start
+                || (shared.stats.last_poll_ok.is_none() && started.elapsed() < max_age);
+            status.push(format!(
+                "sun2000: poll_ok={} poll_errors={} status={}{}{}",
+                shared.stats.poll_ok,
+                shared.stats.poll_errors,
+                shared
+                    .state
+                    .device_status
+                    .map(Sun2000State::get_device_status_description)
+                    .unwrap_or("unknown"),
+                if shared.night { " (night)" } else { "" },
+                if shared.worker.breaker_open { " (breaker open)" } else { "" },
+            ));
+        }
+        for worker in workers {
+            if worker.state != WorkerState::Running {
+                status.push(format!("{}: {:?}", worker.name, worker.state));
+            }
+        }
+        if watchdog.is_some() {
+            if healthy {
+                systemd::ping_watchdog();
+            } else {
+                warn!("sun2000 inverter is not polled, not feeding systemd watchdog");
+            }
+        }
+        systemd::set_status(&status.join(", "));
     }
+    info!("🛑 Ctrl-C or SIGTERM signal detected, exiting...");
+    systemd::notify_stopping();
 
     info!("🏁 Stopping all threads...");
-    //inform all threads about termination
-    cancel_flag.store(true, Ordering::SeqCst);
-    //wait for tokio async tasks
-    let _ = join_all(futures).await;
+    for status in supervisor.statuses() {
+        if status.restarts > 0 {
+            info!(
+                "<i>{}</>: restarted <b>{}</> times, last error: <b>{}</>",
+                status.name,
+                status.restarts,
+                status.last_error.unwrap_or_default()
+            );
+        }
+    }
+    //inform all threads about termination and wait for them
+    supervisor.shutdown(Duration::from_secs(SUPERVISOR_SHUTDOWN_DEADLINE_SECS)).await;
 
     info!(
         "🚩 hard terminated, daemon running time: {}",
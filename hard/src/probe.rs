@@ -0,0 +1,61 @@
+use std::net::SocketAddr;
+
+use ::sun2000::discovery::*;
+
+use super::Result;
+
+/// Lists the devices reachable at the host, on the slave IDs `first..=last`
+pub async fn probe(host: SocketAddr, first: u8, last: u8) -> Result<()> {
+    println!("probing {} (slave IDs {}-{})...", host, first, last);
+    let devices = discover(host, first, last).await?;
+    if devices.is_empty() {
+        println!("no devices found");
+        return Ok(());
+    }
+
+    println!("slave  kind      model                    serial number        details");
+    for d in &devices {
+        let mut details = vec![];
+        match d.meter {
+            Some(true) => details.push("three phase power meter".to_string()),
+            Some(false) => details.push("single phase power meter".to_string()),
+            None => {}
+        }
+        if !d.battery_units.is_empty() {
+            details.push(format!(
+                "battery units: {:?}, SOC: {}",
+                d.battery_units,
+                d.battery_soc.map(|s| format!("{:.1}%", s)).unwrap_or_else(|| "unknown".into())
+            ));
+        }
+        println!(
+            "{:<6} {:<9} {:<24} {:<20} {}",
+            d.slave,
+            d.kind(),
+            d.model.as_deref().unwrap_or("-"),
+            d.serial_number.as_deref().unwrap_or("-"),
+            details.join(", ")
+        );
+        //devices described by the slave (eg. meters and batteries behind a SmartLogger)
+        for identity in &d.identities {
+            println!("       └ {}", identity);
+        }
+    }
+
+    //a single inverter is polled, the line of the chosen one goes into the [sun2000] section
+    let inverters: Vec<&DiscoveredDevice> = devices.iter().filter(|d| d.kind() == "inverter").collect();
+    match inverters[..] {
+        [] => {}
+        [inverter] => println!("\nthe inverter can be polled with slave_id={} in the [sun2000] section", inverter.slave),
+        _ => {
+            println!("\nan inverter can be polled with one of these lines in the [sun2000] section:");
+            for inverter in inverters {
+                println!("slave_id={}", inverter.slave);
+            }
+        }
+    }
+    if let Some(logger) = devices.iter().find(|d| d.kind() == "logger") {
+        println!("the plant data of the SmartLogger can be polled with device=smartlogger and slave_id={}", logger.slave);
+    }
+    Ok(())
+}
@@ -0,0 +1,361 @@
+use simplelog::*;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use ::sun2000::shared::*;
+
+use super::Result;
+
+pub const PROXY_MAX_AGE_SECS: f64 = 5.0; //default: cached registers younger than this are not read from the inverter
+pub const PROXY_REQUEST_TIMEOUT_SECS: u64 = 30; //max wait for the inverter, the worker may be in the middle of a poll
+pub const PROXY_IDLE_TIMEOUT_SECS: u64 = 300; //clients without any request for this long are disconnected
+pub const PROXY_MAX_READ_REGISTERS: u16 = 125; //Modbus limit for function 0x03/0x04
+pub const PROXY_MAX_WRITE_REGISTERS: u16 = 123; //Modbus limit for function 0x10
+
+const EXCEPTION_ILLEGAL_FUNCTION: u8 = 0x01;
+const EXCEPTION_ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const EXCEPTION_ILLEGAL_DATA_VALUE: u8 = 0x03;
+const EXCEPTION_DEVICE_FAILURE: u8 = 0x04;
+const EXCEPTION_GATEWAY_PATH_UNAVAILABLE: u8 = 0x0a;
+const EXCEPTION_GATEWAY_NO_RESPONSE: u8 = 0x0b;
+
+/// Access rule of the proxy clients from a network
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyRule {
+    pub network: IpAddr,
+    pub prefix: u8,
+    /// write requests are allowed
+    pub write: bool,
+    /// allowed register ranges (first, last), all registers when empty
+    pub ranges: Vec<(u16, u16)>,
+}
+
+fn ip_bits(ip: IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(v4) => (u32::from(v4) as u128, 32),
+        IpAddr::V6(v6) => match v6.to_ipv4() {
+            //IPv4 clients of a dual stack listener
+            Some(v4) if !v6.is_loopback() && !v6.is_unspecified() => (u32::from(v4) as u128, 32),
+            _ => (u128::from(v6), 128),
+        },
+    }
+}
+
+impl ProxyRule {
+    pub fn matches(&self, ip: IpAddr) -> bool {
+        let (network, bits) = ip_bits(self.network);
+        let (ip, ip_bits) = ip_bits(ip);
+        if bits != ip_bits {
+            return false;
+        }
+        let shift = bits - self.prefix.min(bits);
+        shift == bits || (network >> shift) == (ip >> shift)
+    }
+
+    /// true when all registers `addr..addr + len` are in one of the allowed ranges
+    pub fn allows(&self, addr: u16, len: u16) -> bool {
+        let last = addr as u32 + len as u32 - 1;
+        self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(first, end)| addr >= *first && last <= *end as u32)
+    }
+}
+
+/// Parses a comma separated list of rules, the first rule matching the client wins, eg:
+/// `192.168.0.10 rw 40000-49999, 192.168.0.0/24 ro, 10.0.0.0/8 ro 32000-32999 37100-37199`
+pub fn parse_proxy_rules(s: &str) -> Result<Vec<ProxyRule>> {
+    let mut res = vec![];
+    for rule in s.split(',').map(str::trim).filter(|r| !r.is_empty()) {
+        let fields: Vec<&str> = rule.split_whitespace().collect();
+        if fields.len() < 2 {
+            return Err(format!("invalid proxy rule: {:?}", rule).into());
+        }
+        let (network, prefix) = match fields[0] {
+            "*" => ("0.0.0.0".parse()?, 0),
+            net => match net.split_once('/') {
+                Some((ip, prefix)) => (ip.parse()?, prefix.parse()?),
+                None => {
+                    let ip: IpAddr = net.parse()?;
+                    (ip, ip_bits(ip).1)
+                }
+            },
+        };
+        let write = match fields[1] {
+            "ro" => false,
+            "rw" => true,
+            other => return Err(format!("invalid access {:?} in {:?}, expected ro or rw", other, rule).into()),
+        };
+        let mut ranges = vec![];
+        for range in &fields[2..] {
+            let (first, last) = match range.split_once('-') {
+                Some((first, last)) => (first.parse()?, last.parse()?),
+                None => {
+                    let addr = range.parse()?;
+                    (addr, addr)
+                }
+            };
+            if first > last {
+                return Err(format!("invalid register range {:?} in {:?}", range, rule).into());
+            }
+            ranges.push((first, last));
+        }
+        let rule = ProxyRule { network, prefix, write, ranges };
+        //the "*" wildcard has to match IPv6 clients too
+        if fields[0] == "*" {
+            res.push(ProxyRule { network: "::".parse()?, ..rule.clone() });
+        }
+        res.push(rule);
+    }
+    Ok(res)
+}
+
+/// Modbus TCP server sharing the single inverter connection of the sun2000 worker
+#[derive(Clone)]
+pub struct ProxyServer {
+    pub name: String,
+    pub listen: SocketAddr,
+    /// unit ID of the inverter, requests for other units are rejected
+    pub slave: u8,
+    pub rules: Vec<ProxyRule>,
+    /// max age of cached registers served without asking the inverter
+    pub max_age: Duration,
+    pub shared: Shared,
+    pub requests: ProxySender,
+}
+
+fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([*data.get(offset)?, *data.get(offset + 1)?]))
+}
+
+impl ProxyServer {
+    pub async fn worker(&self, cancel: CancellationToken) -> Result<()> {
+        let listener = TcpListener::bind(self.listen).await?;
+        info!("<i>{}</>: Modbus TCP proxy listening on <u>{}</>", self.name, self.listen);
+        loop {
+            let (stream, peer) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = cancel.cancelled() => break,
+            };
+            let rule = match self.rules.iter().find(|r| r.matches(peer.ip())) {
+                Some(rule) => rule.clone(),
+                None => {
+                    warn!("<i>{}</>: rejecting client <b>{}</>: no matching rule", self.name, peer);
+                    continue;
+                }
+            };
+            info!(
+                "<i>{}</>: client <b>{}</> connected ({}{})",
+                self.name, peer, if rule.write { "rw" } else { "ro" },
+                if rule.ranges.is_empty() { String::new() } else { format!(", registers: {:?}", rule.ranges) }
+            );
+            let server = self.clone();
+            let cancel = cancel.child_token();
+            tokio::spawn(async move {
+                match server.client(stream, &rule, cancel).await {
+                    Ok(()) => info!("<i>{}</>: client <b>{}</> disconnected", server.name, peer),
+                    Err(e) => warn!("<i>{}</>: client <b>{}</> error: <b>{}</>", server.name, peer, e),
+                }
+            });
+        }
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+
+    async fn client(&self, mut stream: TcpStream, rule: &ProxyRule, cancel: CancellationToken) -> Result<()> {
+        loop {
+            //MBAP header: transaction id, protocol id, length (of the unit id + PDU), unit id
+            let mut header = [0u8; 7];
+            let read = timeout(Duration::from_secs(PROXY_IDLE_TIMEOUT_SECS), stream.read_exact(&mut header));
+            tokio::select! {
+                res = read => match res {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                    Ok(Err(e)) => return Err(e.into()),
+                    Err(_) => return Err("idle timeout".into()),
+                },
+                _ = cancel.cancelled() => return Ok(()),
+            }
+            let protocol = u16::from_be_bytes([header[2], header[3]]);
+            let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+            if protocol != 0 || !(2..=254).contains(&length) {
+                return Err(format!("invalid MBAP header: {:02X?}", header).into());
+            }
+            let mut pdu = vec![0u8; length - 1];
+            stream.read_exact(&mut pdu).await?;
+
+            //only the inverter of the worker connection is reachable
+            let response = if header[6] == self.slave {
+                self.process(rule, &pdu).await
+            } else {
+                debug!("<i>{}</>: request for unknown unit {}", self.name, header[6]);
+                exception(pdu[0], EXCEPTION_GATEWAY_NO_RESPONSE)
+            };
+            let mut adu = Vec::with_capacity(7 + response.len());
+            adu.extend_from_slice(&header[0..4]);
+            adu.extend_from_slice(&(response.len() as u16 + 1).to_be_bytes());
+            adu.push(header[6]);
+            adu.extend_from_slice(&response);
+            stream.write_all(&adu).await?;
+        }
+    }
+
+    /// Executes the request PDU, returns the response PDU
+    async fn process(&self, rule: &ProxyRule, pdu: &[u8]) -> Vec<u8> {
+        let function = pdu[0];
+        match function {
+            //holding and input registers are the same for the SUN2000
+            0x03 | 0x04 => {
+                let (addr, len) = match (be_u16(pdu, 1), be_u16(pdu, 3)) {
+                    (Some(addr), Some(len)) if (1..=PROXY_MAX_READ_REGISTERS).contains(&len) => (addr, len),
+                    _ => return exception(function, EXCEPTION_ILLEGAL_DATA_VALUE),
+                };
+                if !rule.allows(addr, len) {
+                    debug!("<i>{}</>: denied read of registers {}-{}", self.name, addr, len);
+                    return exception(function, EXCEPTION_ILLEGAL_DATA_ADDRESS);
+                }
+                let cached = self.shared.read().unwrap().cached_registers(addr, len, self.max_age);
+                let data = match cached {
+                    Some(data) => data,
+                    None => match self.forward(ProxyRequest::Read { addr, len }).await {
+                        Ok(data) => data,
+                        Err(code) => return exception(function, code),
+                    },
+                };
+                let mut response = vec![function, (data.len() * 2) as u8];
+                for v in data {
+                    response.extend_from_slice(&v.to_be_bytes());
+                }
+                response
+            }
+            0x06 | 0x10 => {
+                let (addr, data) = match function {
+                    0x06 => match (be_u16(pdu, 1), be_u16(pdu, 3)) {
+                        (Some(addr), Some(value)) => (addr, vec![value]),
+                        _ => return exception(function, EXCEPTION_ILLEGAL_DATA_VALUE),
+                    },
+                    _ => match (be_u16(pdu, 1), be_u16(pdu, 3), pdu.get(5)) {
+                        (Some(addr), Some(len), Some(count))
+                            if (1..=PROXY_MAX_WRITE_REGISTERS).contains(&len)
+                                && *count as usize == len as usize * 2
+                                && pdu.len() == 6 + *count as usize =>
+                        {
+                            (addr, (0..len as usize).map(|i| be_u16(pdu, 6 + i * 2).unwrap()).collect())
+                        }
+                        _ => return exception(function, EXCEPTION_ILLEGAL_DATA_VALUE),
+                    },
+                };
+                if !rule.write {
+                    debug!("<i>{}</>: denied write of registers {}-{} (read-only client)", self.name, addr, data.len());
+                    return exception(function, EXCEPTION_ILLEGAL_FUNCTION);
+                }
+                if !rule.allows(addr, data.len() as u16) {
+                    debug!("<i>{}</>: denied write of registers {}-{}", self.name, addr, data.len());
+                    return exception(function, EXCEPTION_ILLEGAL_DATA_ADDRESS);
+                }
+                let len = data.len() as u16;
+                let value = data[0];
+                if let Err(code) = self.forward(ProxyRequest::Write { addr, data }).await {
+                    return exception(function, code);
+                }
+                //the response echoes the address and the value (0x06) or the count (0x10)
+                let mut response = vec![function];
+                response.extend_from_slice(&addr.to_be_bytes());
+                response.extend_from_slice(&(if function == 0x06 { value } else { len }).to_be_bytes());
+                response
+            }
+            _ => exception(function, EXCEPTION_ILLEGAL_FUNCTION),
+        }
+    }
+
+    /// Passes the request to the sun2000 worker, returns the Modbus exception code on failure
+    async fn forward(&self, request: ProxyRequest) -> std::result::Result<Vec<u16>, u8> {
+        let (reply, result) = oneshot::channel();
+        let call = ProxyCall { request, reply };
+        let wait = async {
+            self.requests.send(call).await.map_err(|_| EXCEPTION_GATEWAY_PATH_UNAVAILABLE)?;
+            //the call is dropped when the worker stops
+            result.await.map_err(|_| EXCEPTION_GATEWAY_PATH_UNAVAILABLE)
+        };
+        match timeout(Duration::from_secs(PROXY_REQUEST_TIMEOUT_SECS), wait).await {
+            Ok(Ok(Ok(data))) => Ok(data),
+            Ok(Ok(Err(e))) => {
+                debug!("<i>{}</>: inverter error: {}", self.name, e);
+                Err(EXCEPTION_DEVICE_FAILURE)
+            }
+            Ok(Err(code)) => Err(code),
+            Err(_) => Err(EXCEPTION_GATEWAY_NO_RESPONSE),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn rule_matches() {
+        let rules = parse_proxy_rules("192.168.0.10 rw, 192.168.0.0/24 ro, fd00::/8 ro").unwrap();
+        assert!(rules[0].matches(ip("192.168.0.10")));
+        assert!(!rules[0].matches(ip("192.168.0.11")));
+        assert!(rules[1].matches(ip("192.168.0.11")));
+        assert!(!rules[1].matches(ip("192.168.1.11")));
+        //IPv4 clients of a dual stack listener
+        assert!(rules[0].matches(ip("::ffff:192.168.0.10")));
+        assert!(rules[1].matches(ip("::ffff:192.168.0.200")));
+        assert!(rules[2].matches(ip("fd12::1")));
+        assert!(!rules[2].matches(ip("fe80::1")));
+        assert!(!rules[2].matches(ip("192.168.0.10")));
+    }
+
+    #[test]
+    fn rule_matches_all() {
+        let rules = parse_proxy_rules("0.0.0.0/0 ro").unwrap();
+        assert!(rules[0].matches(ip("10.1.2.3")));
+        assert!(!rules[0].matches(ip("fd12::1")));
+        //the wildcard adds an IPv6 rule
+        let rules = parse_proxy_rules("* ro").unwrap();
+        assert_eq!(rules.len(), 2);
+        for client in ["10.1.2.3", "::ffff:10.1.2.3", "fd12::1", "::1"] {
+            assert!(rules.iter().any(|r| r.matches(ip(client))), "{}", client);
+        }
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = parse_proxy_rules("10.0.0.0/8 ro 32000-32999 37100, 192.168.0.10 rw").unwrap();
+        assert_eq!(
+            rules,
+            vec![
+                ProxyRule { network: ip("10.0.0.0"), prefix: 8, write: false, ranges: vec![(32000, 32999), (37100, 37100)] },
+                ProxyRule { network: ip("192.168.0.10"), prefix: 32, write: true, ranges: vec![] },
+            ]
+        );
+        assert!(rules[0].allows(32000, 1000));
+        assert!(!rules[0].allows(32999, 2));
+        assert!(rules[0].allows(37100, 1));
+        assert!(rules[1].allows(0, 125));
+
+        assert!(parse_proxy_rules("192.168.0.10").is_err());
+        assert!(parse_proxy_rules("192.168.0.10 wo").is_err());
+        assert!(parse_proxy_rules("192.168.0.0/x ro").is_err());
+        assert!(parse_proxy_rules("192.168.0.10 ro 40010-40000").is_err());
+        assert!(parse_proxy_rules("").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,242 @@
+use simplelog::*;
+
+use futures::future::join_all;
+use futures::FutureExt;
+use humantime::format_duration;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use super::Result;
+
+pub const SUPERVISOR_BACKOFF_INITIAL_SECS: u64 = 2; //delay before the first restart of a failed worker
+pub const SUPERVISOR_BACKOFF_MAX_SECS: u64 = 300; //upper limit for the restart delay
+pub const SUPERVISOR_HEALTHY_RUN_SECS: u64 = 120; //worker running this long resets its backoff
+pub const SUPERVISOR_SHUTDOWN_DEADLINE_SECS: u64 = 15; //max time to wait for workers on shutdown
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+    Running,
+    Backoff,
+    Stopped,
+}
+
+#[derive(Clone, Debug)]
+pub struct WorkerStatus {
+    pub name: String,
+    pub state: WorkerState,
+    pub restarts: u32,
+    pub last_error: Option<String>,
+    pub since: Instant,
+}
+
+pub struct Supervisor {
+    cancel: CancellationToken,
+    statuses: Arc<Mutex<HashMap<String, WorkerStatus>>>,
+    handles: Vec<(String, JoinHandle<()>)>,
+}
+
+impl Supervisor {
+    pub fn new() -> Self {
+        Self {
+            cancel: CancellationToken::new(),
+            statuses: Arc::new(Mutex::new(HashMap::new())),
+            handles: vec![],
+        }
+    }
+
+    /// Spawns a supervised worker. `factory` is called for every (re)start and gets a
+    /// cancellation token which is triggered on shutdown. A worker returning `Err`, panicking
+    /// or returning before shutdown was requested is restarted with exponential backoff.
+    pub fn spawn<F, Fut>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(CancellationToken) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.to_string();
+        let cancel = self.cancel.clone();
+        let statuses = self.statuses.clone();
+        set_status(&statuses, &name, WorkerState::Running, None);
+
+        let task_name = name.clone();
+        let handle = tokio::spawn(async move {
+            let name = task_name;
+            let mut backoff = Duration::from_secs(SUPERVISOR_BACKOFF_INITIAL_SECS);
+            loop {
+                let started = Instant::now();
+                set_status(&statuses, &name, WorkerState::Running, None);
+
+                //run the worker in this task (catching a panic), so aborting it on shutdown drops the worker as well
+                let result = AssertUnwindSafe(factory(cancel.child_token())).catch_unwind().await;
+                if cancel.is_cancelled() {
+                    if let Ok(Err(e)) = &result {
+                        warn!("<i>{}</>: worker failed during shutdown: <b>{}</>", name, e);
+                    }
+                    break;
+                }
+
+                let reason = match result {
+                    Ok(Ok(())) => "worker exited unexpectedly".to_string(),
+                    Ok(Err(e)) => format!("worker error: {}", e),
+                    Err(panic) => format!("worker panicked: {}", panic_message(panic.as_ref())),
+                };
+                if started.elapsed() > Duration::from_secs(SUPERVISOR_HEALTHY_RUN_SECS) {
+                    backoff = Duration::from_secs(SUPERVISOR_BACKOFF_INITIAL_SECS);
+                }
+                error!(
+                    "<i>{}</>: {}, restarting in <b>{}</>",
+                    name,
+                    reason,
+                    format_duration(backoff)
+                );
+                set_status(&statuses, &name, WorkerState::Backoff, Some(reason));
+
+                tokio::select! {
+                    _ = tokio::time::sleep(backoff) => {}
+                    _ = cancel.cancelled() => break,
+                }
+                backoff = (backoff * 2).min(Duration::from_secs(SUPERVISOR_BACKOFF_MAX_SECS));
+            }
+            set_status(&statuses, &name, WorkerState::Stopped, None);
+        });
+        self.handles.push((name, handle));
+    }
+
+    pub fn statuses(&self) -> Vec<WorkerStatus> {
+        let mut res: Vec<WorkerStatus> = self.statuses.lock().unwrap().values().cloned().collect();
+        res.sort_by(|a, b| a.name.cmp(&b.name));
+        res
+    }
+
+    /// Cancels all workers and waits for them at most `deadline`, aborting the ones which
+    /// didn't finish in time.
+    pub async fn shutdown(self, deadline: Duration) {
+        self.cancel.cancel();
+
+        let mut handles = self.handles;
+        if timeout(deadline, join_all(handles.iter_mut().map(|(_, h)| h))).await.is_err() {
+            let statuses = self.statuses.lock().unwrap();
+            for (name, handle) in &handles {
+                if statuses.get(name).map(|s| s.state) != Some(WorkerState::Stopped) {
+                    warn!("<i>{}</>: still running after <b>{}</>, aborting", name, format_duration(deadline));
+                }
+                handle.abort();
+            }
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(s) => s,
+        None => panic.downcast_ref::<String>().map(|s| s.as_str()).unwrap_or("unknown"),
+    }
+}
+
+fn set_status(
+    statuses: &Arc<Mutex<HashMap<String, WorkerStatus>>>,
+    name: &str,
+    state: WorkerState,
+    error: Option<String>,
+) {
+    let mut statuses = statuses.lock().unwrap();
+    let status = statuses.entry(name.to_string()).or_insert(WorkerStatus {
+        name: name.to_string(),
+        state,
+        restarts: 0,
+        last_error: None,
+        since: Instant::now(),
+    });
+    if state == WorkerState::Backoff {
+        status.restarts += 1;
+    }
+    if status.state != state {
+        debug!("<i>{}</>: worker state: {:?} -> {:?}", name, status.state, state);
+        status.state = state;
+        status.since = Instant::now();
+    }
+    if error.is_some() {
+        status.last_error = error;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn status(supervisor: &Supervisor, name: &str) -> WorkerStatus {
+        supervisor.statuses().into_iter().find(|s| s.name == name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn restart_backoff() {
+        tokio::time::pause();
+        let start = tokio::time::Instant::now();
+        let starts = Arc::new(Mutex::new(vec![]));
+        let mut supervisor = Supervisor::new();
+        let worker_starts = starts.clone();
+        supervisor.spawn("failing", move |_| {
+            worker_starts.lock().unwrap().push(start.elapsed().as_secs());
+            async { Err("no connection".into()) }
+        });
+        supervisor.spawn("panicking", |_| async { panic!("boom") });
+
+        tokio::time::sleep(Duration::from_secs(20)).await;
+        //2, 4 and 8 secs between the restarts
+        assert_eq!(*starts.lock().unwrap(), vec![0, 2, 6, 14]);
+        let failing = status(&supervisor, "failing");
+        assert_eq!((failing.state, failing.restarts), (WorkerState::Backoff, 4));
+        assert_eq!(failing.last_error.as_deref(), Some("worker error: no connection"));
+        assert_eq!(status(&supervisor, "panicking").last_error.as_deref(), Some("worker panicked: boom"));
+
+        supervisor.shutdown(Duration::from_secs(SUPERVISOR_SHUTDOWN_DEADLINE_SECS)).await;
+        assert_eq!(start.elapsed().as_secs(), 20);
+    }
+
+    /// Sets the flag when the worker is dropped
+    struct DropFlag(Arc<AtomicBool>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_deadline() {
+        tokio::time::pause();
+        let mut supervisor = Supervisor::new();
+        supervisor.spawn("cooperative", |cancel| async move {
+            cancel.cancelled().await;
+            Ok(())
+        });
+        let dropped = Arc::new(AtomicBool::new(false));
+        let flag = dropped.clone();
+        supervisor.spawn("stuck", move |_| {
+            let flag = DropFlag(flag.clone());
+            async move {
+                let _flag = flag;
+                //ignores the cancellation
+                tokio::time::sleep(Duration::from_secs(3600)).await;
+                Ok(())
+            }
+        });
+        tokio::task::yield_now().await;
+        assert_eq!(status(&supervisor, "stuck").state, WorkerState::Running);
+
+        let start = tokio::time::Instant::now();
+        let statuses = supervisor.statuses.clone();
+        supervisor.shutdown(Duration::from_secs(SUPERVISOR_SHUTDOWN_DEADLINE_SECS)).await;
+        assert_eq!(start.elapsed().as_secs(), SUPERVISOR_SHUTDOWN_DEADLINE_SECS);
+        assert_eq!(statuses.lock().unwrap()["cooperative"].state, WorkerState::Stopped);
+        //the aborted task drops the worker
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert!(dropped.load(Ordering::SeqCst));
+    }
+}
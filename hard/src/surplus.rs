@@ -0,0 +1,382 @@
+use simplelog::*;
+
+use humantime::format_duration;
+use hyper::{Client, Uri};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use ::sun2000::options::{self, OPTION_MAX_SECS};
+use ::sun2000::shared::*;
+
+use super::Result;
+
+pub const SURPLUS_HYSTERESIS_W: f64 = 200.0; //default: margin around the switching thresholds
+pub const SURPLUS_MIN_ON_SECS: u64 = 300; //default: min time a load stays on
+pub const SURPLUS_MIN_OFF_SECS: u64 = 300; //default: min time a load stays off
+pub const SURPLUS_STALE_SECS: u64 = 60; //all loads are switched off without fresh meter data
+pub const SURPLUS_ACTUATOR_TIMEOUT_SECS: u64 = 10; //max time for a single actuator command
+
+/// The way a load is switched on/off
+#[derive(Clone, Debug)]
+pub enum Actuator {
+    /// only logs the state, useful for testing
+    Log,
+    /// shell command, `%state%` is replaced with `on`/`off` and `%load%` with the load name
+    Script(String),
+    /// HTTP GET of the url, with the same replacements as for `Script`
+    Http(String),
+    /// MQTT (3.1.1, QoS 0) publish of `payload_on`/`payload_off` to the topic
+    Mqtt {
+        host: String,
+        topic: String,
+        payload_on: String,
+        payload_off: String,
+    },
+}
+
+fn expand(template: &str, name: &str, on: bool) -> String {
+    template
+        .replace("%state%", if on { "on" } else { "off" })
+        .replace("%load%", name)
+}
+
+impl Actuator {
+    async fn set(&self, name: &str, on: bool) -> Result<()> {
+        match self {
+            Actuator::Log => {
+                info!("surplus: <i>{}</>: (log actuator) state: <b>{}</>", name, if on { "on" } else { "off" });
+                Ok(())
+            }
+            Actuator::Script(command) => {
+                let status = Command::new("sh").arg("-c").arg(expand(command, name, on)).status().await?;
+                if !status.success() {
+                    return Err(format!("script failed: {}", status).into());
+                }
+                Ok(())
+            }
+            Actuator::Http(url) => {
+                let uri: Uri = expand(url, name, on).parse()?;
+                let response = Client::new().get(uri).await?;
+                if !response.status().is_success() {
+                    return Err(format!("http status: {}", response.status()).into());
+                }
+                Ok(())
+            }
+            Actuator::Mqtt { host, topic, payload_on, payload_off } => {
+                let payload = if on { payload_on } else { payload_off };
+                mqtt_publish(host, &format!("hard-{}", name), topic, payload.as_bytes()).await
+            }
+        }
+    }
+}
+
+fn mqtt_remaining_length(mut len: usize, buf: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        buf.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn mqtt_string(s: &str, buf: &mut Vec<u8>) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn mqtt_packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    mqtt_remaining_length(body.len(), &mut packet);
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Minimal MQTT client: connect, publish a single QoS 0 message and disconnect
+async fn mqtt_publish(host: &str, client_id: &str, topic: &str, payload: &[u8]) -> Result<()> {
+    let mut stream = TcpStream::connect(host).await?;
+
+    //CONNECT: protocol name, level 4 (3.1.1), clean session, 60s keep alive
+    let mut body = vec![];
+    mqtt_string("MQTT", &mut body);
+    body.extend_from_slice(&[0x04, 0x02, 0x00, 0x3c]);
+    mqtt_string(client_id, &mut body);
+    stream.write_all(&mqtt_packet(0x10, &body)).await?;
+
+    let mut connack = [0u8; 4];
+    stream.read_exact(&mut connack).await?;
+    if connack[0] != 0x20 || connack[3] != 0 {
+        return Err(format!("mqtt connection refused, CONNACK: {:02X?}", connack).into());
+    }
+
+    let mut body = vec![];
+    mqtt_string(topic, &mut body);
+    body.extend_from_slice(payload);
+    stream.write_all(&mqtt_packet(0x30, &body)).await?;
+    stream.write_all(&[0xe0, 0x00]).await?;
+    Ok(())
+}
+
+/// Load switched according to the PV surplus
+#[derive(Clone)]
+pub struct SurplusLoad {
+    pub name: String,
+    /// lower value is switched on first and off last
+    pub priority: u32,
+    /// nominal consumption (W)
+    pub power: f64,
+    pub min_on: Duration,
+    pub min_off: Duration,
+    pub actuator: Actuator,
+    on: bool,
+    changed: Option<Instant>,
+}
+
+impl SurplusLoad {
+    /// Builds the load from its config options (`priority`, `power`, `min_on`, `min_off`, `actuator`
+    /// and the actuator specific `command`, `url`, `mqtt_host`, `topic`, `payload_on`, `payload_off`)
+    pub fn from_options<F: Fn(&str) -> Option<String>>(name: &str, get: F) -> Result<SurplusLoad> {
+        let secs = |option: &str, default: u64| -> Result<Duration> {
+            Ok(options::secs(&get, option, 0.0, OPTION_MAX_SECS)?.unwrap_or_else(|| Duration::from_secs(default)))
+        };
+        let required = |option: &str| get(option).ok_or_else(|| format!("missing {}", option));
+        let actuator = match get("actuator").as_deref().unwrap_or("log") {
+            "log" => Actuator::Log,
+            "script" => Actuator::Script(required("command")?),
+            "http" => Actuator::Http(required("url")?),
+            "mqtt" => Actuator::Mqtt {
+                host: required("mqtt_host")?,
+                topic: required("topic")?,
+                payload_on: get("payload_on").unwrap_or_else(|| "ON".into()),
+                payload_off: get("payload_off").unwrap_or_else(|| "OFF".into()),
+            },
+            other => return Err(format!("unknown actuator: {:?}", other).into()),
+        };
+        Ok(SurplusLoad {
+            name: name.to_string(),
+            priority: options::integer(&get, "priority", 0, u32::MAX)?.unwrap_or_default(),
+            power: options::number(&get, "power", 1.0, f64::MAX)?.ok_or("missing power")?,
+            min_on: secs("min_on", SURPLUS_MIN_ON_SECS)?,
+            min_off: secs("min_off", SURPLUS_MIN_OFF_SECS)?,
+            actuator,
+            on: false,
+            changed: None,
+        })
+    }
+
+    /// true when the min on/off time after the last change is over
+    fn can_switch(&self, now: Instant) -> bool {
+        match self.changed {
+            Some(t) => now.duration_since(t) >= if self.on { self.min_on } else { self.min_off },
+            None => true,
+        }
+    }
+
+    fn set_state(&mut self, on: bool, now: Instant) {
+        self.on = on;
+        self.changed = Some(now);
+    }
+}
+
+/// Switching decision: index of the load, its new state and the reason
+type Switch = (usize, bool, String);
+
+/// Switches loads on/off by priority to consume the power otherwise exported to the grid
+pub struct SurplusManager {
+    pub name: String,
+    pub hysteresis: f64,
+    pub loads: Vec<SurplusLoad>,
+    pub events: broadcast::Sender<Sun2000Event>,
+}
+
+impl SurplusManager {
+    async fn switch(&mut self, index: usize, on: bool, reason: &str) {
+        let load = &mut self.loads[index];
+        info!(
+            "<i>{}</>: switching <b>{}</> {} ({} W), {}",
+            self.name, load.name, if on { "on" } else { "off" }, load.power, reason
+        );
+        match timeout(Duration::from_secs(SURPLUS_ACTUATOR_TIMEOUT_SECS), load.actuator.set(&load.name, on)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("<i>{}</>: {}: actuator error: <b>{}</>", self.name, load.name, e),
+            Err(_) => error!("<i>{}</>: {}: actuator timeout", self.name, load.name),
+        }
+        //the state is changed even on error, so a failing actuator is not retried on every poll
+        load.set_state(on, Instant::now());
+    }
+
+    /// Makes at most one switching decision for the current export power (W, negative when importing),
+    /// so the meter can show the effect before the next one
+    fn decide(&self, export: f64, now: Instant) -> Option<Switch> {
+        if export < -self.hysteresis {
+            //importing: the least important load goes off first
+            let i = (0..self.loads.len()).rev().find(|&i| self.loads[i].on && self.loads[i].can_switch(now))?;
+            Some((i, false, format!("importing {:.0} W", -export)))
+        } else {
+            //the most important load which is off goes on first, lower priority loads have to wait for it
+            let i = (0..self.loads.len()).find(|&i| !self.loads[i].on)?;
+            let load = &self.loads[i];
+            if export >= load.power + self.hysteresis && load.can_switch(now) {
+                Some((i, true, format!("surplus {:.0} W", export)))
+            } else {
+                None
+            }
+        }
+    }
+
+    async fn regulate(&mut self, export: f64) {
+        if let Some((i, on, reason)) = self.decide(export, Instant::now()) {
+            self.switch(i, on, &reason).await;
+        }
+    }
+
+    async fn all_off(&mut self, reason: &str) {
+        for i in 0..self.loads.len() {
+            if self.loads[i].on {
+                self.switch(i, false, reason).await;
+            }
+        }
+    }
+
+    pub async fn worker(&mut self, cancel: CancellationToken) -> Result<()> {
+        info!("<i>{}</>: Starting task", self.name);
+        self.loads.sort_by_key(|l| l.priority);
+        for l in &self.loads {
+            info!(
+                "<i>{}</>: load <b>{}</>: priority {}, {} W, min on {}, min off {}, actuator: {:?}",
+                self.name, l.name, l.priority, l.power, format_duration(l.min_on), format_duration(l.min_off), l.actuator
+            );
+        }
+        //the real state of the loads is unknown after start
+        for i in 0..self.loads.len() {
+            self.switch(i, false, "initial state").await;
+        }
+
+        let mut rx = self.events.subscribe();
+        let mut last_data = Instant::now();
+        let mut stale = false;
+        let mut check = tokio::time::interval(Duration::from_secs(SURPLUS_STALE_SECS));
+        loop {
+            tokio::select! {
+                ev = rx.recv() => match ev {
+                    Ok(Sun2000Event::Snapshot(snapshot)) => {
+                        //positive: feeding to the grid, negative: obtaining from the grid
+                        //(a stale value, kept from before the meter became unreadable, is no meter data)
+                        if let Some(export) = snapshot.fresh_value("power_meter_active_power") {
+                            last_data = Instant::now();
+                            stale = false;
+                            self.regulate(export).await;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(n)) => debug!("<i>{}</>: skipped {} events", self.name, n),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = check.tick() => {
+                    if !stale && last_data.elapsed() > Duration::from_secs(SURPLUS_STALE_SECS) {
+                        warn!("<i>{}</>: no power meter data for {}, switching all loads off", self.name, format_duration(Duration::from_secs(SURPLUS_STALE_SECS)));
+                        stale = true;
+                        self.all_off("no meter data").await;
+                    }
+                }
+                _ = cancel.cancelled() => break,
+            }
+        }
+
+        self.all_off("shutdown").await;
+        info!("{}: task stopped", self.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, priority: u32, power: f64) -> SurplusLoad {
+        SurplusLoad::from_options(name, |option| match option {
+            "priority" => Some(priority.to_string()),
+            "power" => Some(power.to_string()),
+            _ => None,
+        })
+        .unwrap()
+    }
+
+    /// Manager of a 1000 W and a 500 W load with the log actuator
+    fn manager() -> SurplusManager {
+        SurplusManager {
+            name: "surplus".to_string(),
+            hysteresis: SURPLUS_HYSTERESIS_W,
+            loads: vec![load("boiler", 1, 1000.0), load("pool", 2, 500.0)],
+            events: broadcast::channel(1).0,
+        }
+    }
+
+    /// Applies the decision like the actuator would
+    fn step(m: &mut SurplusManager, export: f64, now: Instant) -> Option<(usize, bool)> {
+        let (i, on, _) = m.decide(export, now)?;
+        m.loads[i].set_state(on, now);
+        Some((i, on))
+    }
+
+    #[test]
+    fn switches_by_priority() {
+        let mut m = manager();
+        let now = Instant::now();
+        //enough for the pool, but the boiler goes first
+        assert_eq!(step(&mut m, 900.0, now), None);
+        assert_eq!(step(&mut m, 1300.0, now), Some((0, true)));
+        assert_eq!(step(&mut m, 800.0, now), Some((1, true)));
+        //importing: the pool goes off first, as soon as its min on time is over
+        let later = now + Duration::from_secs(SURPLUS_MIN_ON_SECS);
+        assert_eq!(step(&mut m, -500.0, later), Some((1, false)));
+        assert_eq!(step(&mut m, -500.0, later), Some((0, false)));
+        assert_eq!(step(&mut m, -500.0, later), None);
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut m = manager();
+        let now = Instant::now();
+        assert_eq!(step(&mut m, 1000.0 + SURPLUS_HYSTERESIS_W - 1.0, now), None);
+        assert_eq!(step(&mut m, 1000.0 + SURPLUS_HYSTERESIS_W, now), Some((0, true)));
+        //small import stays within the hysteresis
+        let later = now + Duration::from_secs(SURPLUS_MIN_ON_SECS);
+        assert_eq!(step(&mut m, -SURPLUS_HYSTERESIS_W, later), None);
+        assert_eq!(step(&mut m, -SURPLUS_HYSTERESIS_W - 1.0, later), Some((0, false)));
+    }
+
+    #[test]
+    fn min_on_and_off_time() {
+        let mut m = manager();
+        let start = Instant::now();
+        assert_eq!(step(&mut m, 2000.0, start), Some((0, true)));
+        let min_on = Duration::from_secs(SURPLUS_MIN_ON_SECS);
+        assert_eq!(step(&mut m, -1000.0, start + min_on - Duration::from_secs(1)), None);
+        let off = start + min_on;
+        assert_eq!(step(&mut m, -1000.0, off), Some((0, false)));
+        //the boiler has to stay off for a while, the pool waits for it
+        let min_off = Duration::from_secs(SURPLUS_MIN_OFF_SECS);
+        assert_eq!(step(&mut m, 2000.0, off + min_off - Duration::from_secs(1)), None);
+        assert_eq!(step(&mut m, 2000.0, off + min_off), Some((0, true)));
+    }
+
+    #[tokio::test]
+    async fn regulate_with_log_actuator() {
+        let mut m = manager();
+        m.regulate(1500.0).await;
+        assert!(m.loads[0].on && !m.loads[1].on);
+        m.all_off("test").await;
+        assert!(!m.loads[0].on);
+    }
+}
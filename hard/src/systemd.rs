@@ -0,0 +1,38 @@
+use simplelog::*;
+
+use sd_notify::NotifyState;
+use std::time::Duration;
+
+//all notifications are silently ignored when not running under systemd (no NOTIFY_SOCKET)
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        debug!("systemd: notify error: {}", e);
+    }
+}
+
+pub fn notify_ready() {
+    notify(&[NotifyState::Ready]);
+}
+
+pub fn notify_stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+pub fn ping_watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+pub fn set_status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+/// Returns how often the watchdog should be pinged (half of `WatchdogSec=`),
+/// or `None` if the watchdog is not enabled for this service.
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec / 2))
+    } else {
+        None
+    }
+}
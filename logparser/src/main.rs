@@ -1,85 +1,191 @@
+use std::collections::BTreeMap;
 use std::env;
 
-use chrono::Duration;
+use chrono::prelude::*;
 use clap::*;
 
 use simplelog::*;
-use chrono::prelude::*;
 
-use sun2000::params::*;
 use sun2000::dump::*;
+use sun2000::energy::*;
+use sun2000::params::*;
+use sun2000::tariff::*;
+use sun2000::upload::*;
+
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Report {
+    /// all parameters of every poll
+    Decode,
+    /// energy and costs per hour
+    Hourly,
+    /// energy and costs per day
+    Daily,
+    /// optimizer data and other files uploaded from the inverter
+    Files,
+}
 
-
-/// Simple program to greet a person
+/// Sun2000 dump files decoder and energy/cost reports
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
-    /// Name of the person to greet
+    /// First day to process (YYYY-MM-DD, dump files are stored by UTC date)
     #[clap(short, long)]
-    name: String,
+    from: NaiveDate,
+
+    /// Last day to process (defaults to --from)
+    #[clap(short, long)]
+    to: Option<NaiveDate>,
+
+    /// Report type
+    #[clap(short, long, arg_enum, default_value = "daily")]
+    report: Report,
+
+    /// Flat price of the energy imported from the grid (per kWh)
+    #[clap(long)]
+    import_price: Option<f64>,
+
+    /// Time-of-use import prices, eg: "weekday 07:00-13:00 0.89, all 22:00-06:00 0.35"
+    #[clap(long)]
+    import_windows: Option<String>,
 
-    /// Number of times to greet
-    #[clap(short, long, default_value_t = 1)]
-    count: u8,
+    /// Flat price of the energy exported to the grid (per kWh)
+    #[clap(long)]
+    export_price: Option<f64>,
+
+    /// Time-of-use export prices, same format as --import-windows
+    #[clap(long)]
+    export_windows: Option<String>,
+
+    /// Currency shown in the report
+    #[clap(long, default_value = "")]
+    currency: String,
 }
 
 fn main() {
     env::set_var("RUST_BACKTRACE", "full");
     sun2000::logging::init(None);
-    
+    let args = Args::parse();
+
     info!("🛡️ Welcome to hard (home automation rust-daemon)");
 
-    decode(2022,5,14);
+    let tariff = match get_tariff(&args) {
+        Ok(tariff) => tariff,
+        Err(e) => {
+            error!("invalid tariff: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    match args.report {
+        Report::Decode => {
+            for date in days(&args) {
+                decode(date.year(), date.month(), date.day());
+            }
+        }
+        Report::Hourly => report(&args, &tariff, |t| t.with_timezone(&Local).format("%Y-%m-%d %H:00").to_string()),
+        Report::Daily => report(&args, &tariff, day_key),
+        Report::Files => {
+            for date in days(&args) {
+                decode_files(date.year(), date.month(), date.day());
+            }
+        }
+    }
 }
 
-fn decode(year: i32, month: u32, day: u32) -> Vec<Parameter> {
+fn days(args: &Args) -> Vec<NaiveDate> {
+    let to = args.to.unwrap_or(args.from);
+    let mut res = vec![];
+    let mut date = args.from;
+    while date <= to {
+        res.push(date);
+        date = date.succ();
+    }
+    res
+}
 
-    use std::fs::*;
-    use std::io::*;
+fn get_tariff(args: &Args) -> Result<Tariff, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(Tariff {
+        currency: args.currency.clone(),
+        import_price: args.import_price.unwrap_or_default(),
+        export_price: args.export_price.unwrap_or_default(),
+        import_windows: parse_tariff_windows(args.import_windows.as_deref().unwrap_or_default())?,
+        export_windows: parse_tariff_windows(args.export_windows.as_deref().unwrap_or_default())?,
+    })
+}
 
-    let path = format!("sun2000_{}_{}_{}.bin", year, month, day);
-    let start_of_day: DateTime<chrono::Utc> = DateTime::<Utc>::from_utc(NaiveDateTime::new(NaiveDate::from_ymd(year, month, day), NaiveTime::from_hms_milli(0, 0, 0, 0)), Utc);
+/// Replays the dumps through the energy ledger and prints totals grouped by `period`
+fn report(args: &Args, tariff: &Tariff, period: fn(&DateTime<Utc>) -> String) {
+    let mut ledger = EnergyLedger::default();
+    let mut periods: BTreeMap<String, EnergyTotals> = BTreeMap::new();
+    let mut total = EnergyTotals::default();
+
+    for date in days(args) {
+        for (time, params) in read_day(date.year(), date.month(), date.day()) {
+            if let Some(interval) = ledger.update(time, &params, Some(tariff)) {
+                periods.entry(period(&time)).or_default().add(&interval.totals);
+                total.add(&interval.totals);
+            }
+        }
+    }
 
-    let mut len_header: [u8;2] = [0,0];
-    let mut buff: [u8;4096] = [0; 4096];
+    println!(
+        "{:<16} {:>10} {:>11} {:>8} {:>8} {:>9} {:>11} {:>11} {:>9}",
+        "period", "prod. kWh", "cons. kWh", "imp. kWh", "exp. kWh", "self kWh",
+        format!("import {}", tariff.currency).trim(),
+        format!("export {}", tariff.currency).trim(),
+        format!("saved {}", tariff.currency).trim(),
+    );
+    for (name, totals) in periods.iter().chain(std::iter::once((&"total".to_string(), &total))) {
+        println!(
+            "{:<16} {:>10.2} {:>11.2} {:>8.2} {:>8.2} {:>9.2} {:>11.2} {:>11.2} {:>9.2}",
+            name,
+            totals.production,
+            totals.consumption,
+            totals.import,
+            totals.export,
+            totals.self_consumed,
+            totals.import_cost,
+            totals.export_revenue,
+            totals.savings,
+        );
+    }
+}
 
+fn decode(year: i32, month: u32, day: u32) -> Vec<Parameter> {
     let mut res: Vec<Parameter> = vec![];
 
-    if let Ok(mut f) = OpenOptions::new().read(true).open(path) {
-        while f.read(&mut len_header).unwrap() > 0 {
-            let len: usize = u16::from_le_bytes(len_header) as usize;
-            if f.read(&mut buff[0..len]).unwrap() == len {
-                let record: DiskRecord = postcard::from_bytes(&buff[0..len]).unwrap();
-                
-                
-                let timestamp = start_of_day +  Duration::milliseconds(record.time_offset_from_day_start_millis as i64);
-                
-
-                let mut prev_addr = record.base_address;
-                for v in &record.params {
-                    prev_addr = prev_addr + v.addr_offset;
-                    let p_ = PARAMETER_MAP.get(&prev_addr);
-                    match p_ {
-                        Some(&p) => {
-                            let mut param: Parameter = p.clone();
-                            param.value = v.value.clone();
-
-                            println!("{} {:?}", &timestamp, &param);
-
-                            res.push(param);
-                            
-                        },
-                        None => {
-                            error!("PARAMETER NOT FOUND {} {:?}", prev_addr, &record);
+    for (timestamp, params) in read_day(year, month, day) {
+        for param in params {
+            println!("{} {:?}", &timestamp, &param);
+            res.push(param);
+        }
+    }
+
+    return res
+}
+
+fn decode_files(year: i32, month: u32, day: u32) {
+    for (timestamp, file_type, data) in read_files_day(year, month, day) {
+        match file_type {
+            FILE_OPTIMIZER_REAL_TIME_DATA => match parse_optimizer_real_time_data(&data) {
+                Ok(samples) => {
+                    for sample in samples {
+                        for o in sample.optimizers {
+                            println!("{} {:?}", sample.time, o);
                         }
                     }
                 }
-            } else {
-                error!("ERROR PARSING, NOT ENOUGH DATA {} {:?}", len, f.stream_position());
-            }
+                Err(e) => error!("{}: invalid optimizer real-time data: {}", timestamp, e),
+            },
+            FILE_OPTIMIZER_SYSTEM_INFO => match parse_optimizer_system_info(&data) {
+                Ok(info) => {
+                    for i in info {
+                        println!("{} {:?}", timestamp, i);
+                    }
+                }
+                Err(e) => error!("{}: invalid optimizer system information: {}", timestamp, e),
+            },
+            _ => println!("{} file 0x{:02X}: {} bytes {:02X?}", timestamp, file_type, data.len(), data),
         }
     }
-
-    
-    return res
-}
\ No newline at end of file
+}
@@ -0,0 +1,303 @@
+use chrono::prelude::*;
+use std::fmt;
+
+use super::tariff::*;
+use super::options;
+use super::Result;
+
+pub const BATTERY_WORKING_MODE_REGISTER: u16 = 47004; //storage working mode, U16
+pub const BATTERY_FORCED_PERIOD_REGISTER: u16 = 47083; //forced charging/discharging period (min), U16
+pub const BATTERY_FORCED_POWER_REGISTER: u16 = 47084; //forced charging (+) / discharging (-) power (W), I32
+pub const BATTERY_FORCIBLE_COMMAND_REGISTER: u16 = 47100; //forcible charge/discharge: 0 stop, 1 charge, 2 discharge, U16
+pub const BATTERY_SOC_REGISTER: u16 = 37760; //state of capacity (%), U16, gain 10
+pub const BATTERY_SOC_HYSTERESIS: f64 = 2.0; //% below/above the cutoff needed to start charging/discharging again
+pub const BATTERY_SOC_MAX_AGE_MINS: i64 = 10; //last known SOC is used for this long when the SOC read fails
+pub const BATTERY_PRICE_PERIOD_MINS: u32 = 15; //forced period of price driven charging, re-evaluated after
+pub const BATTERY_FORCED_MODE: u16 = 1; //default working mode for forced windows: fixed charge/discharge
+pub const BATTERY_IDLE_MODE: u16 = 2; //default working mode outside of forced windows: maximise self consumption
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BatteryAction {
+    Idle,
+    /// forced charging with the power (W)
+    Charge(u32),
+    /// forced discharging with the power (W)
+    Discharge(u32),
+}
+
+impl fmt::Display for BatteryAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatteryAction::Idle => write!(f, "idle"),
+            BatteryAction::Charge(p) => write!(f, "charge {} W", p),
+            BatteryAction::Discharge(p) => write!(f, "discharge {} W", p),
+        }
+    }
+}
+
+/// Forced charging/discharging in a weekly time window
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatteryWindow {
+    pub window: TimeWindow,
+    pub action: BatteryAction,
+}
+
+/// Parses a comma separated list of windows, eg:
+/// `all 01:00-05:00 charge 3000, weekday 17:00-21:00 discharge 2500`
+pub fn parse_battery_windows(s: &str) -> Result<Vec<BatteryWindow>> {
+    let mut res = vec![];
+    for window in s.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        let fields: Vec<&str> = window.split_whitespace().collect();
+        let (days, range, action, power) = match fields[..] {
+            [days, range, action, power] => (days, range, action, power),
+            [range, action, power] => ("all", range, action, power),
+            _ => return Err(format!("invalid battery window: {:?}", window).into()),
+        };
+        let power: u32 = power.parse()?;
+        let action = match action {
+            "charge" => BatteryAction::Charge(power),
+            "discharge" => BatteryAction::Discharge(power),
+            _ => return Err(format!("invalid battery action {:?} in {:?}", action, window).into()),
+        };
+        res.push(BatteryWindow {
+            window: TimeWindow::parse(days, range).map_err(|e| format!("{} in {:?}", e, window))?,
+            action,
+        });
+    }
+    Ok(res)
+}
+
+/// Settings of the battery time-of-use scheduler
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatteryScheduleConfig {
+    pub windows: Vec<BatteryWindow>,
+    /// charge whenever the tariff import price is at most this value
+    pub charge_price: Option<f64>,
+    /// charging power for the price driven charging (W)
+    pub charge_power: u32,
+    /// max SOC for forced charging (%)
+    pub charge_cutoff: f64,
+    /// min SOC for forced discharging (%)
+    pub discharge_cutoff: f64,
+    pub forced_mode: u16,
+    pub idle_mode: u16,
+}
+
+impl BatteryScheduleConfig {
+    /// Builds the config from options (`battery_schedule`, `battery_charge_price`, `battery_charge_power`,
+    /// `battery_charge_cutoff`, `battery_discharge_cutoff`, `battery_forced_mode`, `battery_idle_mode`).
+    /// Returns `None` when neither a schedule nor a charge price is set.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<Option<BatteryScheduleConfig>> {
+        let percent = |name: &str| options::number(&get, name, 0.0, 100.0);
+        let mode = |name: &str| options::integer(&get, name, 0, u16::MAX as u32);
+        let windows = match get("battery_schedule") {
+            Some(v) => parse_battery_windows(&v)?,
+            None => vec![],
+        };
+        //negative with dynamic tariffs
+        let charge_price = options::number(&get, "battery_charge_price", f64::MIN, f64::MAX)?;
+        if windows.is_empty() && charge_price.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(BatteryScheduleConfig {
+            windows,
+            charge_price,
+            charge_power: options::integer(&get, "battery_charge_power", 0, i32::MAX as u32)?.unwrap_or(2500),
+            charge_cutoff: percent("battery_charge_cutoff")?.unwrap_or(100.0),
+            discharge_cutoff: percent("battery_discharge_cutoff")?.unwrap_or(10.0),
+            forced_mode: mode("battery_forced_mode")?.map(|m| m as u16).unwrap_or(BATTERY_FORCED_MODE),
+            idle_mode: mode("battery_idle_mode")?.map(|m| m as u16).unwrap_or(BATTERY_IDLE_MODE),
+        }))
+    }
+}
+
+/// Change of the battery operation to be written to the inverter
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatteryDecision {
+    pub action: BatteryAction,
+    /// forced period (min), 0 for `Idle`
+    pub minutes: u32,
+    pub reason: String,
+}
+
+impl BatteryDecision {
+    pub fn working_mode(&self, config: &BatteryScheduleConfig) -> u16 {
+        match self.action {
+            BatteryAction::Idle => config.idle_mode,
+            _ => config.forced_mode,
+        }
+    }
+
+    /// Value of the forcible charge/discharge command register: the period and power registers only
+    /// set up the forced operation, it is started (or stopped) by this command
+    pub fn forcible_command(&self) -> u16 {
+        match self.action {
+            BatteryAction::Idle => 0,
+            BatteryAction::Charge(_) => 1,
+            BatteryAction::Discharge(_) => 2,
+        }
+    }
+
+    /// Value of the forced power register, positive for charging
+    pub fn forced_power(&self) -> i32 {
+        match self.action {
+            BatteryAction::Idle => 0,
+            BatteryAction::Charge(p) => p as i32,
+            BatteryAction::Discharge(p) => -(p as i32),
+        }
+    }
+}
+
+/// Decides the forced charging/discharging of the battery from the weekly schedule
+/// or the tariff windows, respecting the SOC cutoffs
+pub struct BatteryScheduler {
+    pub config: BatteryScheduleConfig,
+    current: Option<BatteryAction>,
+    expires: Option<DateTime<Utc>>,
+    /// last successfully read SOC with its time
+    last_soc: Option<(f64, DateTime<Utc>)>,
+}
+
+impl BatteryScheduler {
+    pub fn new(config: BatteryScheduleConfig) -> Self {
+        Self {
+            config,
+            current: None,
+            expires: None,
+            last_soc: None,
+        }
+    }
+
+    /// The state of the inverter is unknown (eg. after reconnect or a failed write),
+    /// the decision has to be written again on the next update
+    pub fn reset(&mut self) {
+        self.current = None;
+        self.expires = None;
+    }
+
+    fn planned(&self, now: &DateTime<Utc>, tariff: Option<&Tariff>) -> Option<(BatteryAction, u32, String)> {
+        let local = now.with_timezone(&Local);
+        if let Some(w) = self.config.windows.iter().find(|w| w.window.matches(&local)) {
+            let reason = format!(
+                "schedule window {:02}:{:02}-{:02}:{:02}",
+                w.window.start / 60, w.window.start % 60, w.window.end / 60, w.window.end % 60
+            );
+            return Some((w.action, w.window.minutes_left(&local), reason));
+        }
+        if let (Some(max_price), Some(tariff)) = (self.config.charge_price, tariff) {
+            let price = tariff.import_price_at(now);
+            if price <= max_price {
+                let reason = format!("import price {:.3} <= {:.3} {}", price, max_price, tariff.currency);
+                return Some((BatteryAction::Charge(self.config.charge_power), BATTERY_PRICE_PERIOD_MINS, reason));
+            }
+        }
+        None
+    }
+
+    /// SOC to decide with: the read one, or the last known one when the read failed recently,
+    /// so a single failed read doesn't interrupt a forced window
+    fn soc(&mut self, now: DateTime<Utc>, soc: Option<f64>) -> Option<f64> {
+        match soc {
+            Some(soc) => {
+                self.last_soc = Some((soc, now));
+                Some(soc)
+            }
+            None => match self.last_soc {
+                Some((soc, time)) if now - time <= chrono::Duration::minutes(BATTERY_SOC_MAX_AGE_MINS) => Some(soc),
+                _ => None,
+            },
+        }
+    }
+
+    /// Returns the new decision when the battery operation has to change (or be renewed)
+    pub fn update(&mut self, now: DateTime<Utc>, soc: Option<f64>, tariff: Option<&Tariff>) -> Option<BatteryDecision> {
+        let soc = self.soc(now, soc);
+        let (action, minutes, reason) = match (self.planned(&now, tariff), soc) {
+            (None, _) => (BatteryAction::Idle, 0, "no active window".to_string()),
+            (Some(_), None) => (BatteryAction::Idle, 0, format!("SOC is unknown for more than {} min", BATTERY_SOC_MAX_AGE_MINS)),
+            (Some((BatteryAction::Charge(p), minutes, reason)), Some(soc)) => {
+                //hysteresis: charging continues up to the cutoff, but starts only below it
+                let cutoff = match self.current {
+                    Some(BatteryAction::Charge(_)) => self.config.charge_cutoff,
+                    _ => self.config.charge_cutoff - BATTERY_SOC_HYSTERESIS,
+                };
+                if soc >= cutoff {
+                    (BatteryAction::Idle, 0, format!("SOC {:.1}% reached charge cutoff {:.1}%", soc, self.config.charge_cutoff))
+                } else {
+                    (BatteryAction::Charge(p), minutes, reason)
+                }
+            }
+            (Some((BatteryAction::Discharge(p), minutes, reason)), Some(soc)) => {
+                let cutoff = match self.current {
+                    Some(BatteryAction::Discharge(_)) => self.config.discharge_cutoff,
+                    _ => self.config.discharge_cutoff + BATTERY_SOC_HYSTERESIS,
+                };
+                if soc <= cutoff {
+                    (BatteryAction::Idle, 0, format!("SOC {:.1}% reached discharge cutoff {:.1}%", soc, self.config.discharge_cutoff))
+                } else {
+                    (BatteryAction::Discharge(p), minutes, reason)
+                }
+            }
+            (Some((BatteryAction::Idle, _, reason)), _) => (BatteryAction::Idle, 0, reason),
+        };
+
+        //forced period set in the inverter is over, but the action continues (eg. adjacent windows)
+        let expired = match self.expires {
+            Some(t) => now >= t,
+            None => false,
+        };
+        if self.current == Some(action) && !expired {
+            return None;
+        }
+
+        self.current = Some(action);
+        self.expires = if minutes > 0 {
+            Some(now + chrono::Duration::minutes(minutes as i64))
+        } else {
+            None
+        };
+        Some(BatteryDecision { action, minutes, reason })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler() -> BatteryScheduler {
+        BatteryScheduler::new(BatteryScheduleConfig {
+            windows: parse_battery_windows("00:00-24:00 charge 3000").unwrap(),
+            charge_price: None,
+            charge_power: 2500,
+            charge_cutoff: 90.0,
+            discharge_cutoff: 10.0,
+            forced_mode: BATTERY_FORCED_MODE,
+            idle_mode: BATTERY_IDLE_MODE,
+        })
+    }
+
+    #[test]
+    fn failed_soc_read_keeps_charging() {
+        let mut scheduler = scheduler();
+        let now = Utc::now();
+        let decision = scheduler.update(now, Some(50.0), None).unwrap();
+        assert_eq!(decision.action, BatteryAction::Charge(3000));
+        assert_eq!(decision.forcible_command(), 1);
+        //the last known SOC is used, nothing changes
+        assert_eq!(scheduler.update(now + chrono::Duration::minutes(1), None, None), None);
+        //too old, charging stops
+        let decision = scheduler.update(now + chrono::Duration::minutes(BATTERY_SOC_MAX_AGE_MINS + 1), None, None).unwrap();
+        assert_eq!(decision.action, BatteryAction::Idle);
+        assert_eq!(decision.forcible_command(), 0);
+    }
+
+    #[test]
+    fn charge_cutoff_hysteresis() {
+        let mut scheduler = scheduler();
+        let now = Utc::now();
+        assert_eq!(scheduler.update(now, Some(89.0), None).unwrap().action, BatteryAction::Idle);
+        assert_eq!(scheduler.update(now, Some(87.0), None).unwrap().action, BatteryAction::Charge(3000));
+        assert_eq!(scheduler.update(now, Some(89.5), None), None);
+        assert_eq!(scheduler.update(now, Some(90.0), None).unwrap().action, BatteryAction::Idle);
+    }
+}
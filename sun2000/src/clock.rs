@@ -0,0 +1,218 @@
+use chrono::prelude::*;
+use std::time::Duration;
+
+use super::options::{self, OPTION_MAX_SECS};
+use super::Result;
+
+pub const SYSTEM_TIME_REGISTER: u16 = 40000; //inverter local time (time zone offset included) as epoch secs, U32
+pub const TIME_ZONE_REGISTER: u16 = 43006; //offset from UTC in minutes, I16
+pub const CLOCK_CHECK_INTERVAL_SECS: f64 = 3600.0; //default: secs between the clock checks
+pub const CLOCK_MAX_DRIFT_SECS: f64 = 30.0; //default: larger drift is corrected (when enabled)
+
+/// Settings of the inverter clock check. The inverter resets its daily counters
+/// on its own midnight, so a drifting clock shifts the daily energy.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockConfig {
+    /// write the host time when the drift exceeds `max_drift`, otherwise it is only reported
+    pub sync: bool,
+    pub max_drift: Duration,
+    pub check_interval: Duration,
+    /// only write the time when the host clock is synchronized by NTP
+    pub require_ntp: bool,
+}
+
+impl Default for ClockConfig {
+    fn default() -> Self {
+        Self {
+            sync: false,
+            max_drift: Duration::from_secs_f64(CLOCK_MAX_DRIFT_SECS),
+            check_interval: Duration::from_secs_f64(CLOCK_CHECK_INTERVAL_SECS),
+            require_ntp: true,
+        }
+    }
+}
+
+impl ClockConfig {
+    /// Builds the config from options (`clock_sync`, `clock_max_drift`, `clock_check_interval`,
+    /// `clock_require_ntp`), times in secs. Missing options keep the defaults.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<ClockConfig> {
+        let secs = |name: &str, default: Duration| -> Result<Duration> {
+            Ok(options::secs(&get, name, 1.0, OPTION_MAX_SECS)?.unwrap_or(default))
+        };
+        let default = ClockConfig::default();
+        Ok(ClockConfig {
+            sync: options::flag(&get, "clock_sync")?.unwrap_or(default.sync),
+            max_drift: secs("clock_max_drift", default.max_drift)?,
+            check_interval: secs("clock_check_interval", default.check_interval)?,
+            require_ntp: options::flag(&get, "clock_require_ntp")?.unwrap_or(default.require_ntp),
+        })
+    }
+}
+
+/// Inverter clock as read from the registers
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InverterClock {
+    /// local time as epoch secs
+    pub local_time: u32,
+    /// offset from UTC (minutes)
+    pub time_zone: i16,
+}
+
+impl InverterClock {
+    /// Parses the system time (2 registers) and the time zone (1 register)
+    pub fn from_registers(time: &[u16], zone: &[u16]) -> Option<InverterClock> {
+        match (time, zone) {
+            ([hi, lo], [zone]) => Some(InverterClock {
+                local_time: ((*hi as u32) << 16) | *lo as u32,
+                time_zone: *zone as i16,
+            }),
+            _ => None,
+        }
+    }
+
+    /// The clock set to `now` in the time zone `offset` (minutes)
+    pub fn at(now: DateTime<Utc>, offset: i16) -> InverterClock {
+        InverterClock {
+            local_time: (now.timestamp() + offset as i64 * 60) as u32,
+            time_zone: offset,
+        }
+    }
+
+    pub fn utc(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.local_time as i64 - self.time_zone as i64 * 60, 0)
+    }
+
+    /// Secs the inverter clock is ahead of `now` (negative: behind)
+    pub fn drift(&self, now: DateTime<Utc>) -> f64 {
+        (self.utc() - now).num_milliseconds() as f64 / 1000.0
+    }
+
+    pub fn time_registers(&self) -> [u16; 2] {
+        [(self.local_time >> 16) as u16, (self.local_time & 0xffff) as u16]
+    }
+}
+
+/// Offset of the host local time from UTC at `now` (minutes)
+pub fn host_time_zone(now: DateTime<Utc>) -> i16 {
+    (Local.offset_from_utc_datetime(&now.naive_utc()).fix().local_minus_utc() / 60) as i16
+}
+
+/// Whether the host clock is synchronized by NTP (as told by systemd), false when it can't be told
+pub async fn host_ntp_synchronized() -> bool {
+    let output = tokio::process::Command::new("timedatectl")
+        .args(["show", "--property=NTPSynchronized", "--value"])
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => String::from_utf8_lossy(&output.stdout).trim() == "yes",
+        _ => false,
+    }
+}
+
+/// Result of a clock check
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClockStatus {
+    pub time: DateTime<Utc>,
+    /// secs the inverter clock is ahead of the host
+    pub drift: f64,
+    pub inverter_time_zone: i16,
+    pub host_time_zone: i16,
+    /// the host clock is synchronized by NTP
+    pub host_synchronized: bool,
+    /// time of the last correction
+    pub last_sync: Option<DateTime<Utc>>,
+}
+
+impl ClockStatus {
+    pub fn new(clock: &InverterClock, now: DateTime<Utc>, host_synchronized: bool) -> Self {
+        Self {
+            time: now,
+            drift: clock.drift(now),
+            inverter_time_zone: clock.time_zone,
+            host_time_zone: host_time_zone(now),
+            host_synchronized,
+            last_sync: None,
+        }
+    }
+
+    /// The inverter clock or its time zone is off
+    pub fn is_off(&self, config: &ClockConfig) -> bool {
+        self.drift.abs() > config.max_drift.as_secs_f64() || self.inverter_time_zone != self.host_time_zone
+    }
+
+    /// Reason why the clock is not corrected, `None` when it should be
+    pub fn sync_blocked(&self, config: &ClockConfig) -> Option<&'static str> {
+        if !config.sync {
+            Some("clock_sync is disabled")
+        } else if config.require_ntp && !self.host_synchronized {
+            Some("the host clock is not NTP synchronized")
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(drift: f64, inverter_time_zone: i16, host_synchronized: bool) -> ClockStatus {
+        ClockStatus {
+            time: Utc.ymd(2024, 6, 21).and_hms(12, 0, 0),
+            drift,
+            inverter_time_zone,
+            host_time_zone: 120,
+            host_synchronized,
+            last_sync: None,
+        }
+    }
+
+    #[test]
+    fn registers_round_trip() {
+        let now = Utc.ymd(2024, 6, 21).and_hms(12, 0, 0);
+        for zone in [120, 0, -300] {
+            let clock = InverterClock::at(now, zone);
+            assert_eq!(clock.local_time as i64, now.timestamp() + zone as i64 * 60);
+            assert_eq!(InverterClock::from_registers(&clock.time_registers(), &[zone as u16]), Some(clock));
+            assert_eq!(clock.utc(), now);
+            assert_eq!(clock.drift(now - chrono::Duration::seconds(45)), 45.0);
+            assert_eq!(clock.drift(now + chrono::Duration::milliseconds(1500)), -1.5);
+        }
+        assert_eq!(InverterClock::at(now, 0).time_registers(), [0x6675, 0x6B40]);
+        assert_eq!(InverterClock::from_registers(&[0x6675], &[0]), None);
+        assert_eq!(InverterClock::from_registers(&[0x6675, 0x6B40], &[]), None);
+    }
+
+    #[test]
+    fn clock_off() {
+        let config = ClockConfig::default();
+        assert!(!status(30.0, 120, true).is_off(&config));
+        assert!(status(-30.5, 120, true).is_off(&config));
+        //the wrong time zone shifts the inverter day
+        assert!(status(0.0, 60, true).is_off(&config));
+    }
+
+    #[test]
+    fn sync_blocked() {
+        let mut config = ClockConfig::default();
+        assert_eq!(status(100.0, 120, true).sync_blocked(&config), Some("clock_sync is disabled"));
+        config.sync = true;
+        assert_eq!(status(100.0, 120, false).sync_blocked(&config), Some("the host clock is not NTP synchronized"));
+        assert_eq!(status(100.0, 120, true).sync_blocked(&config), None);
+        config.require_ntp = false;
+        assert_eq!(status(100.0, 120, false).sync_blocked(&config), None);
+    }
+
+    #[test]
+    fn options() {
+        let config = ClockConfig::from_options(|name| match name {
+            "clock_sync" => Some("yes".to_string()),
+            "clock_max_drift" => Some("60".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(config, ClockConfig { sync: true, max_drift: Duration::from_secs(60), ..Default::default() });
+        //not silently disabled
+        assert!(ClockConfig::from_options(|name| Some("on".to_string()).filter(|_| name == "clock_sync")).is_err());
+    }
+}
@@ -5,10 +5,11 @@ use std::collections::HashMap;
 
 
 
+#[derive(Clone, Debug)]
 pub struct Alarm {
-    name: &'static str,
-    code: u16,
-    severity: &'static str,
+    pub name: &'static str,
+    pub code: u16,
+    pub severity: &'static str,
 }
 
 impl Alarm {
@@ -22,6 +23,7 @@ impl Alarm {
 }
 
 
+#[derive(Clone, Default)]
 pub struct Sun2000State {
     pub device_status: Option<u16>,
     pub storage_status: Option<i16>,
@@ -84,8 +86,8 @@ impl Sun2000State {
     }
 
     #[rustfmt::skip]
-    pub fn get_grid_code_description(code: u16) -> String {
-        let grid_code = match code {
+    pub fn get_grid_code(code: u16) -> (&'static str, &'static str) {
+        match code {
             0 => ("VDE-AR-N-4105", "Germany 🇩🇪"),
             1 => ("NB/T 32004", "China 🇨🇳"),
             2 => ("UTE C 15-712-1(A)", "France 🇫🇷"),
@@ -412,7 +414,11 @@ impl Sun2000State {
             328 => ("AUSTRALIA-AS4777_A-MV800", "Australia 🇦🇺"),
             329 => ("CHINA-GBT34120-MV800", "China 🇨🇳"),
             _ => ("unknown", "unknown"),
-        };
+        }
+    }
+
+    pub fn get_grid_code_description(code: u16) -> String {
+        let grid_code = Sun2000State::get_grid_code(code);
         format!("standard: <b><cyan>{}</>, country: <b><cyan>{}</>", grid_code.0, grid_code.1)
     }
 
@@ -490,9 +496,8 @@ impl Sun2000State {
     }
 
     #[rustfmt::skip]
-    pub fn get_alarm1_description(code: u16) -> String {
-        let mut descr = String::from("");
-        let alarm1_masks = vec! [
+    fn alarm1_masks() -> Vec<(u16, Alarm)> {
+        vec! [
             (0b0000_0000_0000_0001, Alarm::new("High String Input Voltage", 2001, "Major")),
             (0b0000_0000_0000_0010, Alarm::new("DC Arc Fault", 2002, "Major")),
             (0b0000_0000_0000_0100, Alarm::new("String Reverse Connection", 2011, "Major")),
@@ -509,8 +514,12 @@ impl Sun2000State {
             (0b0010_0000_0000_0000, Alarm::new("Unstable Grid Frequency", 2038, "Major")),
             (0b0100_0000_0000_0000, Alarm::new("Output Overcurrent", 2039, "Major")),
             (0b1000_0000_0000_0000, Alarm::new("Output DC Component Overhigh", 2040, "Major")),
-        ];
-        for mask in alarm1_masks {
+        ]
+    }
+
+    pub fn get_alarm1_description(code: u16) -> String {
+        let mut descr = String::from("");
+        for mask in Sun2000State::alarm1_masks() {
             if code & mask.0 > 0 {
                 descr = descr.add(
                     format!("code={} {:?} severity={}", mask.1.code, mask.1.name, mask.1.severity).as_str()
@@ -528,9 +537,8 @@ impl Sun2000State {
     }
 
     #[rustfmt::skip]
-    pub fn get_alarm2_description(code: u16) -> String {
-        let mut descr = String::from("");
-        let alarm2_masks = vec! [
+    fn alarm2_masks() -> Vec<(u16, Alarm)> {
+        vec! [
             (0b0000_0000_0000_0001, Alarm::new("Abnormal Residual Current", 2051, "Major")),
             (0b0000_0000_0000_0010, Alarm::new("Abnormal Grounding", 2061, "Major")),
             (0b0000_0000_0000_0100, Alarm::new("Low Insulation Resistance", 2062, "Major")),
@@ -547,8 +555,12 @@ impl Sun2000State {
             (0b0010_0000_0000_0000, Alarm::new("Peripheral port short circuit", 2075, "Warning")),
             (0b0100_0000_0000_0000, Alarm::new("Churn output overload", 2077, "Major")),
             (0b1000_0000_0000_0000, Alarm::new("Abnormal PV module configuration", 2080, "Major")),
-        ];
-        for mask in alarm2_masks {
+        ]
+    }
+
+    pub fn get_alarm2_description(code: u16) -> String {
+        let mut descr = String::from("");
+        for mask in Sun2000State::alarm2_masks() {
             if code & mask.0 > 0 {
                 descr = descr.add(
                     format!("code={} {:?} severity={}", mask.1.code, mask.1.name, mask.1.severity).as_str()
@@ -566,9 +578,8 @@ impl Sun2000State {
     }
 
     #[rustfmt::skip]
-    pub fn get_alarm3_description(code: u16) -> String {
-        let mut descr = String::from("");
-        let alarm3_masks = vec! [
+    fn alarm3_masks() -> Vec<(u16, Alarm)> {
+        vec! [
             (0b0000_0000_0000_0001, Alarm::new("Optimizer fault", 2081, "Warning")),
             (0b0000_0000_0000_0010, Alarm::new("Built-in PID operation abnormal", 2085, "Minor")),
             (0b0000_0000_0000_0100, Alarm::new("High input string voltage to ground", 2014, "Major")),
@@ -578,8 +589,12 @@ impl Sun2000State {
             (0b0000_0000_0100_0000, Alarm::new("PV String Loss", 2015, "Warning")),
             (0b0000_0000_1000_0000, Alarm::new("Internal Fan Abnormal", 2087, "Major")),
             (0b0000_0001_0000_0000, Alarm::new("DC Protection Unit Abnormal", 2088, "Major")),
-        ];
-        for mask in alarm3_masks {
+        ]
+    }
+
+    pub fn get_alarm3_description(code: u16) -> String {
+        let mut descr = String::from("");
+        for mask in Sun2000State::alarm3_masks() {
             if code & mask.0 > 0 {
                 descr = descr.add(
                     format!("code={} {:?} severity={}", mask.1.code, mask.1.name, mask.1.severity).as_str()
@@ -596,6 +611,26 @@ impl Sun2000State {
         }
     }
 
+    /// Returns all alarms which are currently signaled in the alarm registers
+    pub fn get_active_alarms(&self) -> Vec<Alarm> {
+        let mut alarms = vec![];
+        let registers = [
+            (self.alarm_1, Sun2000State::alarm1_masks()),
+            (self.alarm_2, Sun2000State::alarm2_masks()),
+            (self.alarm_3, Sun2000State::alarm3_masks()),
+        ];
+        for (code, masks) in registers {
+            if let Some(code) = code {
+                for mask in masks {
+                    if code & mask.0 > 0 {
+                        alarms.push(mask.1);
+                    }
+                }
+            }
+        }
+        alarms
+    }
+
     pub fn set_new_status(
         &mut self,
         thread_name: &String,
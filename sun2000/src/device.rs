@@ -0,0 +1,94 @@
+use futures::future::BoxFuture;
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use super::defs::*;
+use super::identity::*;
+use super::params::*;
+
+/// Values read from a device
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// parameters which were read, named and scaled as the ones of the SUN2000 register map
+    pub params: Vec<Parameter>,
+    /// parameters due in this read, the ones missing in `params` were not readable
+    pub expected: Vec<&'static str>,
+    /// read time
+    pub ms: u64,
+    /// the connection failed, as opposed to some of the values not being readable
+    pub disconnected: bool,
+}
+
+/// Inverter backend: connects to the device and reads it. The values are normalized to the SUN2000
+/// parameters, so the outputs (influx, dumps, http, energy accounting) are the same for all backends.
+pub trait Device: Send {
+    /// Opens the Modbus connection to the device
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<Context>>;
+
+    /// Reads the values which do not change (model, serial number, rated power), once after connecting
+    fn read_info<'a>(&'a mut self, ctx: &'a mut Context, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>>;
+
+    /// Reads the values due in this poll, or all of them when `full`
+    fn read_snapshot<'a>(&'a mut self, ctx: &'a mut Context, full: bool, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>>;
+
+    /// Identity of the device and of the devices behind it
+    fn read_identity<'a>(&'a mut self, ctx: &'a mut Context) -> BoxFuture<'a, Vec<DeviceIdentity>>;
+
+    /// Operating state (status, alarms) given by the parameters of a snapshot
+    fn state(&self, params: &[Parameter]) -> Sun2000State;
+}
+
+/// Backend and register map of the polled device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Huawei(DeviceType),
+    /// any inverter implementing the SunSpec information models (eg. SolarEdge)
+    SunSpec,
+}
+
+impl Backend {
+    /// Parses the `device` option: `inverter` (default), `smartlogger` or `sunspec`
+    pub fn parse(s: &str) -> Result<Backend, String> {
+        match s.trim().to_lowercase().as_str() {
+            "sunspec" => Ok(Backend::SunSpec),
+            other => DeviceType::parse(other).map(Backend::Huawei),
+        }
+    }
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Backend::Huawei(DeviceType::Inverter)
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Huawei(device_type) => write!(f, "{}", device_type),
+            Backend::SunSpec => write!(f, "sunspec"),
+        }
+    }
+}
+
+/// Opens a Modbus TCP connection, a timeout is reported as `ErrorKind::TimedOut`
+pub async fn connect_modbus(addr: SocketAddr, slave: Slave, connect_timeout: Duration) -> io::Result<Context> {
+    match timeout(connect_timeout, tcp::connect_slave(addr, slave)).await {
+        Ok(res) => res,
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+/// Parses the `host:port` of the device
+pub fn parse_host(host_port: &str) -> io::Result<SocketAddr> {
+    host_port
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, format!("invalid host {:?}: {}", host_port, e)))
+}
@@ -0,0 +1,170 @@
+use simplelog::*;
+use std::io;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use super::identity::*;
+use super::Result;
+
+pub const DISCOVERY_TIMEOUT_SECS: f64 = 1.5; //max wait for a probed slave, missing slaves usually just time out
+pub const DISCOVERY_CONNECT_TIMEOUT_SECS: u64 = 5;
+pub const DISCOVERY_MAX_SLAVE: u8 = 16; //default: slave IDs 0..=max are probed
+
+const MODEL_NAME_REGISTER: (u16, u16) = (30000, 15);
+const SERIAL_NUMBER_REGISTER: (u16, u16) = (30015, 10);
+const METER_STATUS_REGISTER: u16 = 37100; //0: offline, 1: normal
+const METER_TYPE_REGISTER: u16 = 37125; //0: single phase, 1: three phase
+const BATTERY_UNIT_STATUS_REGISTERS: [u16; 2] = [37000, 37741]; //running status of the units, 0: offline
+const BATTERY_SOC_REGISTER: u16 = 37760; //gain 10
+
+/// Device found at a slave ID
+#[derive(Clone, Debug, Default)]
+pub struct DiscoveredDevice {
+    pub slave: u8,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    /// devices described in the device identification of the slave
+    pub identities: Vec<DeviceIdentity>,
+    /// power meter connected to the inverter: three phase meter
+    pub meter: Option<bool>,
+    /// running battery units
+    pub battery_units: Vec<u8>,
+    pub battery_soc: Option<f64>,
+}
+
+impl DiscoveredDevice {
+    pub fn kind(&self) -> &'static str {
+        let identity = DeviceIdentity {
+            model: self.model.clone(),
+            ..Default::default()
+        };
+        match identity.kind() {
+            "device" => self.identities.first().map(|i| i.kind()).unwrap_or("device"),
+            kind => kind,
+        }
+    }
+}
+
+/// Parses the slave ID range, eg: `1-16` or `3`
+pub fn parse_slave_range(s: &str) -> Result<(u8, u8)> {
+    let (first, last) = match s.trim().split_once('-') {
+        Some((first, last)) => (first.trim().parse()?, last.trim().parse()?),
+        None => {
+            let slave = s.trim().parse()?;
+            (slave, slave)
+        }
+    };
+    if first > last || last > 247 {
+        return Err(format!("invalid slave range: {:?}", s).into());
+    }
+    Ok((first, last))
+}
+
+fn registers_to_text(data: &[u16]) -> String {
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_be_bytes()).filter(|b| *b != 0).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+async fn read(ctx: &mut Context, addr: u16, len: u16) -> io::Result<Vec<u16>> {
+    match timeout(Duration::from_secs_f64(DISCOVERY_TIMEOUT_SECS), ctx.read_holding_registers(addr, len)).await {
+        Ok(res) => res,
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+async fn read_u16(ctx: &mut Context, addr: u16) -> Option<u16> {
+    read(ctx, addr, 1).await.ok().and_then(|d| d.first().copied())
+}
+
+async fn read_identities(ctx: &mut Context) -> Vec<DeviceIdentity> {
+    let mut objects = vec![];
+    let mut object = DEVICE_ID_FIRST_OBJECT;
+    for _ in 0..DEVICE_ID_MAX_REQUESTS {
+        let call = ctx.call(Request::Custom(DEVICE_ID_FUNCTION, device_id_request(object)));
+        let rsp = match timeout(Duration::from_secs_f64(DISCOVERY_TIMEOUT_SECS), call).await {
+            Ok(Ok(Response::Custom(_, rsp))) => rsp,
+            _ => break,
+        };
+        match parse_device_id_response(&rsp) {
+            Ok(response) => {
+                objects.extend(response.objects);
+                if !response.more_follows {
+                    break;
+                }
+                object = response.next_object;
+            }
+            Err(_) => break,
+        }
+    }
+    DeviceIdentity::from_objects(&objects)
+}
+
+async fn connect(host: SocketAddr) -> io::Result<Context> {
+    match timeout(Duration::from_secs(DISCOVERY_CONNECT_TIMEOUT_SECS), tcp::connect_slave(host, Slave(0))).await {
+        Ok(res) => res,
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+/// Probes a single slave, `Ok(None)` when nothing answers
+async fn probe_slave(ctx: &mut Context, slave: u8) -> io::Result<Option<DiscoveredDevice>> {
+    ctx.set_slave(Slave(slave));
+    let model = match read(ctx, MODEL_NAME_REGISTER.0, MODEL_NAME_REGISTER.1).await {
+        Ok(data) => Some(registers_to_text(&data)),
+        Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+        //an exception: something is there, but not an inverter
+        Err(_) => None,
+    };
+    let identities = read_identities(ctx).await;
+    if model.is_none() && identities.is_empty() {
+        return Ok(None);
+    }
+
+    let mut device = DiscoveredDevice {
+        slave,
+        model,
+        identities,
+        ..Default::default()
+    };
+    if device.kind() == "inverter" {
+        device.serial_number = read(ctx, SERIAL_NUMBER_REGISTER.0, SERIAL_NUMBER_REGISTER.1).await.ok().map(|d| registers_to_text(&d));
+        if read_u16(ctx, METER_STATUS_REGISTER).await == Some(1) {
+            device.meter = Some(read_u16(ctx, METER_TYPE_REGISTER).await == Some(1));
+        }
+        for (unit, register) in BATTERY_UNIT_STATUS_REGISTERS.iter().enumerate() {
+            if matches!(read_u16(ctx, *register).await, Some(status) if status != 0) {
+                device.battery_units.push(unit as u8 + 1);
+            }
+        }
+        if !device.battery_units.is_empty() {
+            device.battery_soc = read_u16(ctx, BATTERY_SOC_REGISTER).await.map(|v| v as f64 / 10.0);
+        }
+    }
+    Ok(Some(device))
+}
+
+/// Probes the slave IDs `first..=last` at the host for inverters, SmartLoggers and the power meters
+/// and batteries connected to them. The connection is reopened after a timeout, so a late answer
+/// of a slow slave is not taken as the answer for the next one.
+pub async fn discover(host: SocketAddr, first: u8, last: u8) -> io::Result<Vec<DiscoveredDevice>> {
+    let mut res = vec![];
+    let mut ctx = connect(host).await?;
+    for slave in first..=last {
+        match probe_slave(&mut ctx, slave).await {
+            Ok(Some(device)) => {
+                debug!("discovery: slave {}: {:?}", slave, device);
+                res.push(device);
+            }
+            Ok(None) => debug!("discovery: slave {}: no answer", slave),
+            Err(e) => {
+                debug!("discovery: slave {}: {}", slave, e);
+                ctx = connect(host).await?;
+            }
+        }
+    }
+    Ok(res)
+}
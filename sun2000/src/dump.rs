@@ -21,13 +21,133 @@ pub struct DiskRecord {
     pub params: Vec<DiskParamValue>
 }
 
-pub async fn log_params(date: chrono::DateTime<chrono::Utc>, parameters: &Vec<Parameter>) -> Result<()>  {
+pub fn get_dump_path(year: i32, month: u32, day: u32) -> String {
+    format!("sun2000_{}_{}_{}.bin", year, month, day)
+}
+
+/// File uploaded from the inverter (function 0x41), stored raw so it can be decoded again later
+#[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
+pub struct DiskFileRecord {
+    pub time_offset_from_day_start_millis: u32,
+    pub file_type: u8,
+    pub data: Vec<u8>,
+}
+
+pub fn get_files_dump_path(year: i32, month: u32, day: u32) -> String {
+    format!("sun2000_files_{}_{}_{}.bin", year, month, day)
+}
+
+/// Appends an uploaded file to the files dump of the day (days are in UTC)
+pub async fn log_file(date: chrono::DateTime<chrono::Utc>, file_type: u8, data: &[u8]) -> Result<()> {
+    let path = get_files_dump_path(date.year(), date.month(), date.day());
+    let start_of_day = Utc.from_utc_datetime(&date.date().naive_utc().and_hms(0, 0, 0));
+    let record = DiskFileRecord {
+        time_offset_from_day_start_millis: (date - start_of_day).num_milliseconds() as u32,
+        file_type,
+        data: data.to_vec(),
+    };
+    let coded = postcard::to_allocvec(&record).map_err(|e| format!("encoding error: {}", e))?;
+    //files can be longer than the 64 kB of a u16 length header
+    let mut buf = (coded.len() as u32).to_le_bytes().to_vec();
+    buf.extend_from_slice(&coded);
+
+    let mut f = tokio::fs::OpenOptions::new().append(true).create(true).open(&path).await?;
+    f.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads all uploaded files of a single day with their timestamps
+pub fn read_files_day(year: i32, month: u32, day: u32) -> Vec<(chrono::DateTime<chrono::Utc>, u8, Vec<u8>)> {
+    use std::io::*;
+
+    let path = get_files_dump_path(year, month, day);
+    let start_of_day = Utc.from_utc_datetime(&NaiveDate::from_ymd(year, month, day).and_hms(0, 0, 0));
+    let mut res = vec![];
+    if let Ok(mut f) = OpenOptions::new().read(true).open(path) {
+        let mut len_header = [0u8; 4];
+        while f.read_exact(&mut len_header).is_ok() {
+            let mut buff = vec![0u8; u32::from_le_bytes(len_header) as usize];
+            if f.read_exact(&mut buff).is_err() {
+                error!("ERROR PARSING FILES DUMP, NOT ENOUGH DATA {} {:?}", buff.len(), f.stream_position());
+                break;
+            }
+            match postcard::from_bytes::<DiskFileRecord>(&buff) {
+                Ok(record) => res.push((
+                    start_of_day + chrono::Duration::milliseconds(record.time_offset_from_day_start_millis as i64),
+                    record.file_type,
+                    record.data,
+                )),
+                Err(e) => error!("ERROR DECODING FILE RECORD {} {:?}", e, f.stream_position()),
+            }
+        }
+    }
+    res
+}
+
+/// Reads all records of a single day dump file (days are in UTC), returning the
+/// decoded parameters of every record with its timestamp
+pub fn read_day(year: i32, month: u32, day: u32) -> Vec<(chrono::DateTime<chrono::Utc>, Vec<Parameter>)> {
+    use std::io::*;
+
+    let path = get_dump_path(year, month, day);
+    let start_of_day: chrono::DateTime<chrono::Utc> = chrono::DateTime::<Utc>::from_utc(NaiveDateTime::new(NaiveDate::from_ymd(year, month, day), NaiveTime::from_hms_milli(0, 0, 0, 0)), Utc);
+
+    let mut len_header: [u8;2] = [0,0];
+    let mut buff: [u8;4096] = [0; 4096];
+
+    let mut res = vec![];
+
+    if let Ok(mut f) = OpenOptions::new().read(true).open(path) {
+        while f.read_exact(&mut len_header).is_ok() {
+            let len: usize = u16::from_le_bytes(len_header) as usize;
+            if len > buff.len() || f.read_exact(&mut buff[0..len]).is_err() {
+                error!("ERROR PARSING, NOT ENOUGH DATA {} {:?}", len, f.stream_position());
+                break;
+            }
+            let record: DiskRecord = match postcard::from_bytes(&buff[0..len]) {
+                Ok(record) => record,
+                Err(e) => {
+                    error!("ERROR DECODING RECORD {} {:?}", e, f.stream_position());
+                    continue;
+                }
+            };
+
+            let timestamp = start_of_day + chrono::Duration::milliseconds(record.time_offset_from_day_start_millis as i64);
+
+            let mut params = vec![];
+            let mut prev_addr = record.base_address;
+            for v in &record.params {
+                prev_addr += v.addr_offset;
+                match PARAMETER_MAP.get(&prev_addr) {
+                    Some(&p) => {
+                        let mut param: Parameter = p.clone();
+                        param.value = v.value.clone();
+                        params.push(param);
+                    },
+                    None => {
+                        error!("PARAMETER NOT FOUND {} {:?}", prev_addr, &record);
+                    }
+                }
+            }
+            add_pv_string_power(&mut params);
+            res.push((timestamp, params));
+        }
+    }
+
+    res
+}
+
+pub async fn log_params(date: chrono::DateTime<chrono::Utc>, parameters: &[Parameter]) -> Result<()>  {
+
+    //only the register values, the computed ones (eg. the string power) are derived again when reading
+    let registers: Vec<Parameter> = parameters.iter().filter(|p| p.len > 0).cloned().collect();
+    let parameters = &registers;
 
     if parameters.len() == 0 {
         return Ok(())
     }
 
-    let path = format!("sun2000_{}_{}_{}.bin", &date.year(), &date.month(), &date.day());
+    let path = get_dump_path(date.year(), date.month(), date.day());
 
     let open = OpenOptions::new().append(true).create(true).open(&path);
 
@@ -0,0 +1,403 @@
+use serde::{Serialize, Deserialize};
+use super::params::*;
+use super::tariff::*;
+use simplelog::*;
+use chrono::prelude::*;
+use std::collections::BTreeMap;
+
+pub const ENERGY_LEDGER_PATH: &str = "sun2000_energy.bin";
+pub const ENERGY_MAX_GAP_SECS: i64 = 300; //longer gaps between polls are not integrated
+pub const ENERGY_PERSIST_INTERVAL_SECS: i64 = 60; //secs between saving the ledger to disk
+
+/// Energy flows of the installation (kWh) and their value according to the tariff
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct EnergyTotals {
+    pub production: f64,
+    pub consumption: f64,
+    pub import: f64,
+    pub export: f64,
+    pub self_consumed: f64,
+    pub import_cost: f64,
+    pub export_revenue: f64,
+    /// avoided import cost of the self-consumed energy plus the export revenue
+    pub savings: f64,
+}
+
+impl EnergyTotals {
+    pub fn add(&mut self, other: &EnergyTotals) {
+        self.production += other.production;
+        self.consumption += other.consumption;
+        self.import += other.import;
+        self.export += other.export;
+        self.self_consumed += other.self_consumed;
+        self.import_cost += other.import_cost;
+        self.export_revenue += other.export_revenue;
+        self.savings += other.savings;
+    }
+
+    /// Prices the energy flows using the tariff valid at `time`
+    pub fn apply_tariff(&mut self, tariff: &Tariff, time: &DateTime<Utc>) {
+        let import_price = tariff.import_price_at(time);
+        self.import_cost = self.import * import_price;
+        self.export_revenue = self.export * tariff.export_price_at(time);
+        self.savings = self.self_consumed * import_price + self.export_revenue;
+    }
+
+    /// Part of the production consumed locally instead of being exported
+    pub fn self_consumption_ratio(&self) -> Option<f64> {
+        if self.production > 0.0 {
+            Some(self.self_consumed / self.production)
+        } else {
+            None
+        }
+    }
+
+    /// Part of the consumption covered without importing from the grid
+    pub fn autarky(&self) -> Option<f64> {
+        if self.consumption > 0.0 {
+            Some(((self.consumption - self.import) / self.consumption).max(0.0))
+        } else {
+            None
+        }
+    }
+}
+
+/// Values of a single poll needed for the power integration
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EnergySample {
+    time_millis: i64,
+    active_power: f64,
+    meter_power: Option<f64>,
+}
+
+/// Energy counter which is not necessarily read in every poll
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct CounterTrack {
+    last: Option<f64>,
+    /// energy integrated from power since the last counter reading
+    integrated: f64,
+}
+
+impl CounterTrack {
+    /// Energy of the interval: the integrated power, corrected by the counter when it was read,
+    /// so the sum over the intervals follows the counter
+    fn account(&mut self, counter: Option<f64>, integrated: f64) -> f64 {
+        self.integrated += integrated;
+        match (self.last, counter) {
+            (Some(last), Some(c)) if c >= last => {
+                let res = (integrated + (c - last) - self.integrated).max(0.0);
+                self.last = Some(c);
+                self.integrated = 0.0;
+                res
+            }
+            (_, Some(c)) => {
+                //first reading or a counter reset
+                self.last = Some(c);
+                self.integrated = 0.0;
+                integrated
+            }
+            (_, None) => integrated,
+        }
+    }
+}
+
+/// Result of accounting a single poll interval
+#[derive(Debug, Clone)]
+pub struct EnergyInterval {
+    pub time: DateTime<Utc>,
+    pub seconds: f64,
+    /// current house consumption (W)
+    pub house_power: f64,
+    pub totals: EnergyTotals,
+}
+
+/// Daily and monthly energy totals (by local date), persisted between restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct EnergyLedger {
+    pub days: BTreeMap<String, EnergyTotals>,
+    pub months: BTreeMap<String, EnergyTotals>,
+    last: Option<EnergySample>,
+    yield_counter: CounterTrack,
+    export_counter: CounterTrack,
+    import_counter: CounterTrack,
+    #[serde(skip)]
+    last_persist_millis: i64,
+    /// time the last meter value was carried over for
+    #[serde(skip)]
+    meter_carried_millis: i64,
+}
+
+pub fn day_key(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local).format("%Y-%m-%d").to_string()
+}
+
+pub fn month_key(time: &DateTime<Utc>) -> String {
+    time.with_timezone(&Local).format("%Y-%m").to_string()
+}
+
+impl EnergyLedger {
+    pub fn load(path: &str) -> Self {
+        match std::fs::read(path) {
+            Ok(data) => match postcard::from_bytes(&data) {
+                Ok(ledger) => ledger,
+                Err(e) => {
+                    error!("energy: cannot decode {}: {}, starting from scratch", path, e);
+                    EnergyLedger::default()
+                }
+            },
+            Err(_) => EnergyLedger::default(),
+        }
+    }
+
+    pub fn save(&mut self, path: &str) {
+        match postcard::to_allocvec(&self) {
+            Ok(data) => {
+                if let Err(e) = std::fs::write(path, data) {
+                    error!("energy: cannot write {}: {}", path, e);
+                }
+            }
+            Err(e) => error!("energy: cannot encode ledger: {}", e),
+        }
+    }
+
+    /// Saves the ledger if it wasn't saved for `ENERGY_PERSIST_INTERVAL_SECS`
+    pub fn save_periodically(&mut self, path: &str, time: &DateTime<Utc>) {
+        if time.timestamp_millis() - self.last_persist_millis > ENERGY_PERSIST_INTERVAL_SECS * 1000 {
+            self.last_persist_millis = time.timestamp_millis();
+            self.save(path);
+        }
+    }
+
+    pub fn today(&self, time: &DateTime<Utc>) -> EnergyTotals {
+        self.days.get(&day_key(time)).cloned().unwrap_or_default()
+    }
+
+    pub fn this_month(&self, time: &DateTime<Utc>) -> EnergyTotals {
+        self.months.get(&month_key(time)).cloned().unwrap_or_default()
+    }
+
+    /// Accounts the interval since the previous poll.
+    /// Power is integrated and corrected by the energy counters whenever they are read.
+    pub fn update(&mut self, time: DateTime<Utc>, params: &[Parameter], tariff: Option<&Tariff>) -> Option<EnergyInterval> {
+        let mut sample = EnergySample {
+            time_millis: time.timestamp_millis(),
+            active_power: get_param_value(params, "active_power")?,
+            //positive: feeding to the grid, negative: obtaining from the grid
+            meter_power: get_param_value(params, "power_meter_active_power"),
+        };
+        let counters = [
+            get_param_value(params, "accumulated_yield_energy"),
+            get_param_value(params, "grid_exported_energy"),
+            get_param_value(params, "grid_accumulated_energy"),
+        ];
+
+        let prev = self.last.take();
+        let millis = match &prev {
+            Some(prev) => sample.time_millis - prev.time_millis,
+            None => 0,
+        };
+        //the meter wasn't read in this poll: its previous value is used for a while instead of
+        //taking the whole production as the house consumption
+        if sample.meter_power.is_some() {
+            self.meter_carried_millis = 0;
+        } else if let Some(prev) = &prev {
+            self.meter_carried_millis += millis;
+            if self.meter_carried_millis <= ENERGY_MAX_GAP_SECS * 1000 {
+                sample.meter_power = prev.meter_power;
+            }
+        }
+        self.last = Some(sample.clone());
+        let house_power = sample.active_power - sample.meter_power.unwrap_or_default();
+        if prev.is_none() || millis <= 0 || millis > ENERGY_MAX_GAP_SECS * 1000 {
+            debug!("energy: skipping interval of {} ms", millis);
+            //energy of the gap is not known, the counters start over from this reading
+            for (track, counter) in [&mut self.yield_counter, &mut self.export_counter, &mut self.import_counter]
+                .iter_mut()
+                .zip(counters.iter())
+            {
+                **track = CounterTrack { last: *counter, integrated: 0.0 };
+            }
+            return None;
+        }
+        let prev = prev?;
+        let hours = millis as f64 / 3_600_000.0;
+
+        //trapezoidal integration of power (W) into kWh
+        let integrate = |a: f64, b: f64| (a + b) / 2.0 * hours / 1000.0;
+        let meter_prev = prev.meter_power.unwrap_or_default();
+        let meter_cur = sample.meter_power.unwrap_or_default();
+
+        let production = self.yield_counter.account(
+            counters[0],
+            integrate(prev.active_power.max(0.0), sample.active_power.max(0.0)),
+        );
+        let export = self.export_counter.account(
+            counters[1],
+            integrate(meter_prev.max(0.0), meter_cur.max(0.0)),
+        );
+        let import = self.import_counter.account(
+            counters[2],
+            integrate((-meter_prev).max(0.0), (-meter_cur).max(0.0)),
+        );
+        let mut totals = EnergyTotals {
+            production,
+            consumption: (production + import - export).max(0.0),
+            import,
+            export,
+            self_consumed: (production - export).max(0.0),
+            ..Default::default()
+        };
+        if let Some(tariff) = tariff {
+            //price of the middle of the interval
+            totals.apply_tariff(tariff, &(time - chrono::Duration::milliseconds(millis / 2)));
+        }
+
+        self.days.entry(day_key(&time)).or_default().add(&totals);
+        self.months.entry(month_key(&time)).or_default().add(&totals);
+
+        Some(EnergyInterval {
+            time,
+            seconds: millis as f64 / 1000.0,
+            house_power,
+            totals,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(value: f64, expected: f64) {
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    /// Parameter with the value in its unit (W, kWh)
+    fn param(name: &str, value: f64) -> Parameter {
+        let p = find_parameter(name).unwrap();
+        let raw = (value * p.gain as f64).round();
+        let value = match p.value {
+            ParamKind::NumberU32(_) => ParamKind::NumberU32(Some(raw as u32)),
+            ParamKind::NumberI32(_) => ParamKind::NumberI32(Some(raw as i32)),
+            _ => unreachable!(),
+        };
+        Parameter { value, ..p.clone() }
+    }
+
+    fn time(h: u32, m: u32, s: u32) -> DateTime<Utc> {
+        Local.ymd(2022, 3, 15).and_hms(h, m, s).with_timezone(&Utc)
+    }
+
+    #[test]
+    fn counter_track_follows_the_counter() {
+        let mut track = CounterTrack::default();
+        assert_near(track.account(Some(100.0), 0.0), 0.0);
+        //not read, the integrated power is used
+        assert_near(track.account(None, 0.4), 0.4);
+        assert_near(track.account(None, 0.4), 0.4);
+        //read again, the intervals sum up to the counter difference
+        assert_near(track.account(Some(101.0), 0.4), 0.2);
+        //counter reset: the integrated power, then the counter again
+        assert_near(track.account(Some(5.0), 0.3), 0.3);
+        assert_near(track.account(Some(5.5), 0.2), 0.5);
+    }
+
+    #[test]
+    fn ledger_integrates_power() {
+        let mut ledger = EnergyLedger::default();
+        let params = vec![param("active_power", 6000.0), param("power_meter_active_power", 1000.0)];
+        assert!(ledger.update(time(12, 0, 0), &params, None).is_none());
+        let interval = ledger.update(time(12, 1, 0), &params, None).unwrap();
+        assert_near(interval.seconds, 60.0);
+        assert_near(interval.house_power, 5000.0);
+        assert_near(interval.totals.production, 0.1);
+        assert_near(interval.totals.export, 1.0 / 60.0);
+        assert_near(interval.totals.import, 0.0);
+        assert_near(interval.totals.consumption, 0.1 - 1.0 / 60.0);
+        assert_near(interval.totals.self_consumed, 0.1 - 1.0 / 60.0);
+        assert_eq!(ledger.today(&time(12, 1, 0)), interval.totals);
+
+        //importing
+        let params = vec![param("active_power", 0.0), param("power_meter_active_power", -1200.0)];
+        let interval = ledger.update(time(12, 2, 0), &params, None).unwrap();
+        //trapezoid from 6000 W to 0 W and from 1000 W export to 1200 W import
+        assert_near(interval.totals.production, 0.05);
+        assert_near(interval.totals.export, 0.5 / 60.0);
+        assert_near(interval.totals.import, 0.6 / 60.0);
+    }
+
+    #[test]
+    fn ledger_skips_missing_samples_and_gaps() {
+        let mut ledger = EnergyLedger::default();
+        let params = vec![param("active_power", 3600.0)];
+        ledger.update(time(12, 0, 0), &params, None);
+        //no active power in the poll: nothing accounted, the next interval covers it
+        assert!(ledger.update(time(12, 1, 0), &[param("power_meter_active_power", 0.0)], None).is_none());
+        let interval = ledger.update(time(12, 2, 0), &params, None).unwrap();
+        assert_near(interval.seconds, 120.0);
+        assert_near(interval.totals.production, 0.12);
+        //too long gap: not integrated
+        let after_gap = time(12, 2, 0) + chrono::Duration::seconds(ENERGY_MAX_GAP_SECS + 1);
+        assert!(ledger.update(after_gap, &params, None).is_none());
+        assert_near(ledger.today(&after_gap).production, 0.12);
+    }
+
+    #[test]
+    fn ledger_carries_missing_meter_value() {
+        let mut ledger = EnergyLedger::default();
+        let power = param("active_power", 3000.0);
+        ledger.update(time(12, 0, 0), &[power.clone(), param("power_meter_active_power", -600.0)], None);
+        //the meter wasn't read: still importing
+        let interval = ledger.update(time(12, 1, 0), std::slice::from_ref(&power), None).unwrap();
+        assert_near(interval.house_power, 3600.0);
+        assert_near(interval.totals.import, 0.01);
+        assert_near(interval.totals.consumption, 0.06);
+        for m in 2..=5 {
+            assert_near(ledger.update(time(12, m, 0), std::slice::from_ref(&power), None).unwrap().house_power, 3600.0);
+        }
+        //not read for too long
+        let interval = ledger.update(time(12, 6, 0), std::slice::from_ref(&power), None).unwrap();
+        assert_near(interval.house_power, 3000.0);
+        assert_near(interval.totals.import, 0.005);
+        //read again
+        let interval = ledger.update(time(12, 7, 0), &[power.clone(), param("power_meter_active_power", 1000.0)], None).unwrap();
+        assert_near(interval.house_power, 2000.0);
+        let interval = ledger.update(time(12, 8, 0), std::slice::from_ref(&power), None).unwrap();
+        assert_near(interval.house_power, 2000.0);
+        assert_near(interval.totals.export, 1.0 / 60.0);
+    }
+
+    #[test]
+    fn ledger_follows_counters_and_resets() {
+        let mut ledger = EnergyLedger::default();
+        let power = param("active_power", 6000.0);
+        ledger.update(time(12, 0, 0), &[power.clone(), param("accumulated_yield_energy", 1000.0)], None);
+        //the counter is not read in every poll
+        let interval = ledger.update(time(12, 1, 0), std::slice::from_ref(&power), None).unwrap();
+        assert_near(interval.totals.production, 0.1);
+        let interval = ledger.update(time(12, 2, 0), &[power.clone(), param("accumulated_yield_energy", 1000.25)], None).unwrap();
+        assert_near(interval.totals.production, 0.15);
+        assert_near(ledger.today(&time(12, 2, 0)).production, 0.25);
+        //counter reset, the integrated power is used
+        let interval = ledger.update(time(12, 3, 0), &[power, param("accumulated_yield_energy", 0.0)], None).unwrap();
+        assert_near(interval.totals.production, 0.1);
+    }
+
+    #[test]
+    fn ledger_day_and_month_rollover() {
+        let mut ledger = EnergyLedger::default();
+        let params = vec![param("active_power", 6000.0)];
+        let before = Local.ymd(2022, 3, 31).and_hms(23, 59, 0).with_timezone(&Utc);
+        let after = Local.ymd(2022, 4, 1).and_hms(0, 1, 0).with_timezone(&Utc);
+        ledger.update(before - chrono::Duration::minutes(1), &params, None);
+        ledger.update(before, &params, None);
+        ledger.update(after, &params, None);
+        assert_eq!(day_key(&before), "2022-03-31");
+        assert_eq!(day_key(&after), "2022-04-01");
+        assert_near(ledger.today(&before).production, 0.1);
+        assert_near(ledger.this_month(&before).production, 0.1);
+        //the interval belongs to the day it ends in
+        assert_near(ledger.today(&after).production, 0.2);
+        assert_near(ledger.this_month(&after).production, 0.2);
+    }
+}
@@ -0,0 +1,230 @@
+use chrono::prelude::*;
+use std::time::Duration;
+
+use super::options::{self, OPTION_MAX_SECS};
+use super::Result;
+
+pub const NIGHT_POLL_INTERVAL_SECS: u64 = 300; //default: secs between polls at night in the slow mode
+pub const NIGHT_MARGIN_MINS: i64 = 30; //default: night starts this long after sunset and ends this long after sunrise
+
+/// Sunrise and sunset of a day, or the polar day/night when the sun doesn't cross the horizon
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SunTimes {
+    Regular {
+        sunrise: DateTime<Utc>,
+        sunset: DateTime<Utc>,
+    },
+    PolarDay,
+    PolarNight,
+}
+
+fn julian_to_utc(j: f64) -> DateTime<Utc> {
+    let millis = ((j - 2440587.5) * 86_400_000.0).round() as i64;
+    Utc.timestamp_millis(millis)
+}
+
+/// Sunrise equation (NOAA approximation, accuracy about a minute).
+/// `lat` is positive north, `lon` positive east.
+pub fn sun_times(date: NaiveDate, lat: f64, lon: f64) -> SunTimes {
+    //days since the J2000 epoch (noon of the date, the 0.0008 of the usual formula only rounds it)
+    let n = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+    let j_star = n - lon / 360.0;
+    let m = (357.5291 + 0.98560028 * j_star).rem_euclid(360.0).to_radians();
+    let c = 1.9148 * m.sin() + 0.0200 * (2.0 * m).sin() + 0.0003 * (3.0 * m).sin();
+    let lambda = (m.to_degrees() + c + 180.0 + 102.9372).rem_euclid(360.0).to_radians();
+    let j_transit = 2451545.0 + j_star + 0.0053 * m.sin() - 0.0069 * (2.0 * lambda).sin();
+    let declination = (lambda.sin() * 23.4397f64.to_radians().sin()).asin();
+
+    let phi = lat.to_radians();
+    //-0.833° accounts for the refraction and the solar disc size
+    let cos_omega = ((-0.833f64).to_radians().sin() - phi.sin() * declination.sin()) / (phi.cos() * declination.cos());
+    if cos_omega > 1.0 {
+        return SunTimes::PolarNight;
+    }
+    if cos_omega < -1.0 {
+        return SunTimes::PolarDay;
+    }
+    let omega = cos_omega.acos().to_degrees();
+    SunTimes::Regular {
+        sunrise: julian_to_utc(j_transit - omega / 360.0),
+        sunset: julian_to_utc(j_transit + omega / 360.0),
+    }
+}
+
+/// Geographic location of the installation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Location {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Location {
+    /// Date of the local solar day of `time`
+    pub fn solar_date(&self, time: &DateTime<Utc>) -> NaiveDate {
+        (*time + chrono::Duration::seconds((self.lon * 240.0) as i64)).date().naive_utc()
+    }
+
+    pub fn sun_times(&self, time: &DateTime<Utc>) -> SunTimes {
+        sun_times(self.solar_date(time), self.lat, self.lon)
+    }
+
+    /// true between sunset + `margin` and sunrise + `margin`: the inverter keeps running a while
+    /// after sunset, but it wakes up (and its dongle with it) only some time after sunrise
+    pub fn is_night(&self, time: &DateTime<Utc>, margin: chrono::Duration) -> bool {
+        match self.sun_times(time) {
+            SunTimes::Regular { sunrise, sunset } => *time < sunrise + margin || *time > sunset + margin,
+            SunTimes::PolarDay => false,
+            SunTimes::PolarNight => true,
+        }
+    }
+
+    /// The first moment after `time` when the night (as in `is_night`) is over
+    pub fn next_dawn(&self, time: &DateTime<Utc>, margin: chrono::Duration) -> Option<DateTime<Utc>> {
+        let date = self.solar_date(time);
+        //a polar night can last for months
+        for day in 0..=366 {
+            match sun_times(date + chrono::Duration::days(day), self.lat, self.lon) {
+                SunTimes::Regular { sunrise, .. } if sunrise + margin > *time => return Some(sunrise + margin),
+                SunTimes::PolarDay if day > 0 => return Some(Utc.from_utc_datetime(&(date + chrono::Duration::days(day)).and_hms(0, 0, 0))),
+                _ => {}
+            }
+        }
+        None
+    }
+}
+
+/// Polling behavior between dusk and dawn
+#[derive(Clone, Debug, PartialEq)]
+pub struct NightMode {
+    pub location: Location,
+    /// poll interval at night, `None` pauses the polling until dawn
+    pub poll_interval: Option<Duration>,
+    pub margin: chrono::Duration,
+}
+
+impl NightMode {
+    /// Builds the night mode from options (`night_mode`: `slow`/`pause`/`off`, `night_poll_interval`,
+    /// `night_margin` in minutes). Returns `None` when it is disabled.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(location: Location, get: F) -> Result<Option<NightMode>> {
+        let poll_interval = match get("night_mode").as_deref().map(str::trim).unwrap_or("slow") {
+            "off" => return Ok(None),
+            "pause" => None,
+            "slow" => Some(
+                options::secs(&get, "night_poll_interval", 1.0, OPTION_MAX_SECS)?
+                    .unwrap_or_else(|| Duration::from_secs(NIGHT_POLL_INTERVAL_SECS)),
+            ),
+            other => return Err(format!("invalid night_mode: {:?}", other).into()),
+        };
+        Ok(Some(NightMode {
+            location,
+            poll_interval,
+            margin: chrono::Duration::minutes(
+                options::integer(&get, "night_margin", 0, 12 * 60)?.map(|m| m as i64).unwrap_or(NIGHT_MARGIN_MINS),
+            ),
+        }))
+    }
+
+    pub fn is_night(&self, time: &DateTime<Utc>) -> bool {
+        self.location.is_night(time, self.margin)
+    }
+
+    /// How long to wait before the next poll at night: the night poll interval or the time until dawn,
+    /// whichever comes first
+    pub fn night_wait(&self, time: &DateTime<Utc>) -> Duration {
+        let until_dawn = self
+            .location
+            .next_dawn(time, self.margin)
+            .and_then(|dawn| (dawn - *time).to_std().ok())
+            .unwrap_or_else(|| Duration::from_secs(NIGHT_POLL_INTERVAL_SECS));
+        match self.poll_interval {
+            Some(interval) => interval.min(until_dawn),
+            None => until_dawn,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MADRID: Location = Location { lat: 40.4168, lon: -3.7038 };
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32, sec: u32) -> DateTime<Utc> {
+        Utc.ymd(y, m, d).and_hms(h, min, sec)
+    }
+
+    fn regular(times: SunTimes) -> (DateTime<Utc>, DateTime<Utc>) {
+        match times {
+            SunTimes::Regular { sunrise, sunset } => (sunrise, sunset),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    fn assert_near(time: DateTime<Utc>, expected: DateTime<Utc>) {
+        assert!((time - expected).num_seconds().abs() <= 120, "{} != {}", time, expected);
+    }
+
+    #[test]
+    fn known_cities() {
+        //NOAA solar calculator
+        let (sunrise, sunset) = regular(sun_times(NaiveDate::from_ymd(2024, 6, 21), MADRID.lat, MADRID.lon));
+        assert_near(sunrise, utc(2024, 6, 21, 4, 44, 55));
+        assert_near(sunset, utc(2024, 6, 21, 19, 48, 33));
+        let (sunrise, sunset) = regular(sun_times(NaiveDate::from_ymd(2024, 12, 21), 40.7128, -74.006));
+        assert_near(sunrise, utc(2024, 12, 21, 12, 16, 49));
+        assert_near(sunset, utc(2024, 12, 21, 21, 31, 52));
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        let svalbard = Location { lat: 78.22, lon: 15.65 };
+        let margin = chrono::Duration::minutes(NIGHT_MARGIN_MINS);
+        assert_eq!(svalbard.sun_times(&utc(2024, 6, 21, 0, 0, 0)), SunTimes::PolarDay);
+        assert!(!svalbard.is_night(&utc(2024, 6, 21, 0, 0, 0), margin));
+        let midwinter = utc(2024, 12, 21, 12, 0, 0);
+        assert_eq!(svalbard.sun_times(&midwinter), SunTimes::PolarNight);
+        assert!(svalbard.is_night(&midwinter, margin));
+        //the first sunrise of the year
+        let dawn = svalbard.next_dawn(&midwinter, margin).unwrap();
+        assert_eq!((dawn.year(), dawn.month()), (2025, 2));
+        assert!(!svalbard.is_night(&dawn, margin));
+        assert!(svalbard.is_night(&(dawn - chrono::Duration::seconds(1)), margin));
+    }
+
+    #[test]
+    fn margin_edges() {
+        let margin = chrono::Duration::minutes(NIGHT_MARGIN_MINS);
+        let second = chrono::Duration::seconds(1);
+        let (sunrise, sunset) = regular(MADRID.sun_times(&utc(2024, 6, 21, 12, 0, 0)));
+        assert!(MADRID.is_night(&(sunrise + margin - second), margin));
+        assert!(!MADRID.is_night(&(sunrise + margin), margin));
+        assert!(!MADRID.is_night(&(sunset + margin), margin));
+        assert!(MADRID.is_night(&(sunset + margin + second), margin));
+        //no margin
+        assert!(MADRID.is_night(&(sunset + second), chrono::Duration::zero()));
+    }
+
+    #[test]
+    fn next_dawn_across_midnight() {
+        let margin = chrono::Duration::minutes(NIGHT_MARGIN_MINS);
+        let (sunrise, _) = regular(MADRID.sun_times(&utc(2024, 6, 22, 12, 0, 0)));
+        for time in [utc(2024, 6, 21, 12, 0, 0), utc(2024, 6, 21, 23, 30, 0), utc(2024, 6, 22, 0, 30, 0), utc(2024, 6, 22, 4, 50, 0)] {
+            assert_eq!(MADRID.next_dawn(&time, margin), Some(sunrise + margin), "{}", time);
+        }
+    }
+
+    #[test]
+    fn night_wait() {
+        let margin = chrono::Duration::minutes(NIGHT_MARGIN_MINS);
+        let (sunrise, _) = regular(MADRID.sun_times(&utc(2024, 6, 22, 12, 0, 0)));
+        let dawn = sunrise + margin;
+        let slow = NightMode { location: MADRID, poll_interval: Some(Duration::from_secs(NIGHT_POLL_INTERVAL_SECS)), margin };
+        let midnight = utc(2024, 6, 22, 0, 0, 0);
+        assert!(slow.is_night(&midnight));
+        assert_eq!(slow.night_wait(&midnight), Duration::from_secs(NIGHT_POLL_INTERVAL_SECS));
+        //not beyond the dawn
+        assert_eq!(slow.night_wait(&(dawn - chrono::Duration::seconds(60))), Duration::from_secs(60));
+        let pause = NightMode { poll_interval: None, ..slow };
+        assert_eq!(pause.night_wait(&midnight), (dawn - midnight).to_std().unwrap());
+    }
+}
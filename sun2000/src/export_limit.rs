@@ -0,0 +1,268 @@
+use simplelog::*;
+use std::time::{Duration, Instant};
+
+use super::options::{self, OPTION_MAX_SECS};
+use super::Result;
+
+pub const EXPORT_LIMIT_REGISTER: u16 = 40126; //fixed active power derated (W), U32
+pub const EXPORT_LIMIT_DEADBAND_W: f64 = 100.0; //default: smaller limit changes are not sent
+pub const EXPORT_LIMIT_MAX_STEP_W: f64 = 500.0; //default: max limit increase per command
+pub const EXPORT_LIMIT_INTERVAL_SECS: u64 = 10; //default: min secs between limit increases
+pub const EXPORT_LIMIT_STALE_SECS: u64 = 30; //default: meter value older than this is not trusted
+
+/// Settings of the export limiting control loop
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportLimitConfig {
+    /// maximum power fed to the grid (W), 0 for zero-export
+    pub max_export: f64,
+    pub deadband: f64,
+    pub max_step_up: f64,
+    pub min_interval: Duration,
+    pub stale_after: Duration,
+    /// limit used when the meter value is stale (W), defaults to `max_export`
+    /// which keeps the export under the threshold regardless of the house consumption
+    pub fallback_limit: Option<f64>,
+    /// only log the commands, don't write them to the inverter
+    pub dry_run: bool,
+}
+
+impl ExportLimitConfig {
+    /// Builds the config from options (`export_limit`, `export_limit_deadband`, `export_limit_max_step`,
+    /// `export_limit_interval`, `export_limit_stale`, `export_limit_fallback`, `export_limit_dry_run`).
+    /// Returns `None` when `export_limit` is not set.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<Option<ExportLimitConfig>> {
+        let watts = |name: &str, min: f64| options::number(&get, name, min, f64::MAX);
+        let max_export = match watts("export_limit", 0.0)? {
+            Some(v) => v,
+            None => return Ok(None),
+        };
+        Ok(Some(ExportLimitConfig {
+            max_export,
+            deadband: watts("export_limit_deadband", 0.0)?.unwrap_or(EXPORT_LIMIT_DEADBAND_W),
+            max_step_up: watts("export_limit_max_step", 1.0)?.unwrap_or(EXPORT_LIMIT_MAX_STEP_W),
+            min_interval: options::secs(&get, "export_limit_interval", 0.0, OPTION_MAX_SECS)?
+                .unwrap_or_else(|| Duration::from_secs(EXPORT_LIMIT_INTERVAL_SECS)),
+            stale_after: options::secs(&get, "export_limit_stale", 1.0, OPTION_MAX_SECS)?
+                .unwrap_or_else(|| Duration::from_secs(EXPORT_LIMIT_STALE_SECS)),
+            fallback_limit: watts("export_limit_fallback", 0.0)?,
+            dry_run: options::flag(&get, "export_limit_dry_run")?.unwrap_or_default(),
+        }))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportLimitReason {
+    /// regular closed loop adjustment
+    Regulate,
+    /// the meter value is stale, fail-safe limit
+    Fallback,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ExportLimitCommand {
+    /// new active power limit (W)
+    pub limit: u32,
+    pub reason: ExportLimitReason,
+}
+
+/// Closed loop controller adjusting the inverter active power limit, so the power exported
+/// to the grid (as measured by the power meter) stays under the configured threshold
+pub struct ExportLimiter {
+    pub config: ExportLimitConfig,
+    rated_power: Option<f64>,
+    limit: Option<f64>,
+    last_command: Option<Instant>,
+    last_meter: Option<Instant>,
+    stale: bool,
+}
+
+impl ExportLimiter {
+    pub fn new(config: ExportLimitConfig) -> Self {
+        Self {
+            config,
+            rated_power: None,
+            limit: None,
+            last_command: None,
+            last_meter: None,
+            stale: false,
+        }
+    }
+
+    pub fn set_rated_power(&mut self, rated_power: f64) {
+        self.rated_power = Some(rated_power);
+    }
+
+    /// last commanded limit (W)
+    pub fn limit(&self) -> Option<f64> {
+        self.limit
+    }
+
+    pub fn is_stale(&self) -> bool {
+        self.stale
+    }
+
+    /// The command couldn't be applied, it has to be sent again on the next update
+    pub fn command_failed(&mut self) {
+        self.limit = None;
+    }
+
+    /// Computes the new limit from the current inverter output and the meter power
+    /// (positive when exporting). Returns `None` when the limit shouldn't be changed.
+    pub fn update(
+        &mut self,
+        now: Instant,
+        active_power: Option<f64>,
+        meter_power: Option<f64>,
+    ) -> Option<ExportLimitCommand> {
+        let rated_power = self.rated_power?;
+        if meter_power.is_some() {
+            self.last_meter = Some(now);
+        }
+        let stale = match self.last_meter {
+            Some(t) => now.duration_since(t) > self.config.stale_after,
+            //we don't know anything about the meter yet
+            None => true,
+        };
+        if stale != self.stale {
+            if stale {
+                warn!("export limit: power meter value is stale, using fail-safe limit");
+            } else {
+                info!("export limit: power meter value is valid again, resuming regulation");
+            }
+            self.stale = stale;
+        }
+
+        let (limit, reason) = if stale {
+            //fail-safe limit is applied immediately without deadband and rate limiting
+            let limit = self
+                .config
+                .fallback_limit
+                .unwrap_or(self.config.max_export)
+                .clamp(0.0, rated_power);
+            if self.limit == Some(limit) {
+                return None;
+            }
+            (limit, ExportLimitReason::Fallback)
+        } else {
+            //not read in this poll: the current limit is held until the value gets stale
+            let export = meter_power?;
+            let desired = (active_power? + self.config.max_export - export).clamp(0.0, rated_power);
+            let limit = match self.limit {
+                Some(current) => {
+                    if (desired - current).abs() < self.config.deadband {
+                        return None;
+                    }
+                    if desired > current {
+                        //raising the limit is rate limited, exceeding the threshold is corrected immediately
+                        if let Some(t) = self.last_command {
+                            if now.duration_since(t) < self.config.min_interval {
+                                return None;
+                            }
+                        }
+                        desired.min(current + self.config.max_step_up)
+                    } else {
+                        desired
+                    }
+                }
+                //the previous limit set in the inverter is unknown
+                None => desired,
+            };
+            (limit, ExportLimitReason::Regulate)
+        };
+
+        self.limit = Some(limit);
+        self.last_command = Some(now);
+        Some(ExportLimitCommand {
+            limit: limit.round() as u32,
+            reason,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter() -> ExportLimiter {
+        let mut limiter = ExportLimiter::new(ExportLimitConfig {
+            max_export: 0.0,
+            deadband: EXPORT_LIMIT_DEADBAND_W,
+            max_step_up: EXPORT_LIMIT_MAX_STEP_W,
+            min_interval: Duration::from_secs(EXPORT_LIMIT_INTERVAL_SECS),
+            stale_after: Duration::from_secs(EXPORT_LIMIT_STALE_SECS),
+            fallback_limit: Some(1000.0),
+            dry_run: false,
+        });
+        limiter.set_rated_power(10000.0);
+        limiter
+    }
+
+    fn regulate(limit: u32) -> Option<ExportLimitCommand> {
+        Some(ExportLimitCommand { limit, reason: ExportLimitReason::Regulate })
+    }
+
+    fn secs(start: Instant, secs: u64) -> Instant {
+        start + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn deadband_and_decrease() {
+        let mut l = limiter();
+        let t = Instant::now();
+        assert_eq!(l.update(t, Some(5000.0), Some(0.0)), regulate(5000));
+        //small changes are not sent
+        assert_eq!(l.update(secs(t, 1), Some(5000.0), Some(50.0)), None);
+        //exporting: lowered at once, without waiting for the interval
+        assert_eq!(l.update(secs(t, 2), Some(5000.0), Some(1000.0)), regulate(4000));
+        assert_eq!(l.limit(), Some(4000.0));
+    }
+
+    #[test]
+    fn rate_limited_increase() {
+        let mut l = limiter();
+        let t = Instant::now();
+        assert_eq!(l.update(t, Some(4000.0), Some(0.0)), regulate(4000));
+        //importing 2 kW, the limit goes up by steps after the interval
+        assert_eq!(l.update(secs(t, 5), Some(4000.0), Some(-2000.0)), None);
+        assert_eq!(l.update(secs(t, EXPORT_LIMIT_INTERVAL_SECS), Some(4000.0), Some(-2000.0)), regulate(4500));
+        assert_eq!(l.update(secs(t, EXPORT_LIMIT_INTERVAL_SECS + 1), Some(4500.0), Some(-1500.0)), None);
+        assert_eq!(l.update(secs(t, 2 * EXPORT_LIMIT_INTERVAL_SECS), Some(4500.0), Some(-1500.0)), regulate(5000));
+        //never above the rated power
+        let t = secs(t, 100);
+        assert_eq!(l.update(t, Some(5000.0), Some(-20000.0)), regulate(5500));
+        l.config.max_step_up = 100000.0;
+        assert_eq!(l.update(secs(t, 100), Some(5500.0), Some(-20000.0)), regulate(10000));
+    }
+
+    #[test]
+    fn missing_and_stale_meter_value() {
+        let mut l = limiter();
+        let t = Instant::now();
+        //nothing is known about the meter: fail-safe limit
+        assert_eq!(l.update(t, Some(5000.0), None), Some(ExportLimitCommand { limit: 1000, reason: ExportLimitReason::Fallback }));
+        assert!(l.is_stale());
+        let t = secs(t, EXPORT_LIMIT_INTERVAL_SECS);
+        assert_eq!(l.update(t, Some(1000.0), Some(-3000.0)), regulate(1500));
+        //the meter wasn't read in a poll: the limit is held
+        assert_eq!(l.update(secs(t, 1), Some(1500.0), None), None);
+        assert_eq!(l.update(secs(t, EXPORT_LIMIT_STALE_SECS), Some(1500.0), None), None);
+        assert_eq!(l.limit(), Some(1500.0));
+        //too old
+        let stale = secs(t, 1 + EXPORT_LIMIT_STALE_SECS);
+        assert_eq!(l.update(stale, Some(1500.0), None), Some(ExportLimitCommand { limit: 1000, reason: ExportLimitReason::Fallback }));
+        assert_eq!(l.update(secs(stale, 1), Some(1000.0), None), None);
+    }
+
+    #[test]
+    fn failed_command_is_sent_again() {
+        let mut l = limiter();
+        let t = Instant::now();
+        assert_eq!(l.update(t, Some(5000.0), Some(0.0)), regulate(5000));
+        assert_eq!(l.update(secs(t, 1), Some(5000.0), Some(0.0)), None);
+        l.command_failed();
+        assert_eq!(l.update(secs(t, 2), Some(5000.0), Some(0.0)), regulate(5000));
+        //without the rated power nothing is sent
+        let mut l = ExportLimiter::new(limiter().config);
+        assert_eq!(l.update(t, Some(5000.0), Some(0.0)), None);
+    }
+}
@@ -0,0 +1,387 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use simplelog::*;
+use std::collections::HashMap;
+use std::io;
+use std::io::ErrorKind;
+use std::time::Instant;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use super::defs::*;
+use super::device::*;
+use super::identity::*;
+use super::params::*;
+use super::retry::*;
+use super::shared::*;
+use super::sun2000::SUN2000_POLL_INTERVAL_SECS;
+
+/// Huawei backend: SUN2000 inverters and SmartLoggers, read using their register maps
+pub struct HuaweiDevice {
+    pub name: String,
+    pub host_port: String,
+    pub slave: Slave,
+    pub device_type: DeviceType,
+    pub modbus: ModbusConfig,
+    pub shared: Shared,
+    schedule: PollSchedule,
+    poll_tick: u32,
+    poll_intervals: HashMap<String, u32>,
+    /// PV strings of the inverter, polled in addition to the device parameters
+    pv_strings: u16,
+}
+
+impl HuaweiDevice {
+    /// `poll_intervals` are the poll interval overrides (secs) by parameter name
+    pub fn new(
+        name: String,
+        host_port: String,
+        slave: Slave,
+        device_type: DeviceType,
+        modbus: ModbusConfig,
+        shared: Shared,
+        poll_intervals: &HashMap<String, u32>,
+    ) -> Self {
+        Self {
+            name,
+            host_port,
+            slave,
+            device_type,
+            modbus,
+            shared,
+            schedule: PollSchedule::new(device_type, SUN2000_POLL_INTERVAL_SECS, poll_intervals),
+            poll_tick: 0,
+            poll_intervals: poll_intervals.clone(),
+            pv_strings: 0,
+        }
+    }
+
+    /// Adds the voltage and current of the `strings` PV strings to the polled parameters
+    fn set_pv_strings(&mut self, strings: u16) {
+        let strings = strings.min(PV_STRINGS_MAX);
+        if self.device_type != DeviceType::Inverter || strings == self.pv_strings {
+            return;
+        }
+        info!("<i>{}</>: polling <b><cyan>{}</> PV strings", self.name, strings);
+        self.pv_strings = strings;
+        self.schedule = PollSchedule::with_params(
+            self.device_type,
+            &poll_parameters_with_pv_strings(strings),
+            SUN2000_POLL_INTERVAL_SECS,
+            &self.poll_intervals,
+        );
+    }
+
+    /// Reads the device identification (0x2B/0x0E) of all described devices, following the continuations
+    async fn read_identities(&self, ctx: &mut Context) -> Vec<DeviceIdentity> {
+        let mut objects = vec![];
+        let mut object = DEVICE_ID_FIRST_OBJECT;
+        for _ in 0..DEVICE_ID_MAX_REQUESTS {
+            let retval = ctx.call(Request::Custom(DEVICE_ID_FUNCTION, device_id_request(object)));
+            let rsp = match timeout(self.modbus.read_timeout, retval).await {
+                Ok(Ok(Response::Custom(_, rsp))) => rsp,
+                Ok(Ok(_)) => {
+                    error!("<i>{}</>: unexpected Reading Device Identifiers (0x2B) result", self.name);
+                    break;
+                }
+                Ok(Err(e)) => {
+                    warn!("<i>{}</i>: read error during <green><i>Reading Device Identifiers (0x2B)</>, error: <b>{}</>", self.name, e);
+                    break;
+                }
+                Err(e) => {
+                    warn!("<i>{}</i>: read timeout during <green><i>Reading Device Identifiers (0x2B)</>, error: <b>{}</>", self.name, e);
+                    break;
+                }
+            };
+            debug!("<i>{}</>: Result for function {} is '{:?}'", self.name, DEVICE_ID_FUNCTION, rsp);
+            match parse_device_id_response(&rsp) {
+                Ok(response) => {
+                    objects.extend(response.objects);
+                    if !response.more_follows {
+                        break;
+                    }
+                    object = response.next_object;
+                }
+                Err(e) => {
+                    warn!("<i>{}</>: {}", self.name, e);
+                    break;
+                }
+            }
+        }
+
+        DeviceIdentity::from_objects(&objects)
+    }
+
+    /// Records the result of a span read for the span health statistics
+    fn record_span(&self, time: chrono::DateTime<chrono::Utc>, start: u16, len: u16, error: Option<String>) {
+        let mut shared = self.shared.write().unwrap();
+        let index = match shared.spans.iter().position(|s| s.start == start && s.len == len) {
+            Some(i) => i,
+            None => {
+                shared.spans.push(SpanHealth { start, len, ..Default::default() });
+                shared.spans.sort_by_key(|s| (s.start, s.len));
+                shared.spans.iter().position(|s| s.start == start && s.len == len).unwrap()
+            }
+        };
+        shared.spans[index].record(time, error);
+    }
+
+    /// Reads the spans of the plan. The snapshot tells whether the connection failed
+    /// (as opposed to a single span not being readable, eg. an exception response).
+    async fn read_params(
+        &mut self,
+        ctx: &mut Context,
+        plan: &ReadPlan,
+        quiet: bool,
+    ) -> io::Result<Snapshot> {
+
+        let start = chrono::Utc::now();
+        let now = Instant::now();
+
+        let mut params: Vec<Parameter> = vec![];
+        let mut disconnected = false;
+
+        let (params_to_read, addr_span) = plan;
+
+        let mut value_map = HashMap::new();
+
+        for (addr_start, addr_len) in addr_span {
+            if disconnected {
+                break;
+            }
+            let mut attempts = 0;
+            let mut span_error = None;
+            while attempts < self.modbus.attempts {
+                attempts = attempts + 1;
+                debug!("-> obtaining spam {} {}...", addr_start, addr_len);
+                let retval = ctx.read_holding_registers(*addr_start, *addr_len);
+                let read_res;
+                let start = Instant::now();
+                let read_time;
+                match timeout(self.modbus.read_timeout, retval).await {
+                    Ok(res) => {
+                        read_res = res;
+                        read_time = start.elapsed();
+                    }
+                    Err(e) => {
+                        let msg = format!(
+                            "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{}-{}</>, error: <b>{}</>",
+                            self.name, attempts, self.modbus.attempts, addr_start, addr_len, e
+                        );
+                        if attempts == self.modbus.attempts {
+                            if quiet { debug!("{}", msg) } else { error!("{}", msg) };
+                            //a late response would be taken as the answer to the next request
+                            span_error = Some("timeout".to_string());
+                            disconnected = true;
+                            break;
+                        } else {
+                            warn!("{}", msg);
+                            continue;
+                        };
+                    }
+                }
+                match read_res {
+                    Ok(data) => {
+                        if read_time > self.modbus.lag_warning {
+                            warn!(
+                                "<i>{}</i>: inverter has lagged during read, register: <green><i>{}-{}</>, read time: <b>{:?}</>",
+                                self.name, addr_start, addr_len, read_time
+                            );
+                        }
+
+                        self.shared.write().unwrap().cache_registers(*addr_start, &data);
+                        let mut addr = *addr_start;
+                        for v in data {
+                            value_map.insert(addr, v);
+                            addr = addr + 1;
+                        }
+
+                        span_error = None;
+                        break; //read next parameter span
+                    }
+                    Err(e) => {
+                        let msg = format!(
+                            "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{}-{}</>, error: <b>{}</>, read time: <b>{:?}</>",
+                            self.name, attempts, self.modbus.attempts, addr_start, addr_len, e, read_time
+                        );
+                        span_error = Some(e.to_string());
+                        match e.kind() {
+                            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                            | ErrorKind::NotConnected | ErrorKind::UnexpectedEof => {
+                                if quiet { debug!("{}", msg) } else { error!("{}", msg) };
+                                disconnected = true;
+                                break;
+                            }
+                            _ => {
+                                if attempts == self.modbus.attempts {
+                                    if quiet { debug!("{}", msg) } else { error!("{}", msg) };
+                                    break;
+                                } else {
+                                    warn!("{}", msg);
+                                    continue;
+                                };
+                            }
+                        }
+                    }
+                }
+            }
+            self.record_span(start, *addr_start, *addr_len, span_error);
+        }
+
+        for (a, v) in &value_map {
+            debug!("MAP {} {}", a, v);
+        }
+
+        for p in params_to_read {
+            let mut val2:ParamKind;
+            let mut values = Vec::new();
+            for addr in p.reg_address .. p.reg_address +  p.len {                
+                let v = value_map.get(&addr);
+                match v {
+                    Some(value) => {
+                        values.push(*value)
+                    }
+                    None => {
+                        continue
+                    }
+                }
+            }
+            if values.len() > 0 {
+                match &p.value {
+                    ParamKind::Text(_) => {
+                        let bytes: Vec<u8> = values.iter().fold(vec![], |mut x, elem| {
+                            if (elem >> 8) as u8 != 0 {
+                                x.push((elem >> 8) as u8);
+                            }
+                            if (elem & 0xff) as u8 != 0 {
+                                x.push((elem & 0xff) as u8);
+                            }
+                            x
+                        });
+                        let id = String::from_utf8(bytes).unwrap();
+                        val2 = ParamKind::Text(Some(id));
+                    }
+                    ParamKind::NumberU16(_) => {
+                        debug!("-> {} = {:?}", p.name, values);
+                        val2 = ParamKind::NumberU16(Some(values[0] as u16));
+                    }
+                    ParamKind::NumberI16(_) => {
+                        debug!("-> {} = {:?}", p.name, values);
+                        val2 = ParamKind::NumberI16(Some(values[0] as i16));
+                    }
+                    ParamKind::NumberU32(_) => {
+                        let new_val: u32 = ((values[0] as u32) << 16) | values[1] as u32;
+                        debug!("-> {} = {:X?} {:X}", p.name, values, new_val);
+                        val2 = ParamKind::NumberU32(Some(new_val));
+                        if p.unit.unwrap_or_default() == "epoch" && new_val == 0 {
+                            //zero epoch makes no sense, let's set it to None
+                            val2 = ParamKind::NumberU32(None);
+                        }
+                    }
+                    ParamKind::NumberI32(_) => {
+                        let new_val: i32 =
+                            ((values[0] as i32) << 16) | (values[1] as u32) as i32;
+                        debug!("-> {} = {:X?} {:X}", p.name, values, new_val);
+                        val2 = ParamKind::NumberI32(Some(new_val));
+                    }
+                }
+            
+
+                let param = Parameter::new_from_string(
+                    p.name.clone(),
+                    val2,
+                    p.desc.clone(),
+                    p.unit.clone(),
+                    p.gain,
+                    p.reg_address,
+                    p.len,
+                    p.initial_read,
+                    p.save_to_influx,
+                    p.interval,
+                );
+                params.push(param.clone());
+            }
+        }
+
+        add_pv_string_power(&mut params);
+
+        let elapsed = now.elapsed();
+        let ms = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
+        info!(
+            "{}: read {} parameters [⏱️ {} ms]",
+            self.name,
+            params.len(),
+            ms
+        );
+
+        Ok(Snapshot {
+            params,
+            expected: params_to_read.iter().map(|p| p.name).collect(),
+            ms,
+            disconnected,
+        })
+    }
+
+}
+
+impl Device for HuaweiDevice {
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<Context>> {
+        async move {
+            let addr = parse_host(&self.host_port)?;
+            let ctx = connect_modbus(addr, self.slave, self.modbus.connect_timeout).await?;
+            //every connection starts with reading everything
+            self.poll_tick = 0;
+            Ok(ctx)
+        }
+        .boxed()
+    }
+
+    fn read_info<'a>(&'a mut self, ctx: &'a mut Context, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>> {
+        async move {
+            let snapshot = self.read_params(ctx, self.device_type.initial_plan(), quiet).await?;
+            if let Some(strings) = get_param_value(&snapshot.params, "nb_pv_strings") {
+                self.set_pv_strings(strings as u16);
+            }
+            Ok(snapshot)
+        }
+        .boxed()
+    }
+
+    fn read_snapshot<'a>(&'a mut self, ctx: &'a mut Context, full: bool, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>> {
+        async move {
+            //the first tick reads everything
+            let plan = self.schedule.plan(if full { 0 } else { self.poll_tick });
+            self.poll_tick = self.poll_tick.wrapping_add(1);
+            self.read_params(ctx, &plan, quiet).await
+        }
+        .boxed()
+    }
+
+    fn read_identity<'a>(&'a mut self, ctx: &'a mut Context) -> BoxFuture<'a, Vec<DeviceIdentity>> {
+        async move { self.read_identities(ctx).await }.boxed()
+    }
+
+    fn state(&self, params: &[Parameter]) -> Sun2000State {
+        let mut state = Sun2000State::default();
+        for p in params {
+            match p.value {
+                ParamKind::NumberU16(n) => match p.name {
+                    "fault_code" => state.fault_code = n,
+                    "device_status" => state.device_status = n,
+                    "grid_code" => state.grid_code = n,
+                    "state_1" => state.state_1 = n,
+                    "state_2" => state.state_2 = n,
+                    "alarm_1" => state.alarm_1 = n,
+                    "alarm_2" => state.alarm_2 = n,
+                    "alarm_3" => state.alarm_3 = n,
+                    _ => {}
+                },
+                ParamKind::NumberI16(n) if p.name == "storage_status" => state.storage_status = n,
+                ParamKind::NumberU32(n) if p.name == "state_3" => state.state_3 = n,
+                _ => {}
+            }
+        }
+        state
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+
+use super::defs::*;
+use super::Result;
+
+pub const DEVICE_ID_FUNCTION: u8 = 0x2b; //Read Device Identification (MEI)
+pub const DEVICE_ID_MEI_TYPE: u8 = 0x0e;
+pub const DEVICE_ID_EXTENDED: u8 = 0x03; //read device id code: extended objects (stream access)
+pub const DEVICE_ID_FIRST_OBJECT: u8 = 0x87; //number of devices, followed by their descriptions (0x88, 0x89, ...)
+pub const DEVICE_ID_MAX_REQUESTS: usize = 8; //limit of the "more follows" continuation requests
+
+/// Request data (after the function code) for reading the objects from `object`
+pub fn device_id_request(object: u8) -> Vec<u8> {
+    vec![DEVICE_ID_MEI_TYPE, DEVICE_ID_EXTENDED, object]
+}
+
+/// Objects of a single 0x2B/0x0E response
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceIdResponse {
+    /// the next request has to start with `next_object`
+    pub more_follows: bool,
+    pub next_object: u8,
+    pub objects: Vec<(u8, Vec<u8>)>,
+}
+
+/// Parses the response data: MEI type, read device id code, conformity level, more follows,
+/// next object id, number of objects and the objects (id, length, value)
+pub fn parse_device_id_response(data: &[u8]) -> Result<DeviceIdResponse> {
+    let (header, mut rest) = match data {
+        [DEVICE_ID_MEI_TYPE, _, _, more, next, count, rest @ ..] => ((*more == 0xff, *next, *count), rest),
+        _ => return Err(format!("invalid device identification response: {:02X?}", data).into()),
+    };
+    let (more_follows, next_object, count) = header;
+    let mut objects = vec![];
+    for _ in 0..count {
+        match rest {
+            [id, len, tail @ ..] if tail.len() >= *len as usize => {
+                objects.push((*id, tail[..*len as usize].to_vec()));
+                rest = &tail[*len as usize..];
+            }
+            _ => return Err(format!("truncated device identification object: {:02X?}", rest).into()),
+        }
+    }
+    Ok(DeviceIdResponse { more_follows, next_object, objects })
+}
+
+/// Identity of a device described in the 0x2B/0x0E response: the inverter itself
+/// and the devices behind it (power meter, batteries, ...)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DeviceIdentity {
+    pub model: Option<String>,
+    pub software_version: Option<String>,
+    pub protocol_version: Option<String>,
+    pub esn: Option<String>,
+    /// Modbus slave address of the device
+    pub device_id: Option<u8>,
+    pub feature_version: Option<String>,
+    /// all attributes as reported (id, value)
+    pub attributes: Vec<(String, String)>,
+}
+
+impl DeviceIdentity {
+    /// Parses the device description, eg:
+    /// `1=SUN2000-10KTL-M1;2=V100R001C00SPC133;3=V100R001C00;4=HV2010123456;5=0;6=...`
+    pub fn parse(description: &str) -> DeviceIdentity {
+        let mut identity = DeviceIdentity::default();
+        for attr in description.split(';') {
+            let (id, value) = match attr.split_once('=') {
+                Some((id, value)) => (id.trim(), value.trim()),
+                None => continue,
+            };
+            let text = Some(value.to_string()).filter(|v| !v.is_empty());
+            match id {
+                "1" => identity.model = text,
+                "2" => identity.software_version = text,
+                "3" => identity.protocol_version = text,
+                "4" => identity.esn = text,
+                "5" => identity.device_id = value.parse().ok(),
+                "6" => identity.feature_version = text,
+                _ => {}
+            }
+            identity.attributes.push((id.to_string(), value.to_string()));
+        }
+        identity
+    }
+
+    /// Kind of the device guessed from the model name
+    pub fn kind(&self) -> &'static str {
+        let model = self.model.as_deref().unwrap_or_default().to_uppercase();
+        if model.starts_with("SUN2000") {
+            "inverter"
+        } else if model.starts_with("LUNA") || model.contains("BAT") {
+            "battery"
+        } else if model.starts_with("DTSU") || model.starts_with("DDSU") || model.contains("METER") {
+            "meter"
+        } else if model.contains("DONGLE") || model.starts_with("SMARTLOGGER") {
+            "logger"
+        } else {
+            "device"
+        }
+    }
+
+    /// Builds the identities of all device descriptions (objects 0x88 and up)
+    pub fn from_objects(objects: &[(u8, Vec<u8>)]) -> Vec<DeviceIdentity> {
+        objects
+            .iter()
+            .filter(|(id, _)| *id > DEVICE_ID_FIRST_OBJECT)
+            .map(|(_, value)| DeviceIdentity::parse(&String::from_utf8_lossy(value)))
+            .collect()
+    }
+}
+
+impl fmt::Display for DeviceIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind(), self.model.as_deref().unwrap_or("unknown model"))?;
+        for (id, value) in &self.attributes {
+            if id != "1" {
+                write!(f, ", {}: {}", get_attribute_name(id), value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The identity of the polled inverter: the device at the polled `slave` address, or the first
+/// SUN2000 device (eg. polled at 0 over the internal wifi), or the first device at all
+pub fn inverter_identity(devices: &[DeviceIdentity], slave: u8) -> Option<&DeviceIdentity> {
+    devices
+        .iter()
+        .find(|d| d.device_id == Some(slave))
+        .or_else(|| devices.iter().find(|d| d.kind() == "inverter"))
+        .or_else(|| devices.first())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INVERTER: &str = "1=SUN2000-10KTL-M1;2=V100R001C00SPC133;3=V100R001C00;4=HV2010123456;5=1;6=";
+    const METER: &str = "1=DTSU666-H;2=V100R001C10;3=V100R001C00;4=;5=11";
+    const BATTERY: &str = "1=LUNA2000-5-E0;2=V100R002C00SPC107;4=HV2150012345";
+
+    /// 0x2B/0x0E response data (after the function code): conformity level 0x83
+    fn response(more_follows: bool, next: u8, objects: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut data = vec![DEVICE_ID_MEI_TYPE, DEVICE_ID_EXTENDED, 0x83, if more_follows { 0xff } else { 0x00 }, next, objects.len() as u8];
+        for (id, value) in objects {
+            data.push(*id);
+            data.push(value.len() as u8);
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    #[test]
+    fn continued_responses() {
+        let first = response(true, 0x89, &[(DEVICE_ID_FIRST_OBJECT, &[2]), (0x88, INVERTER.as_bytes())]);
+        let first = parse_device_id_response(&first).unwrap();
+        assert!(first.more_follows);
+        assert_eq!(first.next_object, 0x89);
+        assert_eq!(first.objects, vec![(DEVICE_ID_FIRST_OBJECT, vec![2]), (0x88, INVERTER.as_bytes().to_vec())]);
+        let last = parse_device_id_response(&response(false, 0, &[(0x89, METER.as_bytes())])).unwrap();
+        assert!(!last.more_follows);
+
+        let objects: Vec<_> = first.objects.into_iter().chain(last.objects).collect();
+        let devices = DeviceIdentity::from_objects(&objects);
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].kind(), "inverter");
+        assert_eq!(devices[0].esn.as_deref(), Some("HV2010123456"));
+        assert_eq!(devices[1].kind(), "meter");
+        assert_eq!(devices[1].device_id, Some(11));
+    }
+
+    #[test]
+    fn invalid_responses() {
+        assert!(parse_device_id_response(&[DEVICE_ID_MEI_TYPE, DEVICE_ID_EXTENDED, 0x83]).is_err());
+        assert!(parse_device_id_response(&[0x0d, DEVICE_ID_EXTENDED, 0x83, 0, 0, 0]).is_err());
+        //the object is shorter than its length
+        let mut data = response(false, 0, &[(0x88, INVERTER.as_bytes())]);
+        data.truncate(data.len() - 5);
+        let e = parse_device_id_response(&data).unwrap_err();
+        assert!(e.to_string().starts_with("truncated device identification object"), "{}", e);
+        //less objects than the count
+        let mut data = response(false, 0, &[(0x88, INVERTER.as_bytes())]);
+        data[5] = 2;
+        assert!(parse_device_id_response(&data).is_err());
+    }
+
+    #[test]
+    fn descriptions() {
+        let inverter = DeviceIdentity::parse(INVERTER);
+        assert_eq!(inverter.model.as_deref(), Some("SUN2000-10KTL-M1"));
+        assert_eq!(inverter.software_version.as_deref(), Some("V100R001C00SPC133"));
+        assert_eq!(inverter.protocol_version.as_deref(), Some("V100R001C00"));
+        assert_eq!(inverter.device_id, Some(1));
+        //empty values are kept in the attributes only
+        assert_eq!(inverter.feature_version, None);
+        assert_eq!(inverter.attributes.len(), 6);
+        let meter = DeviceIdentity::parse(METER);
+        assert_eq!((meter.kind(), meter.esn.as_deref()), ("meter", None));
+        let battery = DeviceIdentity::parse(BATTERY);
+        assert_eq!((battery.kind(), battery.device_id), ("battery", None));
+        assert_eq!(DeviceIdentity::parse("garbage").attributes, vec![]);
+    }
+
+    #[test]
+    fn polled_inverter() {
+        let cascade = "1=SUN2000-5KTL-L1;4=HV2010654321;5=2";
+        let devices: Vec<_> = [METER, INVERTER, cascade].iter().map(|d| DeviceIdentity::parse(d)).collect();
+        assert_eq!(inverter_identity(&devices, 2).and_then(|d| d.esn.as_deref()), Some("HV2010654321"));
+        assert_eq!(inverter_identity(&devices, 1).and_then(|d| d.esn.as_deref()), Some("HV2010123456"));
+        //internal wifi
+        assert_eq!(inverter_identity(&devices, 0).and_then(|d| d.esn.as_deref()), Some("HV2010123456"));
+        assert_eq!(inverter_identity(&devices[..1], 0).and_then(|d| d.model.as_deref()), Some("DTSU666-H"));
+        assert_eq!(inverter_identity(&[], 1), None);
+    }
+}
@@ -8,5 +8,22 @@ pub mod params;
 pub mod defs;
 pub mod sun2000;
 pub mod dump;
+pub mod shared;
+pub mod energy;
+pub mod tariff;
+pub mod export_limit;
+pub mod battery;
+pub mod ephemeris;
+pub mod retry;
+pub mod upload;
+pub mod identity;
+pub mod discovery;
+pub mod device;
+pub mod huawei;
+pub mod sunspec;
+pub mod clock;
+pub mod settings;
+pub mod strings;
 
 pub use defs::*;
+pub mod options;
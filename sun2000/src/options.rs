@@ -0,0 +1,94 @@
+use std::time::Duration;
+
+use super::Result;
+
+pub const OPTION_MAX_SECS: f64 = 365.0 * 86400.0; //upper limit of the time options, so they always fit a Duration
+
+/// Number option `name` within `min..=max`, `None` when it is not set
+pub fn number<F: Fn(&str) -> Option<String>>(get: &F, name: &str, min: f64, max: f64) -> Result<Option<f64>> {
+    match get(name) {
+        Some(v) => {
+            let v: f64 = v.trim().parse().map_err(|e| format!("invalid {}: {}", name, e))?;
+            if !v.is_finite() || v < min || v > max {
+                return Err(format!("invalid {}: {}, expected {} to {}", name, v, min, max).into());
+            }
+            Ok(Some(v))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Yes/no option `name`: `yes`/`true`/`1` or `no`/`false`/`0`
+pub fn flag<F: Fn(&str) -> Option<String>>(get: &F, name: &str) -> Result<Option<bool>> {
+    match get(name).as_deref().map(str::trim) {
+        Some("yes") | Some("true") | Some("1") => Ok(Some(true)),
+        Some("no") | Some("false") | Some("0") => Ok(Some(false)),
+        Some(other) => Err(format!("invalid {}: {:?}, expected yes or no", name, other).into()),
+        None => Ok(None),
+    }
+}
+
+/// Whole number option `name` within `min..=max`
+pub fn integer<F: Fn(&str) -> Option<String>>(get: &F, name: &str, min: u32, max: u32) -> Result<Option<u32>> {
+    match number(get, name, min as f64, max as f64)? {
+        Some(v) if v.fract() != 0.0 => Err(format!("invalid {}: {}, expected a whole number", name, v).into()),
+        v => Ok(v.map(|v| v as u32)),
+    }
+}
+
+/// Time option `name` in secs within `min..=max` (at most `OPTION_MAX_SECS`)
+pub fn secs<F: Fn(&str) -> Option<String>>(get: &F, name: &str, min: f64, max: f64) -> Result<Option<Duration>> {
+    Ok(number(get, name, min, max.min(OPTION_MAX_SECS))?.map(Duration::from_secs_f64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(name: &str) -> Option<String> {
+        match name {
+            "interval" => Some(" 2.5 ".to_string()),
+            "negative" => Some("-1".to_string()),
+            "huge" => Some("1e30".to_string()),
+            "nan" => Some("NaN".to_string()),
+            "text" => Some("fast".to_string()),
+            "count" => Some("1000".to_string()),
+            "enabled" => Some(" yes".to_string()),
+            "disabled" => Some("0".to_string()),
+            "switch" => Some("on".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn bounds() {
+        assert_eq!(number(&get, "interval", 0.0, 10.0).unwrap(), Some(2.5));
+        assert_eq!(number(&get, "missing", 0.0, 10.0).unwrap(), None);
+        assert!(number(&get, "negative", 0.0, 10.0).is_err());
+        assert_eq!(number(&get, "negative", -5.0, 10.0).unwrap(), Some(-1.0));
+        assert!(number(&get, "nan", f64::MIN, f64::MAX).is_err());
+        assert!(number(&get, "text", 0.0, 10.0).is_err());
+    }
+
+    #[test]
+    fn whole_numbers() {
+        assert_eq!(integer(&get, "count", 0, 1000).unwrap(), Some(1000));
+        assert!(integer(&get, "count", 0, 255).is_err());
+        assert!(integer(&get, "interval", 0, 10).is_err());
+    }
+
+    #[test]
+    fn flags() {
+        assert_eq!(flag(&get, "enabled").unwrap(), Some(true));
+        assert_eq!(flag(&get, "disabled").unwrap(), Some(false));
+        assert_eq!(flag(&get, "missing").unwrap(), None);
+        assert!(flag(&get, "switch").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(secs(&get, "interval", 0.0, f64::MAX).unwrap(), Some(Duration::from_millis(2500)));
+        //would panic in Duration::from_secs_f64
+        assert!(secs(&get, "huge", 0.0, f64::MAX).is_err());
+    }
+}
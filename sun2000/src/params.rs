@@ -1,12 +1,15 @@
 use std::fmt;
-use std::collections::BinaryHeap;
 use std::collections::HashMap;
 use serde::{Serialize, Deserialize};
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref PARAMETERS_INITIAL: (Vec<&'static Parameter>, Vec<(u16,u16)>) = filter_sort_params(true);
-    pub static ref PARAMETERS_POLL: (Vec<&'static Parameter>, Vec<(u16,u16)>) = filter_sort_params(false);
+    pub static ref PARAMETERS_INITIAL: ReadPlan = filter_sort_params(DeviceType::Inverter, |p| p.initial_read);
+    pub static ref PARAMETERS_POLL: ReadPlan = filter_sort_params(DeviceType::Inverter, is_poll_param);
+    pub static ref SMARTLOGGER_PARAMETERS_INITIAL: ReadPlan = filter_sort_params(DeviceType::SmartLogger, |p| p.initial_read);
+    pub static ref SMARTLOGGER_PARAMETERS_POLL: ReadPlan = filter_sort_params(DeviceType::SmartLogger, is_poll_param);
+    pub static ref PV_STRING_PARAMETERS: Vec<Parameter> = make_pv_string_parameters();
+    pub static ref PV_STRING_POWER_PARAMETERS: Vec<Parameter> = make_pv_string_power_parameters();
     pub static ref PARAMETER_MAP: HashMap<u16, &'static Parameter> = make_map();
 }
 
@@ -43,11 +46,119 @@ pub struct Parameter {
     pub len: u16,
     pub initial_read: bool,
     pub save_to_influx: bool,
+    /// secs between reads when polling
+    pub interval: u32,
+}
+
+pub const INTERVAL_FAST: u32 = 10; //power, state and alarms
+pub const INTERVAL_SLOW: u32 = 60; //energy counters, temperatures
+pub const INTERVAL_STATIC: u32 = 600; //values which almost never change
+
+pub const PV_STRING_REGISTER: u16 = 32016; //PV1 voltage and current, followed by the next strings
+pub const PV_STRINGS_MAX: u16 = 24; //strings in the PV register block
+
+pub const SPAN_MAX_REGISTERS: u16 = 125; //max registers in a single read request (Modbus limit)
+pub const SPAN_MAX_GAP: u16 = 16; //unused registers read to save a separate request
+
+/// Register blocks (first, last) of the SUN2000 register map, the inverter refuses
+/// (illegal data address) a read crossing the block boundary
+const REGISTER_BLOCKS: &[(u16, u16)] = &[
+    (30000, 30209), //device information
+    (32000, 32015), //running state and alarms
+    (32016, 32063), //PV strings
+    (32064, 32299), //inverter running information
+    (37000, 37099), //battery unit 1
+    (37100, 37199), //power meter
+    (37200, 37299), //optimizers
+    (37700, 37799), //battery totals
+    (40000, 40999), //system time and settings
+    (42000, 42999), //grid code
+    (43000, 43999), //time zone
+    (47000, 47999), //battery settings
+];
+
+/// Register blocks of the SmartLogger (plant level) register map
+const SMARTLOGGER_REGISTER_BLOCKS: &[(u16, u16)] = &[
+    (40000, 40099), //system time and settings
+    (40500, 40599), //plant running information
+];
+
+/// Type of the polled device, each one has its own register map
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    /// SUN2000 inverter (directly or via the SDongle)
+    Inverter,
+    /// SmartLogger/EMMA: aggregated plant data, the devices behind it are addressed by slave ID
+    SmartLogger,
+}
+
+impl DeviceType {
+    /// Parses the `device` option: `inverter` (default) or `smartlogger`
+    pub fn parse(s: &str) -> Result<DeviceType, String> {
+        match s.trim().to_lowercase().as_str() {
+            "inverter" | "sun2000" => Ok(DeviceType::Inverter),
+            "smartlogger" | "emma" => Ok(DeviceType::SmartLogger),
+            other => Err(format!("unknown device type: {:?}", other)),
+        }
+    }
+
+    pub fn parameters(&self) -> &'static [Parameter] {
+        match self {
+            DeviceType::Inverter => PARAMETERS,
+            DeviceType::SmartLogger => SMARTLOGGER_PARAMETERS,
+        }
+    }
+
+    fn register_blocks(&self) -> &'static [(u16, u16)] {
+        match self {
+            DeviceType::Inverter => REGISTER_BLOCKS,
+            DeviceType::SmartLogger => SMARTLOGGER_REGISTER_BLOCKS,
+        }
+    }
+
+    /// Parameters read once after connecting
+    pub fn initial_plan(&self) -> &'static ReadPlan {
+        match self {
+            DeviceType::Inverter => &PARAMETERS_INITIAL,
+            DeviceType::SmartLogger => &SMARTLOGGER_PARAMETERS_INITIAL,
+        }
+    }
+
+    /// All parameters read when polling
+    pub fn poll_plan(&self) -> &'static ReadPlan {
+        match self {
+            DeviceType::Inverter => &PARAMETERS_POLL,
+            DeviceType::SmartLogger => &SMARTLOGGER_PARAMETERS_POLL,
+        }
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceType::Inverter => write!(f, "inverter"),
+            DeviceType::SmartLogger => write!(f, "smartlogger"),
+        }
+    }
+}
+
+/// Parameters to read and the register spans (start address, length) covering them
+pub type ReadPlan = (Vec<&'static Parameter>, Vec<(u16,u16)>);
+
+/// Scaled numeric value of the named parameter, if it is present in `params`
+pub fn get_param_value(params: &[Parameter], name: &str) -> Option<f64> {
+    params.iter().find(|p| p.name == name).and_then(|p| p.get_scaled_value())
+}
+
+/// Parameter of the SUN2000 register map by name, the other backends normalize their values to these
+pub fn find_parameter(name: &str) -> Option<&'static Parameter> {
+    PARAMETERS.iter().find(|p| p.name == name)
 }
 
 fn make_map() -> HashMap<u16, &'static Parameter> {
     let mut res = HashMap::new();
-    for p in PARAMETERS.iter() {
+    //the addresses of the register maps do not overlap, so the dumps of both can be decoded
+    for p in PARAMETERS.iter().chain(SMARTLOGGER_PARAMETERS.iter()).chain(PV_STRING_PARAMETERS.iter()) {
         res.insert(p.reg_address, p);
     }
     return res
@@ -55,126 +166,368 @@ fn make_map() -> HashMap<u16, &'static Parameter> {
 
 #[rustfmt::skip]
 const PARAMETERS:  &'static [Parameter] = &[
-        Parameter{name: "model_name", value: ParamKind::Text(None), desc: None,  unit: None, gain: 1, reg_address: 30000, len: 15, initial_read: true, save_to_influx: false},
-        Parameter{name: "serial_number", value: ParamKind::Text(None), desc: None, unit:  None, gain: 1, reg_address: 30015, len: 10, initial_read: true, save_to_influx: false},
-        Parameter{name: "product_number", value: ParamKind::Text(None), desc: None, unit:  None, gain: 1, reg_address: 30025, len: 10, initial_read: true, save_to_influx: false},
-        Parameter{name: "model_id", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30070, len: 1, initial_read: true, save_to_influx: false},
-        Parameter{name: "nb_pv_strings", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30071, len: 1, initial_read: true, save_to_influx: false},
-        Parameter{name: "nb_mpp_tracks", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30072, len: 1, initial_read: true, save_to_influx: false},
-        Parameter{name: "rated_power", value: ParamKind::NumberU32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 30073, len: 2, initial_read: true, save_to_influx: false},
-        //Parameter{name: "P_max", value: ParamKind::NumberU32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 30075, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "S_max", value: ParamKind::NumberU32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 30077, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "Q_max_out", value: ParamKind::NumberI32(None), desc: None, unit: Some("VAr"), gain: 1, reg_address: 30079, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "Q_max_in", value: ParamKind::NumberI32(None), desc: None, unit: Some("VAr"), gain: 1, reg_address: 30081, len: 2, initial_read: false, save_to_influx: false},
-        Parameter{name: "state_1", value: ParamKind::NumberU16(None), desc: None, unit: Some("state_bitfield16"), gain: 1, reg_address: 32000, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "state_2", value: ParamKind::NumberU16(None), desc: None, unit: Some("state_opt_bitfield16"), gain: 1, reg_address: 32002, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "state_3", value: ParamKind::NumberU32(None), desc: None, unit: Some("state_opt_bitfield32"), gain: 1, reg_address: 32003, len: 2, initial_read: false, save_to_influx: false},
-        Parameter{name: "alarm_1", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32008, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "alarm_2", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32009, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "alarm_3", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32010, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "input_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32064, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "line_voltage_A_B", value: ParamKind::NumberU16(None), desc: Some("grid_voltage"), unit: Some("V"), gain: 10, reg_address: 32066, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "line_voltage_B_C", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32067, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "line_voltage_C_A", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32068, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_A_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32069, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_B_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32070, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_C_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32071, len: 1, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_A_current", value: ParamKind::NumberI32(None), desc: Some("grid_current"), unit: Some("A"), gain: 1000, reg_address: 32072, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_B_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("A"), gain: 1000, reg_address: 32074, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "phase_C_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("A"), gain: 1000, reg_address: 32076, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "day_active_power_peak", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32078, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "active_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32080, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "reactive_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 32082, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "power_factor", value: ParamKind::NumberI16(None), desc: None, unit: None, gain: 1000, reg_address: 32084, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "grid_frequency", value: ParamKind::NumberU16(None), desc: None, unit: Some("Hz"), gain: 100, reg_address: 32085, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "efficiency", value: ParamKind::NumberU16(None), desc: None, unit: Some("%"), gain: 100, reg_address: 32086, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "internal_temperature", value: ParamKind::NumberI16(None), desc: None, unit: Some("°C"), gain: 10, reg_address: 32087, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "insulation_resistance", value: ParamKind::NumberU16(None), desc: None, unit: Some("MΩ"), gain: 100, reg_address: 32088, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "device_status", value: ParamKind::NumberU16(None), desc: None, unit: Some("status_enum"), gain: 1, reg_address: 32089, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "fault_code", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 32090, len: 1, initial_read: false, save_to_influx: false},
-        //Parameter{name: "startup_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32091, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "shutdown_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32093, len: 2, initial_read: false, save_to_influx: false},
-        Parameter{name: "accumulated_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 32106, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "unknown_time_1", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32110, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "unknown_time_2", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32156, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "unknown_time_3", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32160, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "unknown_time_4", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 35113, len: 2, initial_read: false, save_to_influx: false},
-
-        //Parameter{name: "storage_status", value: ParamKind::NumberI16(None), desc: None, unit: Some("storage_status_enum"), gain: 1, reg_address: 37000, len: 1, initial_read: false, save_to_influx: false},
-        Parameter{name: "grid_A_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37101, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "grid_B_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37103, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "grid_C_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37105, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "active_grid_A_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37107, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_B_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37109, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_C_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37111, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "power_meter_active_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37113, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "daily_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 32114, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "power_meter_reactive_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 37115, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "active_grid_power_factor", value: ParamKind::NumberI16(None), desc: None, unit: None, gain: 1000, reg_address: 37117, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "active_grid_frequency", value: ParamKind::NumberI16(None), desc: None, unit: Some("Hz"), gain: 100, reg_address: 37118, len: 1, initial_read: false, save_to_influx: true},
-        Parameter{name: "grid_exported_energy", value: ParamKind::NumberI32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 37119, len: 2, initial_read: false, save_to_influx: true},
-        Parameter{name: "grid_accumulated_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 37121, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "grid_accumulated_reactive", value: ParamKind::NumberU32(None), desc: None, unit: Some("kVarh"), gain: 100, reg_address: 37123, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_A_B_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37126, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_B_C_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37128, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_C_A_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37130, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_A_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37132, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_B_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37134, len: 2, initial_read: false, save_to_influx: true},
-        //Parameter{name: "active_grid_C_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37136, len: 2, initial_read: false, save_to_influx: true},
-
-        //Parameter{name: "system_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 40000, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "unknown_time_5", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 40500, len: 2, initial_read: false, save_to_influx: false},
-        //Parameter{name: "grid_code", value: ParamKind::NumberU16(None), desc: None, unit: Some("grid_enum"), gain: 1, reg_address: 42000, len: 1, initial_read: false, save_to_influx: false},
-        //Parameter{name: "time_zone", value: ParamKind::NumberI16(None), desc: None, unit: Some("min"), gain: 1, reg_address: 43006, len: 1, initial_read: false, save_to_influx: false},
+        Parameter{name: "model_name", value: ParamKind::Text(None), desc: None,  unit: None, gain: 1, reg_address: 30000, len: 15, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "serial_number", value: ParamKind::Text(None), desc: None, unit:  None, gain: 1, reg_address: 30015, len: 10, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "product_number", value: ParamKind::Text(None), desc: None, unit:  None, gain: 1, reg_address: 30025, len: 10, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "model_id", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30070, len: 1, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "nb_pv_strings", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30071, len: 1, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "nb_mpp_tracks", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 30072, len: 1, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "rated_power", value: ParamKind::NumberU32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 30073, len: 2, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "P_max", value: ParamKind::NumberU32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 30075, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "S_max", value: ParamKind::NumberU32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 30077, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "Q_max_out", value: ParamKind::NumberI32(None), desc: None, unit: Some("VAr"), gain: 1, reg_address: 30079, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "Q_max_in", value: ParamKind::NumberI32(None), desc: None, unit: Some("VAr"), gain: 1, reg_address: 30081, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "state_1", value: ParamKind::NumberU16(None), desc: None, unit: Some("state_bitfield16"), gain: 1, reg_address: 32000, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "state_2", value: ParamKind::NumberU16(None), desc: None, unit: Some("state_opt_bitfield16"), gain: 1, reg_address: 32002, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "state_3", value: ParamKind::NumberU32(None), desc: None, unit: Some("state_opt_bitfield32"), gain: 1, reg_address: 32003, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "alarm_1", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32008, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "alarm_2", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32009, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "alarm_3", value: ParamKind::NumberU16(None), desc: None, unit: Some("alarm_bitfield16"), gain: 1, reg_address: 32010, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "input_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32064, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "line_voltage_A_B", value: ParamKind::NumberU16(None), desc: Some("grid_voltage"), unit: Some("V"), gain: 10, reg_address: 32066, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "line_voltage_B_C", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32067, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "line_voltage_C_A", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32068, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_A_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32069, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_B_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32070, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_C_voltage", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 32071, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_A_current", value: ParamKind::NumberI32(None), desc: Some("grid_current"), unit: Some("A"), gain: 1000, reg_address: 32072, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_B_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("A"), gain: 1000, reg_address: 32074, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "phase_C_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("A"), gain: 1000, reg_address: 32076, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "day_active_power_peak", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32078, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "active_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 32080, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "reactive_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 32082, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "power_factor", value: ParamKind::NumberI16(None), desc: None, unit: None, gain: 1000, reg_address: 32084, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "grid_frequency", value: ParamKind::NumberU16(None), desc: None, unit: Some("Hz"), gain: 100, reg_address: 32085, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "efficiency", value: ParamKind::NumberU16(None), desc: None, unit: Some("%"), gain: 100, reg_address: 32086, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "internal_temperature", value: ParamKind::NumberI16(None), desc: None, unit: Some("°C"), gain: 10, reg_address: 32087, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "insulation_resistance", value: ParamKind::NumberU16(None), desc: None, unit: Some("MΩ"), gain: 100, reg_address: 32088, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_STATIC},
+        Parameter{name: "device_status", value: ParamKind::NumberU16(None), desc: None, unit: Some("status_enum"), gain: 1, reg_address: 32089, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "fault_code", value: ParamKind::NumberU16(None), desc: None, unit: None, gain: 1, reg_address: 32090, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        //Parameter{name: "startup_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32091, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "shutdown_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32093, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "accumulated_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 32106, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        //Parameter{name: "unknown_time_1", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32110, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "unknown_time_2", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32156, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "unknown_time_3", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 32160, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "unknown_time_4", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 35113, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+
+        //Parameter{name: "storage_status", value: ParamKind::NumberI16(None), desc: None, unit: Some("storage_status_enum"), gain: 1, reg_address: 37000, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_FAST},
+        Parameter{name: "grid_A_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37101, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "grid_B_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37103, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "grid_C_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37105, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "active_grid_A_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37107, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_B_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37109, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_C_current", value: ParamKind::NumberI32(None), desc: None, unit: Some("I"), gain: 100, reg_address: 37111, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "power_meter_active_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37113, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "daily_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 32114, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "power_meter_reactive_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 37115, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "active_grid_power_factor", value: ParamKind::NumberI16(None), desc: None, unit: None, gain: 1000, reg_address: 37117, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "active_grid_frequency", value: ParamKind::NumberI16(None), desc: None, unit: Some("Hz"), gain: 100, reg_address: 37118, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "grid_exported_energy", value: ParamKind::NumberI32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 37119, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "grid_accumulated_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 100, reg_address: 37121, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        //Parameter{name: "grid_accumulated_reactive", value: ParamKind::NumberU32(None), desc: None, unit: Some("kVarh"), gain: 100, reg_address: 37123, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        //Parameter{name: "active_grid_A_B_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37126, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_B_C_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37128, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_C_A_voltage", value: ParamKind::NumberI32(None), desc: None, unit: Some("V"), gain: 10, reg_address: 37130, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_A_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37132, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_B_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37134, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        //Parameter{name: "active_grid_C_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 37136, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+
+        Parameter{name: "system_time", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 40000, len: 2, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "unknown_time_5", value: ParamKind::NumberU32(None), desc: None, unit: Some("epoch"), gain: 1, reg_address: 40500, len: 2, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        //Parameter{name: "grid_code", value: ParamKind::NumberU16(None), desc: None, unit: Some("grid_enum"), gain: 1, reg_address: 42000, len: 1, initial_read: false, save_to_influx: false, interval: INTERVAL_STATIC},
+        Parameter{name: "time_zone", value: ParamKind::NumberI16(None), desc: None, unit: Some("min"), gain: 1, reg_address: 43006, len: 1, initial_read: true, save_to_influx: false, interval: INTERVAL_STATIC},
+    ];
+
+/// Plant level parameters of the SmartLogger, named as their inverter counterparts
+/// so the rest of the pipeline (influx, energy accounting, http) handles them the same way
+#[rustfmt::skip]
+const SMARTLOGGER_PARAMETERS: &[Parameter] = &[
+        Parameter{name: "input_power", value: ParamKind::NumberU32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 40521, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "co2_reduction", value: ParamKind::NumberU32(None), desc: None, unit: Some("kg"), gain: 10, reg_address: 40523, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_STATIC},
+        Parameter{name: "active_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: 40525, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "power_factor", value: ParamKind::NumberI16(None), desc: None, unit: None, gain: 1000, reg_address: 40532, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "reactive_power", value: ParamKind::NumberI32(None), desc: None, unit: Some("VA"), gain: 1, reg_address: 40544, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "accumulated_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 10, reg_address: 40560, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "daily_yield_energy", value: ParamKind::NumberU32(None), desc: None, unit: Some("kWh"), gain: 10, reg_address: 40562, len: 2, initial_read: false, save_to_influx: true, interval: INTERVAL_SLOW},
+        Parameter{name: "phase_A_current", value: ParamKind::NumberI16(None), desc: None, unit: Some("A"), gain: 10, reg_address: 40572, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "phase_B_current", value: ParamKind::NumberI16(None), desc: None, unit: Some("A"), gain: 10, reg_address: 40573, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "phase_C_current", value: ParamKind::NumberI16(None), desc: None, unit: Some("A"), gain: 10, reg_address: 40574, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "line_voltage_A_B", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 40575, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "line_voltage_B_C", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 40576, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
+        Parameter{name: "line_voltage_C_A", value: ParamKind::NumberU16(None), desc: None, unit: Some("V"), gain: 10, reg_address: 40577, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST},
     ];
 
 
-fn filter_sort_params(initial_read: bool) -> (Vec<&'static Parameter>, Vec<(u16,u16)>)  {
+fn pv_string_name(string: u16, value: &str) -> &'static str {
+    //made once for the static tables
+    Box::leak(format!("pv_{:02}_{}", string, value).into_boxed_str())
+}
 
-    let mut params: Vec<&Parameter> = PARAMETERS.iter().collect();
-    params.sort_by(|a,b| a.reg_address.partial_cmp(&b.reg_address).unwrap());
+/// Voltage and current of all the PV strings, in the register order
+fn make_pv_string_parameters() -> Vec<Parameter> {
+    let mut res = vec![];
+    for i in 1..=PV_STRINGS_MAX {
+        let reg_address = PV_STRING_REGISTER + (i - 1) * 2;
+        res.push(Parameter{name: pv_string_name(i, "voltage"), value: ParamKind::NumberI16(None), desc: None, unit: Some("V"), gain: 10, reg_address, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST});
+        res.push(Parameter{name: pv_string_name(i, "current"), value: ParamKind::NumberI16(None), desc: None, unit: Some("A"), gain: 100, reg_address: reg_address + 1, len: 1, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST});
+    }
+    res
+}
+
+/// Power of the PV strings, computed from their voltage and current (no registers of their own)
+fn make_pv_string_power_parameters() -> Vec<Parameter> {
+    (1..=PV_STRINGS_MAX)
+        .map(|i| Parameter{name: pv_string_name(i, "power"), value: ParamKind::NumberI32(None), desc: None, unit: Some("W"), gain: 1, reg_address: PV_STRING_REGISTER + (i - 1) * 2, len: 0, initial_read: false, save_to_influx: true, interval: INTERVAL_FAST})
+        .collect()
+}
 
-    let params_to_read: Vec<&Parameter> = params.into_iter().filter(|s| {
-        (initial_read && s.initial_read)
-            || (!initial_read
-                && (s.save_to_influx
-                    || s.name.starts_with("state_")
-                    || s.name.starts_with("alarm_")
-                    || s.name.ends_with("_status")
-                    || s.name.ends_with("_code")))}).collect();
-
-    let mut params_addr = BinaryHeap::new();
-    use std::cmp::Reverse;
-    for p in &params_to_read {
-        for addr_offset in 0 .. p.len { 
-            params_addr.push(Reverse(p.reg_address + addr_offset));
+/// Voltage and current parameters of the first `strings` PV strings
+pub fn pv_string_parameters(strings: u16) -> Vec<&'static Parameter> {
+    PV_STRING_PARAMETERS.iter().take(strings.min(PV_STRINGS_MAX) as usize * 2).collect()
+}
+
+/// Inverter poll parameters extended with the ones of the first `strings` PV strings
+pub fn poll_parameters_with_pv_strings(strings: u16) -> Vec<&'static Parameter> {
+    let mut params = DeviceType::Inverter.poll_plan().0.clone();
+    params.extend(pv_string_parameters(strings));
+    params.sort_by_key(|p| p.reg_address);
+    params
+}
+
+/// Adds the power of every PV string with both the voltage and current in `params`
+pub fn add_pv_string_power(params: &mut Vec<Parameter>) {
+    for (i, power) in PV_STRING_POWER_PARAMETERS.iter().enumerate() {
+        let voltage = get_param_value(params, PV_STRING_PARAMETERS[i * 2].name);
+        let current = get_param_value(params, PV_STRING_PARAMETERS[i * 2 + 1].name);
+        if let (Some(voltage), Some(current)) = (voltage, current) {
+            let mut p = power.clone();
+            p.value = ParamKind::NumberI32(Some((voltage * current).round() as i32));
+            params.push(p);
         }
     }
+}
 
-    
-    let mut addr_span: Vec<(u16,u16)> = Vec::new();
-    {
-        let mut addr_init: u16 = params_addr.pop().unwrap().0;
-        let mut addr_end: u16 = addr_init;
-        let mut addr_len = 1;
-        while !params_addr.is_empty() {
-            let addr_next: u16 = params_addr.pop().unwrap().0;
-            if addr_next == addr_end + 1 {
-                addr_end = addr_next;
-                addr_len = addr_len + 1;
-                if params_addr.is_empty() {
-                    addr_span.push((addr_init, addr_len));    
-                }
-                continue
+/// Parameters read when polling (and not only on the initial read)
+pub fn is_poll_param(p: &Parameter) -> bool {
+    p.save_to_influx
+        || p.name.starts_with("state_")
+        || p.name.starts_with("alarm_")
+        || p.name.ends_with("_status")
+        || p.name.ends_with("_code")
+}
+
+fn filter_sort_params<F: Fn(&Parameter) -> bool>(device: DeviceType, filter: F) -> ReadPlan {
+
+    let mut params: Vec<&Parameter> = device.parameters().iter().collect();
+    params.sort_by(|a,b| a.reg_address.partial_cmp(&b.reg_address).unwrap());
+
+    let params_to_read: Vec<&Parameter> = params.into_iter().filter(|p| filter(p)).collect();
+    let addr_span = make_spans(device, &params_to_read);
+
+   (params_to_read, addr_span)
+}
+
+/// Merges the registers of the parameters into read spans
+fn make_spans(device: DeviceType, params: &[&Parameter]) -> Vec<(u16,u16)> {
+    let registers: Vec<(u16, u16)> = params.iter().map(|p| (p.reg_address, p.len)).collect();
+    plan_block_spans(device.register_blocks(), &registers, SPAN_MAX_GAP, SPAN_MAX_REGISTERS)
+}
+
+/// Index of the register block containing `addr`, `None` outside of the known blocks
+fn register_block(blocks: &[(u16, u16)], addr: u16) -> Option<usize> {
+    blocks.iter().position(|(first, last)| addr >= *first && addr <= *last)
+}
+
+/// Plans the read requests of the inverter registers, see [`plan_block_spans`]
+pub fn plan_spans(registers: &[(u16, u16)], max_gap: u16, max_len: u16) -> Vec<(u16, u16)> {
+    plan_block_spans(REGISTER_BLOCKS, registers, max_gap, max_len)
+}
+
+/// Plans the read requests for the registers `(address, len)`. The registers are merged into spans
+/// across gaps up to `max_gap` registers (only inside a known register block, the gaps elsewhere may be
+/// unreadable), no span is longer than `max_len` or crosses a boundary of the register `blocks`.
+pub fn plan_block_spans(blocks: &[(u16, u16)], registers: &[(u16, u16)], max_gap: u16, max_len: u16) -> Vec<(u16, u16)> {
+    let mut registers = registers.to_vec();
+    registers.sort_unstable();
+
+    let mut spans: Vec<(u16, u16)> = Vec::new();
+    for (addr, len) in registers {
+        let end = addr + len;
+        if let Some((span_start, span_len)) = spans.last_mut() {
+            let span_end = *span_start + *span_len;
+            let block = register_block(blocks, *span_start);
+            let same_block = block == register_block(blocks, end - 1);
+            let reachable = if addr <= span_end {
+                //contiguous or parameters sharing registers
+                true
             } else {
-                addr_span.push((addr_init, addr_len));
-                addr_init = addr_next;
-                addr_end = addr_next;
-                addr_len = 1;
+                block.is_some() && addr - span_end <= max_gap
+            };
+            let new_end = span_end.max(end);
+            if same_block && reachable && new_end - *span_start <= max_len {
+                *span_len = new_end - *span_start;
+                continue;
             }
         }
+        spans.push((addr, len));
     }
-    
+    spans
+}
 
-   (params_to_read, addr_span)
+/// Parses per-parameter poll interval overrides, eg: `internal_temperature:300, grid_exported_energy:30`
+pub fn parse_poll_intervals(s: &str) -> Result<HashMap<String, u32>, String> {
+    let mut res = HashMap::new();
+    for item in s.split(',').map(str::trim).filter(|i| !i.is_empty()) {
+        let (name, secs) = item.split_once(':').ok_or_else(|| format!("invalid poll interval: {:?}", item))?;
+        let name = name.trim();
+        if !PARAMETERS.iter().chain(SMARTLOGGER_PARAMETERS.iter()).chain(PV_STRING_PARAMETERS.iter()).any(|p| p.name == name && is_poll_param(p)) {
+            return Err(format!("unknown poll parameter: {:?}", name));
+        }
+        let secs = secs.trim().parse::<u32>().map_err(|e| format!("invalid poll interval {:?}: {}", item, e))?;
+        res.insert(name.to_string(), secs);
+    }
+    Ok(res)
+}
+
+/// Decides which parameters are due in a poll tick, according to their intervals
+pub struct PollSchedule {
+    device: DeviceType,
+    /// (parameter, interval in ticks)
+    params: Vec<(&'static Parameter, u32)>,
+}
+
+impl PollSchedule {
+    /// `tick_secs` is the time between poll ticks, `overrides` replace the default parameter intervals
+    pub fn new(device: DeviceType, tick_secs: u32, overrides: &HashMap<String, u32>) -> Self {
+        Self::with_params(device, &device.poll_plan().0, tick_secs, overrides)
+    }
+
+    /// Schedule of the given `params` instead of the default poll parameters of the device
+    pub fn with_params(device: DeviceType, params: &[&'static Parameter], tick_secs: u32, overrides: &HashMap<String, u32>) -> Self {
+        let params = params
+            .iter()
+            .map(|p| {
+                let secs = overrides.get(p.name).copied().unwrap_or(p.interval);
+                //rounded up to whole ticks
+                (*p, secs.div_ceil(tick_secs).max(1))
+            })
+            .collect();
+        Self { device, params }
+    }
+
+    /// Read plan of the poll tick number `tick`, the first tick (0) reads everything
+    pub fn plan(&self, tick: u32) -> ReadPlan {
+        let params: Vec<&'static Parameter> = self
+            .params
+            .iter()
+            .filter(|(_, every)| tick % every == 0)
+            .map(|(p, _)| *p)
+            .collect();
+        let spans = make_spans(self.device, &params);
+        (params, spans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_contiguous_and_shared_registers() {
+        let spans = plan_spans(&[(32064, 2), (32066, 1), (32066, 1), (32067, 2)], 0, SPAN_MAX_REGISTERS);
+        assert_eq!(spans, vec![(32064, 5)]);
+    }
+
+    #[test]
+    fn merges_small_gaps_inside_block() {
+        let spans = plan_spans(&[(37101, 2), (37107, 2), (37136, 2)], 8, SPAN_MAX_REGISTERS);
+        assert_eq!(spans, vec![(37101, 8), (37136, 2)]);
+    }
+
+    #[test]
+    fn no_gap_merging_outside_of_blocks() {
+        let spans = plan_spans(&[(35113, 2), (35116, 2), (35118, 1)], 8, SPAN_MAX_REGISTERS);
+        assert_eq!(spans, vec![(35113, 2), (35116, 3)]);
+    }
+
+    #[test]
+    fn never_crosses_block_boundary() {
+        //contiguous, but in different blocks
+        let spans = plan_spans(&[(32014, 2), (32016, 2)], 8, SPAN_MAX_REGISTERS);
+        assert_eq!(spans, vec![(32014, 2), (32016, 2)]);
+        let spans = plan_spans(&[(37098, 2), (37101, 2)], 8, SPAN_MAX_REGISTERS);
+        assert_eq!(spans, vec![(37098, 2), (37101, 2)]);
+    }
+
+    #[test]
+    fn splits_long_spans() {
+        let registers: Vec<(u16, u16)> = (0..10).map(|i| (32064 + 2 * i, 2)).collect();
+        let spans = plan_spans(&registers, 8, 6);
+        assert_eq!(spans, vec![(32064, 6), (32070, 6), (32076, 6), (32082, 2)]);
+    }
+
+    #[test]
+    fn empty_plan() {
+        assert!(plan_spans(&[], 8, SPAN_MAX_REGISTERS).is_empty());
+        assert!(PollSchedule { device: DeviceType::Inverter, params: vec![] }.plan(0).1.is_empty());
+    }
+
+    #[test]
+    fn pv_string_registers() {
+        assert_eq!(pv_string_parameters(0).len(), 0);
+        assert_eq!(pv_string_parameters(PV_STRINGS_MAX + 1).len(), PV_STRINGS_MAX as usize * 2);
+        let params = pv_string_parameters(2);
+        let names: Vec<&str> = params.iter().map(|p| p.name).collect();
+        assert_eq!(names, vec!["pv_01_voltage", "pv_01_current", "pv_02_voltage", "pv_02_current"]);
+        assert_eq!(params.iter().map(|p| p.reg_address).collect::<Vec<u16>>(), vec![32016, 32017, 32018, 32019]);
+        //the whole PV block is readable in one span
+        let all = pv_string_parameters(PV_STRINGS_MAX);
+        assert_eq!(make_spans(DeviceType::Inverter, &all), vec![(32016, 48)]);
+    }
+
+    #[test]
+    fn pv_string_power() {
+        let mut params: Vec<Parameter> = pv_string_parameters(2).into_iter().cloned().collect();
+        params[0].value = ParamKind::NumberI16(Some(3805)); //380.5 V
+        params[1].value = ParamKind::NumberI16(Some(812)); //8.12 A
+        params.truncate(3); //the current of the second string was not read
+        params[2].value = ParamKind::NumberI16(Some(3790));
+        add_pv_string_power(&mut params);
+        assert_eq!(get_param_value(&params, "pv_01_power"), Some(3090.0));
+        assert_eq!(get_param_value(&params, "pv_02_power"), None);
+    }
+
+    #[test]
+    fn poll_intervals_in_ticks() {
+        let overrides = parse_poll_intervals("internal_temperature:4294967295, active_power: 15").unwrap();
+        let schedule = PollSchedule::new(DeviceType::Inverter, 10, &overrides);
+        let every = |name: &str| schedule.params.iter().find(|(p, _)| p.name == name).map(|(_, every)| *every);
+        assert_eq!(every("internal_temperature"), Some(u32::MAX / 10 + 1));
+        assert_eq!(every("active_power"), Some(2));
+        let read = |tick: u32, name: &str| schedule.plan(tick).0.iter().any(|p| p.name == name);
+        assert!(read(0, "internal_temperature") && !read(1, "internal_temperature"));
+        assert!(!read(1, "active_power") && read(2, "active_power"));
+        assert!(parse_poll_intervals("internal_temperature:4294967296").is_err());
+    }
+
+    #[test]
+    fn poll_plan_covers_all_parameters() {
+        for device in [DeviceType::Inverter, DeviceType::SmartLogger] {
+            for (params, spans) in [device.initial_plan(), device.poll_plan()] {
+                for (start, len) in spans {
+                    assert!(*len <= SPAN_MAX_REGISTERS);
+                    let blocks = device.register_blocks();
+                    assert_eq!(register_block(blocks, *start), register_block(blocks, start + len - 1));
+                }
+                for p in params {
+                    assert!(
+                        spans.iter().any(|(start, len)| p.reg_address >= *start && p.reg_address + p.len <= start + len),
+                        "{} is not read",
+                        p.name
+                    );
+                }
+            }
+        }
+    }
 }
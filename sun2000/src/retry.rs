@@ -0,0 +1,197 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::options::{self, OPTION_MAX_SECS};
+use super::Result;
+
+pub const MODBUS_ATTEMPTS: u8 = 1; //default: max read attempts per register span
+pub const MODBUS_READ_TIMEOUT_SECS: f64 = 3.0; //default: timeout of a single read/write request
+pub const MODBUS_LAG_WARNING_SECS: f64 = 2.0; //default: reads slower than this are logged
+pub const MODBUS_CONNECT_TIMEOUT_SECS: f64 = 5.0; //default: timeout of the TCP connect
+pub const RECONNECT_DELAY_SECS: f64 = 2.0; //default: delay after the first failure, doubled on each next one
+pub const RECONNECT_MAX_DELAY_SECS: f64 = 120.0; //default: upper limit of the doubled delay
+pub const RECONNECT_JITTER: f64 = 0.2; //default: random part of the delay (fraction)
+pub const BREAKER_THRESHOLD: u32 = 10; //default: consecutive failures opening the circuit breaker
+pub const BREAKER_DELAY_SECS: f64 = 600.0; //default: delay between attempts while the breaker is open
+
+/// Timeouts and retry settings of the Modbus client
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModbusConfig {
+    pub attempts: u8,
+    pub read_timeout: Duration,
+    pub lag_warning: Duration,
+    pub connect_timeout: Duration,
+    pub reconnect_delay: Duration,
+    pub reconnect_max_delay: Duration,
+    pub jitter: f64,
+    /// consecutive failures after which the breaker opens, 0 disables it
+    pub breaker_threshold: u32,
+    pub breaker_delay: Duration,
+}
+
+impl Default for ModbusConfig {
+    fn default() -> Self {
+        Self {
+            attempts: MODBUS_ATTEMPTS,
+            read_timeout: Duration::from_secs_f64(MODBUS_READ_TIMEOUT_SECS),
+            lag_warning: Duration::from_secs_f64(MODBUS_LAG_WARNING_SECS),
+            connect_timeout: Duration::from_secs_f64(MODBUS_CONNECT_TIMEOUT_SECS),
+            reconnect_delay: Duration::from_secs_f64(RECONNECT_DELAY_SECS),
+            reconnect_max_delay: Duration::from_secs_f64(RECONNECT_MAX_DELAY_SECS),
+            jitter: RECONNECT_JITTER,
+            breaker_threshold: BREAKER_THRESHOLD,
+            breaker_delay: Duration::from_secs_f64(BREAKER_DELAY_SECS),
+        }
+    }
+}
+
+impl ModbusConfig {
+    /// Builds the config from options (`modbus_attempts`, `modbus_read_timeout`, `modbus_lag_warning`,
+    /// `modbus_connect_timeout`, `reconnect_delay`, `reconnect_max_delay`, `reconnect_jitter`,
+    /// `breaker_threshold`, `breaker_delay`), times in secs. Missing options keep the defaults.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<ModbusConfig> {
+        let secs = |name: &str, min: f64, default: Duration| -> Result<Duration> {
+            Ok(options::secs(&get, name, min, OPTION_MAX_SECS)?.unwrap_or(default))
+        };
+        let default = ModbusConfig::default();
+        let config = ModbusConfig {
+            attempts: options::integer(&get, "modbus_attempts", 1, u8::MAX as u32)?.map(|v| v as u8).unwrap_or(default.attempts),
+            read_timeout: secs("modbus_read_timeout", 0.1, default.read_timeout)?,
+            lag_warning: secs("modbus_lag_warning", 0.0, default.lag_warning)?,
+            connect_timeout: secs("modbus_connect_timeout", 0.1, default.connect_timeout)?,
+            reconnect_delay: secs("reconnect_delay", 0.0, default.reconnect_delay)?,
+            reconnect_max_delay: secs("reconnect_max_delay", 0.0, default.reconnect_max_delay)?,
+            jitter: options::number(&get, "reconnect_jitter", 0.0, 1.0)?.unwrap_or(default.jitter),
+            breaker_threshold: options::integer(&get, "breaker_threshold", 0, u32::MAX)?.unwrap_or(default.breaker_threshold),
+            breaker_delay: secs("breaker_delay", 0.0, default.breaker_delay)?,
+        };
+        if config.reconnect_max_delay < config.reconnect_delay {
+            return Err("reconnect_max_delay is lower than reconnect_delay".into());
+        }
+        Ok(config)
+    }
+}
+
+/// Random value in [0, 1), good enough to spread the reconnects
+fn random_fraction() -> f64 {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    //scramble the low bits, the clock resolution may be coarse
+    (nanos.wrapping_mul(2654435761) % 1_000_000) as f64 / 1_000_000.0
+}
+
+/// Exponential backoff of the reconnects with a circuit breaker: after `breaker_threshold`
+/// consecutive failures the attempts are only made every `breaker_delay`
+pub struct Backoff {
+    pub config: ModbusConfig,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(config: ModbusConfig) -> Self {
+        Self::with_failures(config, 0)
+    }
+
+    /// Backoff continuing after `failures` consecutive failures, eg. of a restarted worker
+    pub fn with_failures(config: ModbusConfig, failures: u32) -> Self {
+        Self { config, failures }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// true when the breaker is open: failures are expected and shouldn't be logged as errors
+    pub fn is_open(&self) -> bool {
+        self.config.breaker_threshold > 0 && self.failures >= self.config.breaker_threshold
+    }
+
+    /// Records a failure and returns the delay before the next attempt
+    pub fn failure(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        let delay = if self.is_open() {
+            self.config.breaker_delay
+        } else {
+            let factor = 2f64.powi(self.failures.min(32) as i32 - 1);
+            self.config
+                .reconnect_delay
+                .mul_f64(factor)
+                .min(self.config.reconnect_max_delay)
+        };
+        //jitter both ways, so multiple clients don't hit a dongle at the same time
+        delay.mul_f64(1.0 + self.config.jitter * (2.0 * random_fraction() - 1.0))
+    }
+
+    /// Records a success, returns true when it closes an open breaker
+    pub fn success(&mut self) -> bool {
+        let was_open = self.is_open();
+        self.failures = 0;
+        was_open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(jitter: f64) -> ModbusConfig {
+        ModbusConfig {
+            jitter,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delay_grows_up_to_the_max() {
+        let mut backoff = Backoff::new(config(0.0));
+        let delays: Vec<u64> = (0..8).map(|_| backoff.failure().as_secs()).collect();
+        assert_eq!(delays, vec![2, 4, 8, 16, 32, 64, 120, 120]);
+        assert_eq!(backoff.failures(), 8);
+        assert!(!backoff.is_open());
+    }
+
+    #[test]
+    fn jitter_bounds() {
+        for failures in 0..20 {
+            let mut backoff = Backoff::with_failures(config(RECONNECT_JITTER), failures);
+            let base = Backoff::with_failures(config(0.0), failures).failure().as_secs_f64();
+            let delay = backoff.failure().as_secs_f64();
+            assert!(delay >= base * (1.0 - RECONNECT_JITTER) - 1e-9 && delay <= base * (1.0 + RECONNECT_JITTER) + 1e-9, "{} {}", base, delay);
+        }
+    }
+
+    #[test]
+    fn breaker_opens_and_closes() {
+        let mut backoff = Backoff::new(config(0.0));
+        for _ in 1..BREAKER_THRESHOLD {
+            backoff.failure();
+        }
+        assert!(!backoff.is_open());
+        assert_eq!(backoff.failure(), Duration::from_secs_f64(BREAKER_DELAY_SECS));
+        assert!(backoff.is_open());
+        assert!(backoff.success());
+        assert!(!backoff.is_open());
+        assert_eq!(backoff.failure(), Duration::from_secs_f64(RECONNECT_DELAY_SECS));
+        assert!(!backoff.success());
+
+        //disabled breaker
+        let mut backoff = Backoff::new(ModbusConfig { breaker_threshold: 0, ..config(0.0) });
+        for _ in 0..100 {
+            backoff.failure();
+        }
+        assert!(!backoff.is_open());
+    }
+
+    #[test]
+    fn options_out_of_range() {
+        let options = |values: &'static [(&'static str, &'static str)]| {
+            ModbusConfig::from_options(move |name| values.iter().find(|(n, _)| *n == name).map(|(_, v)| v.to_string()))
+        };
+        let config = options(&[("modbus_attempts", "3"), ("modbus_read_timeout", "1.5")]).unwrap();
+        assert_eq!(config.attempts, 3);
+        assert_eq!(config.read_timeout, Duration::from_millis(1500));
+        assert!(options(&[("modbus_attempts", "1000")]).is_err());
+        assert!(options(&[("modbus_attempts", "0")]).is_err());
+        assert!(options(&[("reconnect_delay", "1e30")]).is_err());
+        assert!(options(&[("reconnect_jitter", "1.5")]).is_err());
+        assert!(options(&[("reconnect_delay", "200")]).is_err());
+    }
+}
@@ -0,0 +1,112 @@
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+
+use super::battery::*;
+use super::clock::*;
+use super::export_limit::*;
+
+pub const SETTINGS_BACKUP_VERSION: u32 = 1; //format version of the settings backup files
+
+/// Writable configuration register of the SUN2000 (and the LUNA2000 battery behind it)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Setting {
+    pub name: &'static str,
+    pub addr: u16,
+    pub len: u16,
+    pub signed: bool,
+    pub gain: u16,
+    pub unit: Option<&'static str>,
+}
+
+#[rustfmt::skip]
+pub const SETTINGS: &[Setting] = &[
+    //first, the settings are restored in this order and a grid code change resets the grid dependent ones
+    Setting{name: "grid_code", addr: 42000, len: 1, signed: false, gain: 1, unit: None},
+    Setting{name: "power_factor", addr: 40122, len: 1, signed: true, gain: 1000, unit: None},
+    Setting{name: "active_power_percentage_derating", addr: 40125, len: 1, signed: true, gain: 10, unit: Some("%")},
+    Setting{name: "active_power_fixed_derating", addr: EXPORT_LIMIT_REGISTER, len: 2, signed: false, gain: 1, unit: Some("W")},
+    Setting{name: "time_zone", addr: TIME_ZONE_REGISTER, len: 1, signed: true, gain: 1, unit: Some("min")},
+    Setting{name: "storage_working_mode", addr: BATTERY_WORKING_MODE_REGISTER, len: 1, signed: false, gain: 1, unit: None},
+    Setting{name: "storage_time_of_use_price", addr: 47027, len: 1, signed: true, gain: 1, unit: None},
+    Setting{name: "storage_lcoe", addr: 47069, len: 2, signed: false, gain: 1000, unit: None},
+    Setting{name: "storage_maximum_charging_power", addr: 47075, len: 2, signed: false, gain: 1, unit: Some("W")},
+    Setting{name: "storage_maximum_discharging_power", addr: 47077, len: 2, signed: false, gain: 1, unit: Some("W")},
+    Setting{name: "storage_power_limit_grid_tied_point", addr: 47079, len: 2, signed: true, gain: 1, unit: Some("W")},
+    Setting{name: "storage_charging_cutoff_capacity", addr: 47081, len: 1, signed: false, gain: 10, unit: Some("%")},
+    Setting{name: "storage_discharging_cutoff_capacity", addr: 47082, len: 1, signed: false, gain: 10, unit: Some("%")},
+    Setting{name: "storage_forced_charging_and_discharging_period", addr: BATTERY_FORCED_PERIOD_REGISTER, len: 1, signed: false, gain: 1, unit: Some("min")},
+    Setting{name: "storage_charge_from_grid", addr: 47087, len: 1, signed: false, gain: 1, unit: None},
+    Setting{name: "storage_grid_charge_cutoff_capacity", addr: 47088, len: 1, signed: false, gain: 10, unit: Some("%")},
+    //charge/discharge windows: count, then 14 windows (start min, end min, charge flag + weekdays)
+    Setting{name: "storage_time_of_use_periods", addr: 47255, len: 43, signed: false, gain: 1, unit: None},
+];
+
+pub fn find_setting(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|s| s.name == name)
+}
+
+impl Setting {
+    /// Scaled value of the registers, `None` for the multi register (raw) settings
+    pub fn value(&self, registers: &[u16]) -> Option<f64> {
+        let raw = match (registers, self.signed) {
+            ([v], false) => *v as f64,
+            ([v], true) => *v as i16 as f64,
+            ([hi, lo], false) => (((*hi as u32) << 16) | *lo as u32) as f64,
+            ([hi, lo], true) => (((*hi as u32) << 16) | *lo as u32) as i32 as f64,
+            _ => return None,
+        };
+        Some(raw / self.gain as f64)
+    }
+
+    /// Human readable value of the registers
+    pub fn display(&self, registers: &[u16]) -> String {
+        match self.value(registers) {
+            Some(v) => format!("{}{}", v, self.unit.map(|u| format!(" {}", u)).unwrap_or_default()),
+            None => format!("{:?}", registers),
+        }
+    }
+}
+
+/// Reads the registers of a setting
+pub async fn read_setting(ctx: &mut Context, setting: &Setting, read_timeout: Duration) -> io::Result<Vec<u16>> {
+    match timeout(read_timeout, ctx.read_holding_registers(setting.addr, setting.len)).await {
+        Ok(Ok(data)) if data.len() == setting.len as usize => Ok(data),
+        Ok(Ok(data)) => Err(io::Error::new(ErrorKind::InvalidData, format!("expected {} registers, got {}", setting.len, data.len()))),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+/// Writes the registers of a setting
+pub async fn write_setting(ctx: &mut Context, setting: &Setting, data: &[u16], write_timeout: Duration) -> io::Result<()> {
+    if data.len() != setting.len as usize {
+        return Err(io::Error::new(ErrorKind::InvalidInput, format!("{}: expected {} registers, got {}", setting.name, setting.len, data.len())));
+    }
+    match timeout(write_timeout, ctx.write_multiple_registers(setting.addr, data)).await {
+        Ok(res) => res,
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values() {
+        let power_factor = find_setting("power_factor").unwrap();
+        assert_eq!(power_factor.value(&[0xfc7c]), Some(-0.9));
+        let grid_point = find_setting("storage_power_limit_grid_tied_point").unwrap();
+        assert_eq!(grid_point.value(&[0xffff, 0xfc18]), Some(-1000.0));
+        assert_eq!(grid_point.value(&[0x0001, 0x0000]), Some(65536.0));
+        assert_eq!(grid_point.display(&[0xffff, 0xfc18]), "-1000 W");
+        let fixed_derating = find_setting("active_power_fixed_derating").unwrap();
+        assert_eq!(fixed_derating.value(&[0xffff, 0xfc18]), Some(4294966296.0));
+        //raw ones
+        let periods = find_setting("storage_time_of_use_periods").unwrap();
+        assert_eq!(periods.value(&[0; 43]), None);
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use super::battery::*;
+use super::clock::*;
+use super::defs::*;
+use super::ephemeris::*;
+use super::energy::*;
+use super::identity::*;
+use super::params::*;
+use super::upload::*;
+
+/// Inverter poll counters, kept outside of the worker so they survive worker restarts
+#[derive(Clone, Debug, Default)]
+pub struct PollStats {
+    pub poll_ok: u64,
+    pub poll_errors: u64,
+    /// polls with some of the register spans unreadable
+    pub poll_partial: u64,
+    pub last_poll_ok: Option<Instant>,
+    pub last_query_ms: u64,
+}
+
+impl PollStats {
+    /// true when the last successful poll is not older than `max_age`
+    pub fn is_polling(&self, max_age: Duration) -> bool {
+        match self.last_poll_ok {
+            Some(t) => t.elapsed() <= max_age,
+            None => false,
+        }
+    }
+}
+
+/// Progress of the worker loop, tells whether the worker is alive independently of the inverter
+/// being reachable (at night or while reconnecting the attempts are rare)
+#[derive(Clone, Debug, Default)]
+pub struct WorkerActivity {
+    /// the next attempt (poll or connect) is expected to be done before this
+    pub next_step: Option<Instant>,
+    /// consecutive failed attempts, the reconnect backoff continues from them after a worker restart
+    pub failures: u32,
+    /// the circuit breaker is open, the attempts are only made every `breaker_delay`
+    pub breaker_open: bool,
+}
+
+impl WorkerActivity {
+    /// false when the worker loop is stuck (or it hasn't started yet)
+    pub fn is_alive(&self) -> bool {
+        match self.next_step {
+            Some(t) => Instant::now() <= t,
+            None => false,
+        }
+    }
+}
+
+/// Read results of a single register span
+#[derive(Clone, Debug, Default)]
+pub struct SpanHealth {
+    pub start: u16,
+    pub len: u16,
+    pub ok: u64,
+    pub errors: u64,
+    pub consecutive_errors: u32,
+    pub last_ok: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_error: Option<String>,
+}
+
+impl SpanHealth {
+    pub fn record(&mut self, time: chrono::DateTime<chrono::Utc>, error: Option<String>) {
+        match error {
+            None => {
+                self.ok += 1;
+                self.consecutive_errors = 0;
+                self.last_ok = Some(time);
+            }
+            Some(e) => {
+                self.errors += 1;
+                self.consecutive_errors += 1;
+                self.last_error = Some(e);
+            }
+        }
+    }
+}
+
+/// Parameter which failed to be read when it was due, its value in the snapshot is the last known one
+#[derive(Clone, Debug)]
+pub struct StaleParam {
+    pub name: String,
+    /// time of the last successful read, `None` when it was never read
+    pub last_read: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl StaleParam {
+    /// Age of the last known value at `time`
+    pub fn age(&self, time: chrono::DateTime<chrono::Utc>) -> Option<chrono::Duration> {
+        self.last_read.map(|t| time - t)
+    }
+}
+
+/// Latest values of all parameters after a successful poll (slow parameters are not read in every poll)
+#[derive(Clone, Debug)]
+pub struct PollSnapshot {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub params: Vec<Parameter>,
+    pub stale: Vec<StaleParam>,
+}
+
+impl PollSnapshot {
+    pub fn is_stale(&self, name: &str) -> bool {
+        self.stale.iter().any(|s| s.name == name)
+    }
+
+    /// Parameters without the stale ones, for the consumers acting on the values (and not only showing them)
+    pub fn fresh_params(&self) -> Vec<Parameter> {
+        let mut params: Vec<Parameter> = self.params.iter().filter(|p| p.len > 0 && !self.is_stale(p.name)).cloned().collect();
+        //the derived values only from the fresh ones
+        add_pv_string_power(&mut params);
+        params
+    }
+
+    /// Scaled value of the named parameter, `None` when it is missing or stale
+    pub fn fresh_value(&self, name: &str) -> Option<f64> {
+        if self.is_stale(name) {
+            return None;
+        }
+        get_param_value(&self.params, name)
+    }
+}
+
+
+/// Live events published by the sun2000 worker on a broadcast channel
+#[derive(Clone, Debug)]
+pub enum Sun2000Event {
+    /// every successful poll
+    Snapshot(Arc<PollSnapshot>),
+    /// state/alarm changes reported by `Sun2000State::set_new_status`
+    StateChange {
+        time: chrono::DateTime<chrono::Utc>,
+        changes: Vec<(String, String)>,
+    },
+    /// decisions of the battery time-of-use scheduler
+    Battery {
+        time: chrono::DateTime<chrono::Utc>,
+        action: BatteryAction,
+        minutes: u32,
+        soc: Option<f64>,
+        reason: String,
+    },
+}
+
+pub const SUN2000_EVENTS_CAPACITY: usize = 64; //events kept for slow subscribers
+
+pub fn new_events() -> broadcast::Sender<Sun2000Event> {
+    broadcast::channel(SUN2000_EVENTS_CAPACITY).0
+}
+
+/// Modbus request of an external client, executed by the sun2000 worker on its connection
+#[derive(Clone, Debug, PartialEq)]
+pub enum ProxyRequest {
+    Read { addr: u16, len: u16 },
+    Write { addr: u16, data: Vec<u16> },
+}
+
+/// Proxy request with the channel for the result (the read registers, empty for writes)
+pub struct ProxyCall {
+    pub request: ProxyRequest,
+    pub reply: oneshot::Sender<io::Result<Vec<u16>>>,
+}
+
+pub const PROXY_QUEUE_CAPACITY: usize = 16; //proxy requests waiting for the worker
+
+pub type ProxySender = mpsc::Sender<ProxyCall>;
+/// The receiver is taken by each (re)started worker
+pub type ProxyReceiver = Arc<tokio::sync::Mutex<mpsc::Receiver<ProxyCall>>>;
+
+pub fn new_proxy_channel() -> (ProxySender, ProxyReceiver) {
+    let (tx, rx) = mpsc::channel(PROXY_QUEUE_CAPACITY);
+    (tx, Arc::new(tokio::sync::Mutex::new(rx)))
+}
+
+/// State published by the sun2000 worker for other tasks (main supervisor, systemd, http, ...)
+#[derive(Default)]
+pub struct SharedState {
+    pub stats: PollStats,
+    pub worker: WorkerActivity,
+    /// health of the register spans, by the start address
+    pub spans: Vec<SpanHealth>,
+    pub snapshot: Option<PollSnapshot>,
+    pub state: Sun2000State,
+    pub device_info: Vec<Parameter>,
+    /// devices described in the device identification
+    pub devices: Vec<DeviceIdentity>,
+    /// Modbus slave address the devices were read from
+    pub slave: u8,
+    pub house_power: Option<f64>,
+    pub energy_today: EnergyTotals,
+    pub energy_month: EnergyTotals,
+    /// night mode is active, the inverter is not expected to be polled
+    pub night: bool,
+    pub sun_times: Option<SunTimes>,
+    /// latest optimizer real-time data (file 0x44)
+    pub optimizers: Option<OptimizerSample>,
+    pub optimizer_info: Vec<OptimizerInfo>,
+    /// raw register values with the time they were read
+    pub registers: HashMap<u16, (u16, Instant)>,
+    /// result of the last inverter clock check
+    pub clock: Option<ClockStatus>,
+}
+
+impl SharedState {
+    /// Registers `addr..addr + len` when all of them were read within `max_age`
+    pub fn cached_registers(&self, addr: u16, len: u16, max_age: Duration) -> Option<Vec<u16>> {
+        (addr..addr.checked_add(len)?)
+            .map(|a| match self.registers.get(&a) {
+                Some((value, time)) if time.elapsed() <= max_age => Some(*value),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn cache_registers(&mut self, addr: u16, data: &[u16]) {
+        let now = Instant::now();
+        for (a, v) in (addr..).zip(data) {
+            self.registers.insert(a, (*v, now));
+        }
+    }
+
+    /// Drops the cached registers `addr..addr + len`, eg. after a write
+    pub fn invalidate_registers(&mut self, addr: u16, len: u16) {
+        for a in addr..addr.saturating_add(len) {
+            self.registers.remove(&a);
+        }
+    }
+}
+
+pub type Shared = Arc<RwLock<SharedState>>;
+
+pub fn new_shared() -> Shared {
+    Arc::new(RwLock::new(SharedState::default()))
+}
@@ -0,0 +1,502 @@
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use super::energy::day_key;
+use super::params::*;
+use super::options::{self, OPTION_MAX_SECS};
+use super::Result;
+
+pub const STRING_HISTORY_PATH: &str = "sun2000_strings.bin";
+pub const STRING_HISTORY_PERSIST_INTERVAL_SECS: i64 = 300; //secs between saving the history to disk
+pub const STRING_SLOT_MINUTES: u32 = 15; //time of day resolution of the history
+pub const STRING_MIN_HISTORY_DAYS: u32 = 3; //days of history needed before a string is flagged
+pub const STRING_MAX_DEFICIT_PERCENT: f64 = 20.0; //default: string power deficit flagged as underperformance
+pub const STRING_MIN_POWER: f64 = 200.0; //default: median string power (W) below which the strings are not compared
+pub const STRING_PERSISTENCE_SECS: f64 = 1800.0; //default: underperformance raising an event
+pub const STRING_HISTORY_DAYS: u32 = 14; //default: days kept in the history
+pub const INSULATION_MAX_DROP_PERCENT: f64 = 30.0; //default: insulation resistance drop raising an event
+
+const SLOTS_PER_DAY: usize = (24 * 60 / STRING_SLOT_MINUTES) as usize;
+
+/// Settings of the PV string analysis
+#[derive(Clone, Debug, PartialEq)]
+pub struct StringAnalysisConfig {
+    pub enabled: bool,
+    /// relative deficit of a string against its expected power flagged as underperformance
+    pub max_deficit: f64,
+    /// median string power (W) below which the strings are not compared
+    pub min_power: f64,
+    /// time a string has to underperform before the event is raised
+    pub persistence: Duration,
+    /// days kept in the same time of day history
+    pub history_days: u32,
+    /// relative drop of `insulation_resistance` against its daily average raising an event
+    pub insulation_drop: f64,
+}
+
+impl Default for StringAnalysisConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_deficit: STRING_MAX_DEFICIT_PERCENT / 100.0,
+            min_power: STRING_MIN_POWER,
+            persistence: Duration::from_secs_f64(STRING_PERSISTENCE_SECS),
+            history_days: STRING_HISTORY_DAYS,
+            insulation_drop: INSULATION_MAX_DROP_PERCENT / 100.0,
+        }
+    }
+}
+
+impl StringAnalysisConfig {
+    /// Builds the config from options (`string_analysis`, `string_max_deficit` (%), `string_min_power` (W),
+    /// `string_persistence` (secs), `string_history_days`, `insulation_max_drop` (%)). Missing options keep the defaults.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<StringAnalysisConfig> {
+        let percent = |name: &str, default: f64| -> Result<f64> {
+            Ok(options::number(&get, name, 1.0, 100.0)?.map(|v| v / 100.0).unwrap_or(default))
+        };
+        let default = StringAnalysisConfig::default();
+        Ok(StringAnalysisConfig {
+            enabled: options::flag(&get, "string_analysis")?.unwrap_or(default.enabled),
+            max_deficit: percent("string_max_deficit", default.max_deficit)?,
+            min_power: options::number(&get, "string_min_power", 1.0, f64::MAX)?.unwrap_or(default.min_power),
+            persistence: options::secs(&get, "string_persistence", 1.0, OPTION_MAX_SECS)?.unwrap_or(default.persistence),
+            history_days: options::integer(&get, "string_history_days", 1, 365)?.unwrap_or(default.history_days),
+            insulation_drop: percent("insulation_max_drop", default.insulation_drop)?,
+        })
+    }
+}
+
+/// Same time of day history of a string
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SlotHistory {
+    /// daily average power of the string relative to the median of all strings, the oldest first
+    pub shares: Vec<f64>,
+    /// average of the days it was flagged (1) or not (0)
+    pub flagged: f64,
+}
+
+impl SlotHistory {
+    fn add(&mut self, share: f64, flagged: bool, history_days: u32) {
+        self.shares.push(share);
+        if self.shares.len() > history_days.max(1) as usize {
+            self.shares.remove(0);
+        }
+        let weight = 1.0 / self.days() as f64;
+        self.flagged += (if flagged { 1.0 } else { 0.0 } - self.flagged) * weight;
+    }
+
+    fn days(&self) -> u32 {
+        self.shares.len() as u32
+    }
+
+    /// Share learned from all the days, so a string lower by design (eg. with less panels or
+    /// another orientation) becomes its own baseline. `None` until there are enough days.
+    /// The median follows a change (eg. a new shading) only when it lasts for half of the history.
+    fn expected(&self) -> Option<f64> {
+        if self.days() < STRING_MIN_HISTORY_DAYS {
+            return None;
+        }
+        Some(median(&self.shares)).filter(|share| *share > 0.0)
+    }
+}
+
+/// History of the strings and of the insulation resistance, persisted between restarts
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct StringHistory {
+    /// by string, by time of day slot
+    pub slots: Vec<Vec<SlotHistory>>,
+    /// insulation resistance (MΩ) by local date
+    pub insulation: BTreeMap<String, f64>,
+    #[serde(skip)]
+    last_persist_millis: i64,
+}
+
+impl StringHistory {
+    pub fn load(path: &str) -> Self {
+        match std::fs::read(path) {
+            Ok(data) => match postcard::from_bytes(&data) {
+                Ok(history) => history,
+                Err(e) => {
+                    error!("strings: cannot decode {}: {}, starting from scratch", path, e);
+                    StringHistory::default()
+                }
+            },
+            Err(_) => StringHistory::default(),
+        }
+    }
+
+    pub fn save(&mut self, path: &str) {
+        match postcard::to_allocvec(&self) {
+            Ok(data) => {
+                if let Err(e) = std::fs::write(path, data) {
+                    error!("strings: cannot write {}: {}", path, e);
+                }
+            }
+            Err(e) => error!("strings: cannot encode history: {}", e),
+        }
+    }
+
+    /// Saves the history if it wasn't saved for `STRING_HISTORY_PERSIST_INTERVAL_SECS`
+    pub fn save_periodically(&mut self, path: &str, time: &DateTime<Utc>) {
+        if time.timestamp_millis() - self.last_persist_millis > STRING_HISTORY_PERSIST_INTERVAL_SECS * 1000 {
+            self.last_persist_millis = time.timestamp_millis();
+            self.save(path);
+        }
+    }
+
+    fn slot_mut(&mut self, string: usize, slot: usize) -> &mut SlotHistory {
+        if self.slots.len() <= string {
+            self.slots.resize(string + 1, vec![]);
+        }
+        if self.slots[string].len() < SLOTS_PER_DAY {
+            self.slots[string].resize(SLOTS_PER_DAY, SlotHistory::default());
+        }
+        &mut self.slots[string][slot]
+    }
+
+    fn slot(&self, string: usize, slot: usize) -> Option<&SlotHistory> {
+        self.slots.get(string).and_then(|s| s.get(slot))
+    }
+}
+
+/// Samples of the current time of day slot
+#[derive(Debug, Default)]
+struct SlotSamples {
+    day: String,
+    slot: usize,
+    /// by string: (sum of the shares, samples, flagged samples)
+    strings: Vec<(f64, u32, u32)>,
+}
+
+/// Underperformance tracking of a string
+#[derive(Debug, Default, Clone)]
+struct StringStatus {
+    /// start of the current underperformance
+    since: Option<DateTime<Utc>>,
+    /// the underperformance event was raised
+    raised: bool,
+    /// local date of the last shading event
+    shading_day: Option<String>,
+}
+
+/// Compares the PV strings with each other and with their same time of day history, flags the
+/// persistent underperformance, recurring (shading) patterns and a dropping insulation resistance.
+/// The events are reported as state changes, the same way as the inverter alarms.
+pub struct StringAnalyzer {
+    pub config: StringAnalysisConfig,
+    pub history: StringHistory,
+    samples: Option<SlotSamples>,
+    strings: Vec<StringStatus>,
+}
+
+/// Label of the string `index` (from 0), as shown by the inverter
+fn string_label(index: usize) -> String {
+    format!("PV{}", index + 1)
+}
+
+fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    //the middle value, or the average of the middle two
+    let len = sorted.len();
+    (sorted[(len - 1) / 2] + sorted[len / 2]) / 2.0
+}
+
+fn slot_of(time: &DateTime<Utc>) -> usize {
+    let local = time.with_timezone(&Local);
+    ((local.hour() * 60 + local.minute()) / STRING_SLOT_MINUTES) as usize
+}
+
+fn slot_label(slot: usize) -> String {
+    let minutes = slot as u32 * STRING_SLOT_MINUTES;
+    format!("{:02}:{:02}", minutes / 60, minutes % 60)
+}
+
+impl StringAnalyzer {
+    pub fn new(config: StringAnalysisConfig, history: StringHistory) -> Self {
+        Self {
+            config,
+            history,
+            samples: None,
+            strings: vec![],
+        }
+    }
+
+    /// Analyzes the latest parameters, returns the events as (state key, description)
+    pub fn update(&mut self, time: DateTime<Utc>, params: &[Parameter]) -> Vec<(&'static str, String)> {
+        let mut events = vec![];
+        if let Some(value) = get_param_value(params, "insulation_resistance") {
+            events.extend(self.update_insulation(&time, value));
+        }
+
+        let powers: Vec<f64> = PV_STRING_POWER_PARAMETERS
+            .iter()
+            .map_while(|p| get_param_value(params, p.name))
+            .collect();
+        let day = day_key(&time);
+        let slot = slot_of(&time);
+        if matches!(&self.samples, Some(s) if s.day != day || s.slot != slot) {
+            events.extend(self.close_slot());
+        }
+        if powers.len() < 2 {
+            return events;
+        }
+        if self.strings.len() < powers.len() {
+            self.strings.resize(powers.len(), StringStatus::default());
+        }
+        let median = median(&powers);
+        if median < self.config.min_power {
+            //low light, the strings are not comparable
+            for status in &mut self.strings {
+                status.since = None;
+            }
+            return events;
+        }
+
+        let samples = self.samples.get_or_insert_with(|| SlotSamples { day, slot, strings: vec![] });
+        samples.strings.resize(powers.len(), (0.0, 0, 0));
+        for (i, power) in powers.iter().enumerate() {
+            let share = power / median;
+            //the strings are learned first, there is nothing to flag yet
+            let learned = match self.history.slot(i, slot).and_then(SlotHistory::expected) {
+                Some(expected) => 1.0 - share / expected,
+                None => 0.0,
+            };
+            //against the other strings too: a string failing from the start would become its own baseline
+            let others: Vec<f64> = powers.iter().enumerate().filter(|(j, _)| *j != i).map(|(_, p)| *p).collect();
+            let others = self::median(&others);
+            let deficit = if others > 0.0 { learned.max(1.0 - power / others) } else { learned };
+            let flagged = deficit > self.config.max_deficit;
+
+            let sample = &mut samples.strings[i];
+            sample.0 += share;
+            sample.1 += 1;
+            if flagged {
+                sample.2 += 1;
+            }
+
+            let status = &mut self.strings[i];
+            if flagged {
+                let since = *status.since.get_or_insert(time);
+                if !status.raised && (time - since).to_std().unwrap_or_default() >= self.config.persistence {
+                    status.raised = true;
+                    events.push((
+                        "string_underperformance",
+                        format!("{} underperforming: {:.0} W, {:.0}% below the expected power", string_label(i), power, deficit * 100.0),
+                    ));
+                }
+            } else if deficit < self.config.max_deficit / 2.0 {
+                status.since = None;
+                if status.raised {
+                    status.raised = false;
+                    events.push(("string_underperformance", format!("{} back to the expected power", string_label(i))));
+                }
+            }
+        }
+        events
+    }
+
+    /// Adds the samples of the finished slot to the history, checking the recurring underperformance
+    fn close_slot(&mut self) -> Vec<(&'static str, String)> {
+        let mut events = vec![];
+        let samples = match self.samples.take() {
+            Some(s) => s,
+            None => return events,
+        };
+        for (i, (shares, count, flagged)) in samples.strings.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let slot_flagged = *flagged * 2 > *count;
+            let history = self.history.slot_mut(i, samples.slot);
+            history.add(shares / *count as f64, slot_flagged, self.config.history_days);
+            let recurring = slot_flagged && history.days() >= STRING_MIN_HISTORY_DAYS && history.flagged >= 0.5;
+
+            //a persistent underperformance is reported on its own
+            let status = match self.strings.get_mut(i) {
+                Some(status) => status,
+                None => continue,
+            };
+            if recurring && !status.raised && status.shading_day.as_ref() != Some(&samples.day) {
+                status.shading_day = Some(samples.day.clone());
+                events.push((
+                    "string_shading",
+                    format!(
+                        "{} underperforming around {} on {:.0}% of the days, shading?",
+                        string_label(i),
+                        slot_label(samples.slot),
+                        history.flagged * 100.0
+                    ),
+                ));
+            }
+        }
+        events
+    }
+
+    /// Keeps the first insulation resistance of each day, checks it against the average of the previous days
+    fn update_insulation(&mut self, time: &DateTime<Utc>, value: f64) -> Option<(&'static str, String)> {
+        let day = day_key(time);
+        if self.history.insulation.contains_key(&day) {
+            return None;
+        }
+        let previous: Vec<f64> = self.history.insulation.values().copied().collect();
+        self.history.insulation.insert(day, value);
+        while self.history.insulation.len() > self.config.history_days.max(1) as usize + 1 {
+            let oldest = self.history.insulation.keys().next().cloned().unwrap();
+            self.history.insulation.remove(&oldest);
+        }
+
+        if previous.len() < STRING_MIN_HISTORY_DAYS as usize {
+            return None;
+        }
+        let average = previous.iter().sum::<f64>() / previous.len() as f64;
+        let drop = 1.0 - value / average;
+        if average > 0.0 && drop >= self.config.insulation_drop {
+            Some((
+                "insulation_resistance",
+                format!(
+                    "insulation resistance dropped to {:.2} MΩ, {:.0}% below the average of {} days",
+                    value,
+                    drop * 100.0,
+                    previous.len()
+                ),
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> StringAnalysisConfig {
+        StringAnalysisConfig {
+            enabled: true,
+            persistence: Duration::from_secs(600),
+            ..Default::default()
+        }
+    }
+
+    fn time(day: u32, h: u32, m: u32) -> DateTime<Utc> {
+        Local.ymd(2022, 6, day).and_hms(h, m, 0).with_timezone(&Utc)
+    }
+
+    fn string_powers(powers: &[i32]) -> Vec<Parameter> {
+        PV_STRING_POWER_PARAMETERS
+            .iter()
+            .zip(powers)
+            .map(|(p, w)| Parameter { value: ParamKind::NumberI32(Some(*w)), ..p.clone() })
+            .collect()
+    }
+
+    /// Polls every minute from `h:m` for `minutes`, returns the events
+    fn run(a: &mut StringAnalyzer, day: u32, h: u32, m: u32, minutes: u32, powers: &[i32]) -> Vec<(&'static str, String)> {
+        let params = string_powers(powers);
+        (m..m + minutes).flat_map(|m| a.update(time(day, h + m / 60, m % 60), &params)).collect()
+    }
+
+    /// Days of history of the 12:00 slot
+    fn learn(a: &mut StringAnalyzer, days: std::ops::Range<u32>, powers: &[i32]) {
+        for day in days {
+            assert!(run(a, day, 12, 0, 15, powers).is_empty());
+            //closes the slot
+            a.update(time(day, 13, 0), &[]);
+        }
+    }
+
+    #[test]
+    fn string_low_by_design_is_learned() {
+        let mut a = StringAnalyzer::new(config(), StringHistory::default());
+        //PV3 has a panel less, it is not flagged while learning
+        learn(&mut a, 1..4, &[3000, 3000, 2700]);
+        let expected = a.history.slot(2, slot_of(&time(1, 12, 0))).and_then(SlotHistory::expected).unwrap();
+        assert!((expected - 0.9).abs() < 1e-9);
+        //nor after
+        assert!(run(&mut a, 4, 12, 0, 15, &[3000, 3000, 2700]).is_empty());
+    }
+
+    #[test]
+    fn string_failing_from_the_start() {
+        let mut a = StringAnalyzer::new(config(), StringHistory::default());
+        //no history yet, compared with the other strings
+        assert!(run(&mut a, 1, 12, 0, 10, &[3000, 3000, 100]).is_empty());
+        let events = run(&mut a, 1, 12, 10, 5, &[3000, 3000, 100]);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].1.starts_with("PV3 underperforming: 100 W, 97%"), "{}", events[0].1);
+        //learned as its own baseline, still underperforming
+        for day in 2..5 {
+            assert!(run(&mut a, day, 12, 0, 15, &[3000, 3000, 100]).is_empty());
+        }
+        let events = run(&mut a, 5, 12, 0, 1, &[3000, 3000, 3000]);
+        assert_eq!(events, vec![("string_underperformance", "PV3 back to the expected power".to_string())]);
+        //two strings
+        let mut a = StringAnalyzer::new(config(), StringHistory::default());
+        let events = run(&mut a, 1, 12, 0, 15, &[3000, 0]);
+        assert_eq!(events.len(), 1);
+        assert!(events[0].1.starts_with("PV2 underperforming: 0 W, 100%"), "{}", events[0].1);
+    }
+
+    #[test]
+    fn underperformance_after_persistence() {
+        let mut a = StringAnalyzer::new(config(), StringHistory::default());
+        learn(&mut a, 1..4, &[3000, 3000, 3000]);
+        let events = run(&mut a, 4, 12, 0, 10, &[3000, 3000, 1500]);
+        assert!(events.is_empty());
+        let events = run(&mut a, 4, 12, 10, 2, &[3000, 3000, 1500]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "string_underperformance");
+        assert!(events[0].1.starts_with("PV3 underperforming: 1500 W, 50%"), "{}", events[0].1);
+        let events = run(&mut a, 4, 12, 12, 1, &[3000, 3000, 3000]);
+        assert_eq!(events, vec![("string_underperformance", "PV3 back to the expected power".to_string())]);
+        //low light: not compared
+        assert!(run(&mut a, 4, 12, 13, 30, &[150, 150, 10]).is_empty());
+    }
+
+    #[test]
+    fn recurring_shading_on_close_slot() {
+        let mut config = config();
+        config.persistence = Duration::from_secs(3600);
+        let mut a = StringAnalyzer::new(config, StringHistory::default());
+        learn(&mut a, 1..4, &[3000, 3000, 3000]);
+        let slot = slot_of(&time(1, 12, 0));
+        let mut events = vec![];
+        for day in 4..7 {
+            assert!(run(&mut a, day, 12, 0, 15, &[3000, 3000, 1500]).is_empty());
+            events.push(a.close_slot());
+            //the evening ends the underperformance of the day
+            assert!(run(&mut a, day, 20, 0, 1, &[100, 100, 50]).is_empty());
+        }
+        //flagged on 1 of 4, 2 of 5 and 3 of 6 days
+        assert!(events[0].is_empty() && events[1].is_empty());
+        assert_eq!(events[2].len(), 1);
+        assert_eq!(events[2][0].0, "string_shading");
+        assert!(events[2][0].1.starts_with(&format!("PV3 underperforming around {}", slot_label(slot))), "{}", events[2][0].1);
+        //the shading becomes the baseline once it lasts for half of the history
+        assert_eq!(a.history.slot(2, slot).and_then(SlotHistory::expected), Some(0.75));
+        assert_eq!(a.close_slot(), vec![]);
+    }
+
+    #[test]
+    fn insulation_drop() {
+        let mut a = StringAnalyzer::new(config(), StringHistory::default());
+        for day in 1..4 {
+            assert_eq!(a.update_insulation(&time(day, 8, 0), 10.0), None);
+        }
+        //the first value of the day counts
+        assert!(a.update_insulation(&time(4, 8, 0), 8.0).is_none());
+        assert!(a.update_insulation(&time(4, 9, 0), 1.0).is_none());
+        let event = a.update_insulation(&time(5, 8, 0), 6.0).unwrap();
+        assert_eq!(event.0, "insulation_resistance");
+        assert!(event.1.contains("6.00 MΩ, 37% below the average of 4 days"), "{}", event.1);
+        //only the history days are kept
+        for day in 6..30 {
+            a.update_insulation(&time(day, 8, 0), 10.0);
+        }
+        assert_eq!(a.history.insulation.len(), STRING_HISTORY_DAYS as usize + 1);
+    }
+}
@@ -6,22 +6,39 @@ use simplelog::*;
 
 use std::io;
 use std::collections::HashMap;
-
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+
 use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 use tokio::time::timeout;
 use tokio_modbus::client::Context;
+use tokio_util::sync::CancellationToken;
 use tokio_modbus::prelude::*;
 
 use super::Result;
 use super::defs::*;
+use super::energy::*;
+use super::strings::*;
+use super::tariff::*;
+use super::export_limit::*;
+use super::battery::*;
+use super::ephemeris::*;
+use super::retry::*;
+use super::upload::*;
+use super::identity::*;
+use super::device::*;
+use super::clock::*;
+use super::dump::*;
+use super::huawei::*;
+use super::sunspec::*;
 use super::params::*;
+use super::shared::*;
 use futures::prelude::*;
 
 pub const SUN2000_POLL_INTERVAL_SECS: u32 = 10; //secs between polling
 pub const SUN2000_STATS_DUMP_INTERVAL_SECS: f32 = 30.0; //secs between showing stats
-pub const SUN2000_ATTEMPTS_PER_PARAM: u8 = 1; //max read attempts per single parameter
+pub const SUN2000_STEP_MARGIN_SECS: u64 = 60; //time for a poll or connect attempt, beyond its requests
+pub const SUN2000_STEP_MAX_REQUESTS: u32 = 32; //requests of a single attempt (spans of a full read, clock, identity)
 
 
 
@@ -37,6 +54,7 @@ impl Parameter {
         len: u16,
         initial_read: bool,
         save_to_influx: bool,
+        interval: u32,
     ) -> Self {
         Self {
             name,
@@ -48,6 +66,7 @@ impl Parameter {
             len,
             initial_read,
             save_to_influx,
+            interval,
         }
     }
 
@@ -138,14 +157,26 @@ impl Parameter {
             _ => {panic!("{:?}", self)}
         }
     }
+
+    /// Numeric value with the gain applied, `None` for texts and missing values
+    pub fn get_scaled_value(&self) -> Option<f64> {
+        let raw = match &self.value {
+            ParamKind::Text(_) => None,
+            ParamKind::NumberU16(v) => v.map(|v| v as f64),
+            ParamKind::NumberI16(v) => v.map(|v| v as f64),
+            ParamKind::NumberU32(v) => v.map(|v| v as f64),
+            ParamKind::NumberI32(v) => v.map(|v| v as f64),
+        };
+        raw.map(|v| v / self.gain as f64)
+    }
 }
 
 
 pub struct Sun2000 {
     pub name: String,
     pub host_port: String,
-    pub poll_ok: u64,
-    pub poll_errors: u64,
+    pub shared: Shared,
+    pub events: broadcast::Sender<Sun2000Event>,
     pub influxdb_url: Option<String>,
     pub influxdb_org: Option<String>,
     pub influxdb_token: Option<String>,
@@ -154,199 +185,322 @@ pub struct Sun2000 {
     pub optimizers: bool,
     pub battery_installed: bool,
     pub dongle_connection: bool,
+    /// Modbus slave ID, overrides the one given by `dongle_connection`
+    pub slave_id: Option<u8>,
+    /// backend and register map of the polled device
+    pub backend: Backend,
+    pub tariff: Option<Tariff>,
+    pub export_limit: Option<ExportLimitConfig>,
+    pub battery_schedule: Option<BatteryScheduleConfig>,
+    pub night_mode: Option<NightMode>,
+    /// poll interval overrides (secs) by parameter name
+    pub poll_intervals: HashMap<String, u32>,
+    pub modbus: ModbusConfig,
+    /// requests of the Modbus TCP proxy clients
+    pub proxy: Option<ProxyReceiver>,
+    /// extra files (eg. history) uploaded once a day and stored raw in the files dump
+    pub upload_files: Vec<u8>,
+    /// devices described in the device identification (0x2B/0x0E) of the last connection
+    pub identities: Vec<DeviceIdentity>,
+    pub clock: ClockConfig,
+    pub string_analysis: StringAnalysisConfig,
 }
 
 impl Sun2000 {
 
-    async fn read_params(
-        &mut self,
-        mut ctx: Context,
-        initial_read: bool
-    ) -> io::Result<(Context, Vec<Parameter>, u64)> {
+    fn slave(&self) -> Slave {
+        if let Some(id) = self.slave_id {
+            //explicit ID, eg. of an inverter in a cascade (see `hard probe --scan`)
+            Slave(id)
+        } else if self.dongle_connection {
+            //USB dongle connection: Slave ID has to be 0x01
+            Slave(0x01)
+        } else {
+            //internal wifi: Slave ID has to be 0x00, otherwise the inverter is not responding
+            Slave(0x00)
+        }
+    }
 
-        let start = chrono::Utc::now();
-        let now = Instant::now();
+    async fn write_registers(&self, ctx: &mut Context, addr: u16, data: &[u16]) -> io::Result<()> {
+        let retval = ctx.write_multiple_registers(addr, data);
+        match timeout(self.modbus.read_timeout, retval).await {
+            Ok(res) => res,
+            Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+        }
+    }
 
-        let mut params: Vec<Parameter> = vec![];
-        let mut disconnected = false;
+    async fn read_registers(&self, ctx: &mut Context, addr: u16, len: u16) -> io::Result<Vec<u16>> {
+        let retval = ctx.read_holding_registers(addr, len);
+        match timeout(self.modbus.read_timeout, retval).await {
+            Ok(res) => res,
+            Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+        }
+    }
 
-        let (params_to_read, addr_span) = if initial_read {
-            &*PARAMETERS_INITIAL 
-        } else {
-            &*PARAMETERS_POLL
+    async fn write_battery_decision(&self, ctx: &mut Context, decision: &BatteryDecision, config: &BatteryScheduleConfig) -> io::Result<()> {
+        let power = decision.forced_power();
+        self.write_registers(ctx, BATTERY_WORKING_MODE_REGISTER, &[decision.working_mode(config)]).await?;
+        self.write_registers(ctx, BATTERY_FORCED_PERIOD_REGISTER, &[decision.minutes as u16]).await?;
+        self.write_registers(ctx, BATTERY_FORCED_POWER_REGISTER, &[(power >> 16) as u16, (power & 0xffff) as u16]).await?;
+        self.write_registers(ctx, BATTERY_FORCIBLE_COMMAND_REGISTER, &[decision.forcible_command()]).await
+    }
+
+    /// Checks the night mode and publishes the sun times for other tasks, returns true at night
+    fn update_night(&self, was_night: &mut bool) -> bool {
+        let night_mode = match &self.night_mode {
+            Some(n) => n,
+            None => return false,
         };
+        let now = chrono::Utc::now();
+        let night = night_mode.is_night(&now);
+        if night != *was_night {
+            if night {
+                let poll = match night_mode.poll_interval {
+                    Some(interval) => format!("polling every <b>{} s</>", interval.as_secs()),
+                    None => "polling paused".to_string(),
+                };
+                info!("<i>{}</>: 🌙 night mode: {} until dawn", self.name, poll);
+            } else {
+                info!("<i>{}</>: ☀️ night is over, normal polling", self.name);
+            }
+            *was_night = night;
+        }
+        let mut shared = self.shared.write().unwrap();
+        shared.night = night;
+        shared.sun_times = Some(night_mode.location.sun_times(&now));
+        night
+    }
 
-        let mut value_map = HashMap::new();
+    /// Publishes when the next attempt is due, so the watchdog can tell a stuck worker
+    /// from a long wait (night, reconnect backoff)
+    fn publish_next_step(&self, wait: Duration) {
+        let requests = self.modbus.read_timeout * self.modbus.attempts as u32 * SUN2000_STEP_MAX_REQUESTS;
+        let budget = Duration::from_secs(SUN2000_STEP_MARGIN_SECS) + self.modbus.connect_timeout + requests;
+        self.shared.write().unwrap().worker.next_step = Some(Instant::now() + wait + budget);
+    }
 
-        for (addr_start, addr_len) in addr_span {
-            if disconnected {
-                break;
-            }
-            let mut attempts = 0;
-            while attempts < SUN2000_ATTEMPTS_PER_PARAM {
-                attempts = attempts + 1;
-                debug!("-> obtaining spam {} {}...", addr_start, addr_len);
-                let retval = ctx.read_holding_registers(*addr_start, *addr_len);
-                let read_res;
-                let start = Instant::now();
-                let read_time;
-                match timeout(Duration::from_secs_f32(3.0), retval).await {
-                    Ok(res) => {
-                        read_res = res;
-                        read_time = start.elapsed();
-                    }
-                    Err(e) => {
-                        let msg = format!(
-                            "<i>{}</i>: read timeout (attempt #{} of {}), register: <green><i>{}-{}</>, error: <b>{}</>",
-                            self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, addr_start, addr_len, e
-                        );
-                        if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                            error!("{}", msg);
-                            break;
-                        } else {
-                            warn!("{}", msg);
-                            continue;
-                        };
-                    }
-                }
-                match read_res {
-                    Ok(data) => {
-                        if read_time > Duration::from_secs_f32(2.0) {
-                            warn!(
-                                "<i>{}</i>: inverter has lagged during read, register: <green><i>{}-{}</>, read time: <b>{:?}</>",
-                                self.name, addr_start, addr_len, read_time
-                            );
-                        }
+    /// Publishes the reconnect backoff state for the watchdog and the worker restarts
+    fn publish_backoff(&self, backoff: &Backoff) {
+        let mut shared = self.shared.write().unwrap();
+        shared.worker.failures = backoff.failures();
+        shared.worker.breaker_open = backoff.is_open();
+    }
 
-                        let mut addr = *addr_start;
-                        for v in data {
-                            value_map.insert(addr, v);
-                            addr = addr + 1;
-                        }
+    /// Delay before reconnecting: growing with the consecutive failures during the day,
+    /// at night the inverter is expected to be unreachable
+    fn reconnect_delay(&self, night: bool, backoff: &mut Backoff) -> Duration {
+        let delay = self.next_reconnect_delay(night, backoff);
+        self.publish_backoff(backoff);
+        self.publish_next_step(delay);
+        delay
+    }
 
-                        break; //read next parameter span
-                    }
-                    Err(e) => {
-                        let msg = format!(
-                            "<i>{}</i>: read error (attempt #{} of {}), register: <green><i>{}-{}</>, error: <b>{}</>, read time: <b>{:?}</>",
-                            self.name, attempts, SUN2000_ATTEMPTS_PER_PARAM, addr_start, addr_len, e, read_time
-                        );                        match e.kind() {
-                            ErrorKind::BrokenPipe | ErrorKind::ConnectionReset => {
-                                error!("{}", msg);
-                                disconnected = true;
-                                break;
-                            }
-                            _ => {
-                                if attempts == SUN2000_ATTEMPTS_PER_PARAM {
-                                    error!("{}", msg);
-                                    break;
-                                } else {
-                                    warn!("{}", msg);
-                                    continue;
-                                };
-                            }
-                        }
-                    }
-                }
-            }
+    fn next_reconnect_delay(&self, night: bool, backoff: &mut Backoff) -> Duration {
+        if let (Some(night_mode), true) = (&self.night_mode, night) {
+            return night_mode.night_wait(&chrono::Utc::now());
+        }
+        let was_open = backoff.is_open();
+        let delay = backoff.failure();
+        if backoff.is_open() && !was_open {
+            warn!(
+                "<i>{}</>: {} consecutive failures, retrying every <b>{} s</> until the inverter responds",
+                self.name, backoff.failures(), backoff.config.breaker_delay.as_secs()
+            );
+        } else {
+            debug!("<i>{}</>: reconnecting in {:?} (failures: {})", self.name, delay, backoff.failures());
         }
+        delay
+    }
 
-        for (a, v) in &value_map {
-            debug!("MAP {} {}", a, v);
+    /// Reads the inverter clock and corrects it when it is off (and the correction is enabled)
+    async fn check_clock(&self, ctx: &mut Context, last_sync: Option<chrono::DateTime<chrono::Utc>>) -> io::Result<ClockStatus> {
+        let time = self.read_registers(ctx, SYSTEM_TIME_REGISTER, 2).await?;
+        let zone = self.read_registers(ctx, TIME_ZONE_REGISTER, 1).await?;
+        let clock = InverterClock::from_registers(&time, &zone)
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("invalid clock registers: {:?} {:?}", time, zone)))?;
+        let now = chrono::Utc::now();
+        let mut status = ClockStatus::new(&clock, now, host_ntp_synchronized().await);
+        status.last_sync = last_sync;
+        debug!("<i>{}</>: inverter clock: {}, drift: {:.0} s, time zone: {} min", self.name, clock.utc(), status.drift, clock.time_zone);
+        if !status.is_off(&self.clock) {
+            return Ok(status);
         }
 
-        for p in params_to_read {
-            let mut val2:ParamKind;
-            let mut values = Vec::new();
-            for addr in p.reg_address .. p.reg_address +  p.len {                
-                let v = value_map.get(&addr);
-                match v {
-                    Some(value) => {
-                        values.push(*value)
-                    }
-                    None => {
-                        continue
-                    }
-                }
+        let off = format!(
+            "drift: <b>{:.0} s</>, time zone: <b>{} min</> (host: {} min)",
+            status.drift, status.inverter_time_zone, status.host_time_zone
+        );
+        if let Some(reason) = status.sync_blocked(&self.clock) {
+            warn!("<i>{}</>: ⏰ inverter clock is off, {}, not corrected: {}", self.name, off, reason);
+            return Ok(status);
+        }
+        info!("<i>{}</>: ⏰ inverter clock is off, {}, setting the host time", self.name, off);
+        let correct = InverterClock::at(chrono::Utc::now(), status.host_time_zone);
+        if status.inverter_time_zone != status.host_time_zone {
+            self.write_registers(ctx, TIME_ZONE_REGISTER, &[correct.time_zone as u16]).await?;
+        }
+        self.write_registers(ctx, SYSTEM_TIME_REGISTER, &correct.time_registers()).await?;
+        self.shared.write().unwrap().invalidate_registers(SYSTEM_TIME_REGISTER, 2);
+        status.last_sync = Some(chrono::Utc::now());
+        Ok(status)
+    }
+
+    /// Tags the inverter points with its identity
+    fn identity_tags(&self, mut point: influxdb2::models::DataPointBuilder) -> influxdb2::models::DataPointBuilder {
+        if let Some(identity) = inverter_identity(&self.identities, self.slave().0) {
+            if let Some(model) = &identity.model {
+                point = point.tag("model", model.clone());
             }
-            if values.len() > 0 {
-                match &p.value {
-                    ParamKind::Text(_) => {
-                        let bytes: Vec<u8> = values.iter().fold(vec![], |mut x, elem| {
-                            if (elem >> 8) as u8 != 0 {
-                                x.push((elem >> 8) as u8);
-                            }
-                            if (elem & 0xff) as u8 != 0 {
-                                x.push((elem & 0xff) as u8);
-                            }
-                            x
-                        });
-                        let id = String::from_utf8(bytes).unwrap();
-                        val2 = ParamKind::Text(Some(id));
-                    }
-                    ParamKind::NumberU16(_) => {
-                        debug!("-> {} = {:?}", p.name, values);
-                        val2 = ParamKind::NumberU16(Some(values[0] as u16));
-                    }
-                    ParamKind::NumberI16(_) => {
-                        debug!("-> {} = {:?}", p.name, values);
-                        val2 = ParamKind::NumberI16(Some(values[0] as i16));
-                    }
-                    ParamKind::NumberU32(_) => {
-                        let new_val: u32 = ((values[0] as u32) << 16) | values[1] as u32;
-                        debug!("-> {} = {:X?} {:X}", p.name, values, new_val);
-                        val2 = ParamKind::NumberU32(Some(new_val));
-                        if p.unit.unwrap_or_default() == "epoch" && new_val == 0 {
-                            //zero epoch makes no sense, let's set it to None
-                            val2 = ParamKind::NumberU32(None);
-                        }
-                    }
-                    ParamKind::NumberI32(_) => {
-                        let new_val: i32 =
-                            ((values[0] as i32) << 16) | (values[1] as u32) as i32;
-                        debug!("-> {} = {:X?} {:X}", p.name, values, new_val);
-                        val2 = ParamKind::NumberI32(Some(new_val));
-                    }
-                }
-            
-
-                let param = Parameter::new_from_string(
-                    p.name.clone(),
-                    val2,
-                    p.desc.clone(),
-                    p.unit.clone(),
-                    p.gain,
-                    p.reg_address,
-                    p.len,
-                    p.initial_read,
-                    p.save_to_influx,
-                );
-                params.push(param.clone());
+            if let Some(esn) = &identity.esn {
+                point = point.tag("esn", esn.clone());
+            }
+            if let Some(version) = &identity.software_version {
+                point = point.tag("software_version", version.clone());
             }
         }
+        point
+    }
 
-        let elapsed = now.elapsed();
-        let ms = (elapsed.as_secs() * 1_000) + (elapsed.subsec_nanos() / 1_000_000) as u64;
-        info!(
-            "{}: read {} parameters [⏱️ {} ms]",
-            self.name,
-            params.len(),
-            ms
-        );
+    /// Uploads a file (function 0x41) and stores it in the files dump
+    async fn upload(&self, ctx: &mut Context, file_type: u8) -> Option<Vec<u8>> {
+        //every upload takes many requests
+        self.publish_next_step(Duration::ZERO);
+        let started = Instant::now();
+        match upload_file(ctx, file_type, self.modbus.read_timeout).await {
+            Ok(data) => {
+                debug!("<i>{}</>: uploaded file <green><i>0x{:02X}</>: {} bytes [⏱️ {} ms]", self.name, file_type, data.len(), started.elapsed().as_millis());
+                if let Err(e) = log_file(chrono::Utc::now(), file_type, &data).await {
+                    error!("<i>{}</>: error saving file 0x{:02X}: <b>{}</>", self.name, file_type, e);
+                }
+                Some(data)
+            }
+            Err(e) => {
+                warn!("<i>{}</>: file upload error, file: <green><i>0x{:02X}</>, error: <b>{}</>", self.name, file_type, e);
+                None
+            }
+        }
+    }
 
-        use super::dump::*;
-        log_params(start, &params).await;
-        
+    /// Executes a request of a proxy client on the inverter connection
+    async fn serve_proxy_call(&self, ctx: &mut Context, call: ProxyCall) {
+        if call.reply.is_closed() {
+            //the client gave up waiting, a late write could surprise it
+            debug!("<i>{}</>: proxy: dropping expired {:?}", self.name, call.request);
+            return;
+        }
+        let result = match &call.request {
+            ProxyRequest::Read { addr, len } => {
+                let result = self.read_registers(ctx, *addr, *len).await;
+                if let Ok(data) = &result {
+                    self.shared.write().unwrap().cache_registers(*addr, data);
+                }
+                result
+            }
+            ProxyRequest::Write { addr, data } => {
+                info!("<i>{}</>: proxy: writing registers <green><i>{}-{}</>: {:?}", self.name, addr, data.len(), data);
+                let result = self.write_registers(ctx, *addr, data).await.map(|_| vec![]);
+                self.shared.write().unwrap().invalidate_registers(*addr, data.len() as u16);
+                result
+            }
+        };
+        if let Err(e) = &result {
+            debug!("<i>{}</>: proxy: {:?} failed: {}", self.name, call.request, e);
+        }
+        //the client may be gone already
+        let _ = call.reply.send(result);
+    }
 
-        Ok((ctx, params, ms))
+    /// Waits until `deadline` serving the proxy requests in the meantime, returns false when cancelled
+    async fn wait_serving_proxy(
+        &self,
+        ctx: &mut Context,
+        deadline: tokio::time::Instant,
+        proxy: &mut Option<tokio::sync::OwnedMutexGuard<tokio::sync::mpsc::Receiver<ProxyCall>>>,
+        cancel: &CancellationToken,
+    ) -> bool {
+        let sleep = tokio::time::sleep_until(deadline);
+        tokio::pin!(sleep);
+        loop {
+            let next_call = async {
+                match proxy {
+                    Some(rx) => rx.recv().await,
+                    None => future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = &mut sleep => return true,
+                _ = cancel.cancelled() => return false,
+                call = next_call => match call {
+                    Some(call) => self.serve_proxy_call(ctx, call).await,
+                    //all senders are gone
+                    None => *proxy = None,
+                },
+            }
+        }
     }
 
     #[rustfmt::skip]
-    pub async fn worker(&mut self, worker_cancel_flag: Arc<AtomicBool>) -> Result<()> {
+    pub async fn worker(&mut self, cancel: CancellationToken) -> Result<()> {
         info!("<i>{}</>: Starting task", self.name);
         let mut stats_interval = Instant::now();
         let mut terminated = false;
 
+        let mut energy = EnergyLedger::load(ENERGY_LEDGER_PATH);
+        //the PV strings are in the SUN2000 register map only
+        let mut string_analyzer = match (self.string_analysis.enabled, self.backend) {
+            (true, Backend::Huawei(DeviceType::Inverter)) => {
+                Some(StringAnalyzer::new(self.string_analysis.clone(), StringHistory::load(STRING_HISTORY_PATH)))
+            }
+            (true, _) => {
+                warn!("<i>{}</>: string analysis is not supported by a {}, ignoring", self.name, self.backend);
+                None
+            }
+            _ => None,
+        };
+        //the inverter clock is in the SUN2000 register map only
+        let clock_supported = self.backend == Backend::Huawei(DeviceType::Inverter);
+        if !clock_supported {
+            //the control and the files are only available in the inverter register map
+            if self.export_limit.is_some() || self.battery_schedule.is_some() || self.optimizers || !self.upload_files.is_empty() || self.clock.sync {
+                warn!("<i>{}</>: export limit, battery schedule, optimizers, file uploads and clock sync are not supported by a {}, ignoring", self.name, self.backend);
+            }
+            self.export_limit = None;
+            self.battery_schedule = None;
+            self.optimizers = false;
+            self.upload_files.clear();
+        }
+        let mut export_limiter = self.export_limit.clone().map(|config| {
+            info!(
+                "<i>{}</>: export limit: <b>{} W</>{}",
+                self.name, config.max_export, if config.dry_run { " (dry run)" } else { "" }
+            );
+            ExportLimiter::new(config)
+        });
+        let mut battery_scheduler = match (&self.battery_schedule, self.battery_installed) {
+            (Some(config), true) => Some(BatteryScheduler::new(config.clone())),
+            (Some(_), false) => {
+                warn!("<i>{}</>: battery schedule is configured but <b>battery_installed</> is not set, ignoring", self.name);
+                None
+            }
+            _ => None,
+        };
+
+        let mut was_night = false;
+        //local date of the last upload of the extra files
+        let mut files_day: Option<chrono::NaiveDate> = None;
+        //the backoff (and an open breaker) survives the worker restarts
+        let failures = self.shared.read().unwrap().worker.failures;
+        let mut backoff = Backoff::with_failures(self.modbus.clone(), failures);
+        //the first attempt is made right away
+        self.publish_next_step(Duration::ZERO);
+        //the proxy requests are served between the polls
+        let mut proxy = match &self.proxy {
+            Some(rx) => Some(rx.clone().lock_owned().await),
+            None => None,
+        };
+        //the latest value of every parameter, as not all of them are read in each poll
+        let mut latest: Vec<Parameter> = vec![];
+
         let mut state = Sun2000State {
             device_status: None,
             storage_status: None,
@@ -360,43 +514,40 @@ impl Sun2000 {
             fault_code: None,
         };
 
+        let slave = self.slave();
+        let mut device: Box<dyn Device> = match self.backend {
+            Backend::Huawei(device_type) => Box::new(HuaweiDevice::new(
+                self.name.clone(), self.host_port.clone(), slave, device_type, self.modbus.clone(), self.shared.clone(), &self.poll_intervals,
+            )),
+            Backend::SunSpec => Box::new(SunSpecDevice::new(self.name.clone(), self.host_port.clone(), slave, self.modbus.clone())),
+        };
+
         loop {
-            if terminated || worker_cancel_flag.load(Ordering::SeqCst) {
+            if terminated || cancel.is_cancelled() {
                 break;
             }
 
-            let socket_addr = self.host_port.parse().unwrap();
-
-            let slave;
-            if self.dongle_connection {
-                //USB dongle connection: Slave ID has to be 0x01
-                slave = Slave(0x01);
-            } else {
-                //internal wifi: Slave ID has to be 0x00, otherwise the inverter is not responding
-                slave = Slave(0x00);
-            }
+            let night = self.update_night(&mut was_night);
 
             info!("<i>{}</>: connecting to <u>{}</>...", self.name, self.host_port);
-            let retval = tcp::connect_slave(socket_addr, slave);
-            let conn;
-            match timeout(Duration::from_secs(5), retval).await {
-                Ok(res) => { conn = res; }
-                Err(e) => {
-                    error!("<i>{}</>: connect timeout: <b>{}</>", self.name, e);
-                    tokio::time::sleep(Duration::from_secs(2)).await;
-                    continue;
-                }
-            }
-
-            match conn {
+            match device.connect().await {
                 Ok(mut ctx) => {
                     info!("<i>{}</>: connected successfully", self.name);
                     //initial parameters table
                     tokio::time::sleep(Duration::from_secs(2)).await;
 
                     //obtaining all parameters from inverter
-                    let (new_ctx, params, _) = self.read_params(ctx, true).await?;
-                    ctx = new_ctx;
+                    let params = device.read_info(&mut ctx, night || backoff.is_open()).await?.params;
+                    if let Err(e) = log_params(chrono::Utc::now(), &params).await {
+                        error!("<i>{}</>: error saving the parameters dump: <b>{}</>", self.name, e);
+                    }
+                    self.shared.write().unwrap().device_info = params.clone();
+                    if let Some(scheduler) = &mut battery_scheduler {
+                        scheduler.reset();
+                    }
+                    if let (Some(limiter), Some(rated_power)) = (&mut export_limiter, get_param_value(&params, "rated_power")) {
+                        limiter.set_rated_power(rated_power);
+                    }
                     
                     for p in &params {
                         match &p.value {
@@ -412,6 +563,15 @@ impl Sun2000 {
                                 }
                                 _ => {}
                             },
+                            ParamKind::NumberU16(_) => match p.name {
+                                "nb_pv_strings" => {
+                                    info!("<i>{}</>: number of available strings: <b><cyan>{}</>", self.name, &p.get_text_value());
+                                }
+                                "nb_mpp_tracks" => {
+                                    info!("<i>{}</>: number of MPP trackers: <b><cyan>{}</>", self.name, &p.get_text_value());
+                                }
+                                _ => {}
+                            },
                             ParamKind::NumberU32(_) => match p.name.as_ref() {
                                 "rated_power" => {
                                     info!(
@@ -427,18 +587,7 @@ impl Sun2000 {
                         }
                     }
 
-                    /*match nb_pv_strings {
-                        Some(n) => {
-                            info!("<i>{}</>: number of available strings: <b><cyan>{}</>", self.name, n);
-                            for i in 1..=n {
-                                parameters.push(Parameter::new_from_string(format!("pv_{:02}_voltage", i), ParamKind::NumberI16(None), None, Some("V"), 10, 32014 + i*2, 1, false, true));
-                                parameters.push(Parameter::new_from_string(format!("pv_{:02}_current", i), ParamKind::NumberI16(None), None, Some("A"), 100, 32015 + i*2, 1, false, true));
-                            }
-                        }
-                        None => {}
-                    }
-
-                    if self.optimizers {
+                    /*if self.optimizers {
                         info!("<i>{}</>: config: optimizers enabled", self.name);
                         parameters.push(Parameter::new("nb_optimizers", ParamKind::NumberU16(None), None, None, 1, 37200, 1, false, false));
                         parameters.push(Parameter::new("nb_online_optimizers", ParamKind::NumberU16(None), None, None, 1, 37201, 1, false, true));
@@ -460,34 +609,53 @@ impl Sun2000 {
                         parameters.push(Parameter::new("storage_current_day_discharge_capacity", ParamKind::NumberU32(None), None, Some("kWh"), 100, 37017, 2, false, true));
                     }*/
 
-                    // obtain Device Description Definition
-                    use tokio_modbus::prelude::*;
-                    let retval = ctx.call(Request::Custom(0x2b, vec![0x0e, 0x03, 0x87]));
-                    match timeout(Duration::from_secs_f32(5.0), retval).await {
-                        Ok(res) => match res {
-                            Ok(rsp) => match rsp {
-                                Response::Custom(f, rsp) => {
-                                    debug!("<i>{}</>: Result for function {} is '{:?}'", self.name, f, rsp);
-                                    let _ = self.attribute_parser(rsp);
-                                }
-                                _ => {
-                                    error!("<i>{}</>: unexpected Reading Device Identifiers (0x2B) result", self.name);
-                                }
-                            },
-                            Err(e) => {
-                                warn!("<i>{}</i>: read error during <green><i>Reading Device Identifiers (0x2B)</>, error: <b>{}</>", self.name, e);
-                            }
-                        },
-                        Err(e) => {
-                            warn!("<i>{}</i>: read timeout during <green><i>Reading Device Identifiers (0x2B)</>, error: <b>{}</>", self.name, e);
+                    //identification of the inverter and the devices behind it
+                    let identities = device.read_identity(&mut ctx).await;
+                    if !identities.is_empty() {
+                        info!("<i>{}</i>: <blue>Device Description attributes:</>", self.name);
+                        for identity in &identities {
+                            info!("<i>{}</i>: <b><cyan>{}</>", self.name, identity);
                         }
+                        {
+                            let mut shared = self.shared.write().unwrap();
+                            shared.devices = identities.clone();
+                            shared.slave = slave.0;
+                        }
+                        self.identities = identities;
                     }
 
                     
 
-                    let mut daily_yield_energy: Option<u32> = None;
+                    if self.optimizers {
+                        if let Some(data) = self.upload(&mut ctx, FILE_OPTIMIZER_SYSTEM_INFO).await {
+                            match parse_optimizer_system_info(&data) {
+                                Ok(info) => {
+                                    info!("<i>{}</>: optimizers: <b><cyan>{}</>", self.name, info.len());
+                                    self.shared.write().unwrap().optimizer_info = info;
+                                }
+                                Err(e) => warn!("<i>{}</>: invalid optimizer system information: <b>{}</>", self.name, e),
+                            }
+                        }
+                    }
+                    let today = Local::now().date().naive_local();
+                    if !self.upload_files.is_empty() && files_day != Some(today) {
+                        for file_type in self.upload_files.clone() {
+                            if let Some(data) = self.upload(&mut ctx, file_type).await {
+                                info!("<i>{}</>: saved file <green><i>0x{:02X}</> ({} bytes)", self.name, file_type, data.len());
+                            }
+                        }
+                        files_day = Some(today);
+                    }
+
+                    let mut daily_yield_energy: Option<f64> = None;
+                    let mut optimizers_uploaded: Option<Instant> = None;
+                    //the clock is checked right after connecting
+                    let mut clock_checked: Option<Instant> = None;
+                    //time of the last successful read and the params which failed when due
+                    let mut last_read: HashMap<&'static str, chrono::DateTime<chrono::Utc>> = HashMap::new();
+                    let mut stale: Vec<&'static str> = vec![];
                     loop {
-                        if worker_cancel_flag.load(Ordering::SeqCst) {
+                        if cancel.is_cancelled() {
                             debug!("<i>{}</>: Got terminate signal from main", self.name);
                             terminated = true;
                         }
@@ -497,10 +665,11 @@ impl Sun2000 {
                                 > Duration::from_secs_f32(SUN2000_STATS_DUMP_INTERVAL_SECS)
                         {
                             stats_interval = Instant::now();
+                            let stats = self.shared.read().unwrap().stats.clone();
                             info!(
                                 "<i>{}</>: 📊 inverter query statistics: ok: <b>{}</>, errors: <b>{}</>, daily energy yield: <b>{:.1} kWh</>",
-                                self.name, self.poll_ok, self.poll_errors,
-                                daily_yield_energy.unwrap_or_default() as f64 / 100.0,
+                                self.name, stats.poll_ok, stats.poll_errors,
+                                daily_yield_energy.unwrap_or_default(),
                             );
 
                             if terminated {
@@ -508,85 +677,84 @@ impl Sun2000 {
                             }
                         }
 
+                        let night = self.update_night(&mut was_night);
                         let now = chrono::Utc::now();
-                        let mut start = now.with_second((now.second()/SUN2000_POLL_INTERVAL_SECS)*SUN2000_POLL_INTERVAL_SECS).unwrap().with_nanosecond(0).unwrap().signed_duration_since(now);
+                        if night {
+                            //slow polling or a pause until dawn
+                            let wait = self.reconnect_delay(night, &mut backoff);
+                            if !self.wait_serving_proxy(&mut ctx, tokio::time::Instant::now() + wait, &mut proxy, &cancel).await {
+                                continue;
+                            }
+                        } else {
+                            let mut start = now.with_second((now.second()/SUN2000_POLL_INTERVAL_SECS)*SUN2000_POLL_INTERVAL_SECS).unwrap().with_nanosecond(0).unwrap().signed_duration_since(now);
 
-                        let period = chrono::Duration::seconds(SUN2000_POLL_INTERVAL_SECS.into()).to_std().unwrap();
-                        while start < chrono::Duration::seconds(0) {
-                            start = start.checked_add(&chrono::Duration::seconds(SUN2000_POLL_INTERVAL_SECS.into())).unwrap();
+                            while start < chrono::Duration::seconds(0) {
+                                start = start.checked_add(&chrono::Duration::seconds(SUN2000_POLL_INTERVAL_SECS.into())).unwrap();
+                            }
+                            let deadline = tokio::time::Instant::now() + start.to_std().unwrap();
+                            self.publish_next_step(start.to_std().unwrap());
+                            if !self.wait_serving_proxy(&mut ctx, deadline, &mut proxy, &cancel).await {
+                                continue;
+                            }
                         }
-                        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + start.to_std().unwrap(), period);    
-                        interval.tick().await;
-
-                        let mut device_status: Option<u16> = None;
-                        let mut storage_status: Option<i16> = None;
-                        let mut grid_code: Option<u16> = None;
-                        let mut state_1: Option<u16> = None;
-                        let mut state_2: Option<u16> = None;
-                        let mut state_3: Option<u32> = None;
-                        let mut alarm_1: Option<u16> = None;
-                        let mut alarm_2: Option<u16> = None;
-                        let mut alarm_3: Option<u16> = None;
-                        let mut fault_code: Option<u16> = None;
-                        
+
                         //obtaining all parameters from inverter
                         let now = chrono::Utc::now();
 
-                        let (new_ctx, params, ms) = self.read_params(ctx, false).await?;
-                        ctx = new_ctx;
-
-
-                        for p in &params {
-                            match p.value {
-                                ParamKind::NumberU16(n) => match p.name.as_ref() {
-                                    "fault_code" => match n {
-                                        Some(fc) => {
-                                            if fc != 0 {
-                                                error!(
-                                                    "<i>{}</>: inverter fault code is: <b><red>{:#08X}</>",
-                                                    self.name, fc
-                                                );
-                                            }
-                                            fault_code = n;
-                                        }
-                                        _ => {}
-                                    },
-                                    "device_status" => device_status = n,
-                                    "grid_code" => grid_code = n,
-                                    "state_1" => state_1 = n,
-                                    "state_2" => state_2 = n,
-                                    "alarm_1" => alarm_1 = n,
-                                    "alarm_2" => alarm_2 = n,
-                                    "alarm_3" => alarm_3 = n,
-                                    _ => {}
-                                },
-                                ParamKind::NumberI16(n) => match p.name.as_ref() {
-                                    "storage_status" => storage_status = n,
-                                    _ => {}
-                                },
-                                ParamKind::NumberU32(n) => match p.name.as_ref() {
-                                    "state_3" => state_3 = n,
-                                    "daily_yield_energy" => daily_yield_energy = n,
-                                    _ => {}
-                                },
-                                _ => {}
-                            }
+                        //at night there are only a few polls, so everything is read
+                        let Snapshot { params, expected, ms, disconnected } = device.read_snapshot(&mut ctx, night, night || backoff.is_open()).await?;
+                        if let Err(e) = log_params(now, &params).await {
+                            error!("<i>{}</>: error saving the parameters dump: <b>{}</>", self.name, e);
+                        }
+                        let new_state = device.state(&params);
+                        if let Some(fc) = new_state.fault_code.filter(|fc| *fc != 0) {
+                            error!(
+                                "<i>{}</>: inverter fault code is: <b><red>{:#08X}</>",
+                                self.name, fc
+                            );
+                        }
+                        //the gain differs between the register maps
+                        if let Some(energy) = get_param_value(&params, "daily_yield_energy") {
+                            daily_yield_energy = Some(energy);
                         }
 
-                        let param_count = PARAMETERS_POLL.0.iter().filter(|s| s.save_to_influx ||
-                            s.name.starts_with("state_") ||
-                            s.name.starts_with("alarm_") ||
-                            s.name.ends_with("_status") ||
-                            s.name.ends_with("_code")).count();
-                        if params.len() != param_count {
-                            error!("<i>{}</>: problem obtaining a complete parameter list (read: {}, expected: {}), reconnecting...", self.name, params.len(), param_count);
-                            self.poll_errors = self.poll_errors + 1;
+                        let param_count = expected.len();
+                        //only a broken connection (or nothing readable at all) needs a reconnect,
+                        //parameters of the unreadable spans keep their last known values
+                        if disconnected || params.is_empty() {
+                            if night || backoff.is_open() {
+                                debug!("<i>{}</>: poll failed at night (read: {}, expected: {}), reconnecting...", self.name, params.len(), param_count);
+                            } else {
+                                error!("<i>{}</>: poll failed (read: {}, expected: {}), reconnecting...", self.name, params.len(), param_count);
+                            }
+                            self.shared.write().unwrap().stats.poll_errors += 1;
                             break;
-                        } else {
-                            self.poll_ok = self.poll_ok + 1;
+                        }
+                        for p in &params {
+                            last_read.insert(p.name, now);
+                        }
+                        let missing: Vec<&'static str> = expected.iter().copied().filter(|n| !params.iter().any(|p| p.name == *n)).collect();
+                        let newly_stale: Vec<&str> = missing.iter().copied().filter(|n| !stale.contains(n)).collect();
+                        if !newly_stale.is_empty() {
+                            warn!("<i>{}</>: unreadable parameters, keeping the last known values: <b>{}</>", self.name, newly_stale.join(", "));
+                        }
+                        stale.retain(|n| !params.iter().any(|p| p.name == *n));
+                        stale.extend(newly_stale);
+                        if backoff.success() {
+                            info!("<i>{}</>: inverter is responding again, normal polling", self.name);
+                        }
+                        self.publish_backoff(&backoff);
+                        {
+                            let mut shared = self.shared.write().unwrap();
+                            shared.stats.poll_ok += 1;
+                            if !missing.is_empty() {
+                                shared.stats.poll_partial += 1;
+                            }
+                            shared.stats.last_poll_ok = Some(Instant::now());
+                            shared.stats.last_query_ms = ms;
                         }
 
-                        let mut point = influxdb2::models::DataPoint::builder("inverter");
+                        let mut point = self.identity_tags(influxdb2::models::DataPoint::builder("inverter"));
 
                         for p in &params {
                             if p.save_to_influx {
@@ -598,30 +766,232 @@ impl Sun2000 {
 
                                         
                         //save query time                
-                        points.push(influxdb2::models::DataPoint::builder("inverter_query_time")
+                        points.push(self.identity_tags(influxdb2::models::DataPoint::builder("inverter_query_time"))
                             .field("value", ms as i64)
-                            .field("param_count", param_count as i64).build()?);
+                            .field("param_count", param_count as i64)
+                            .field("read_count", params.len() as i64)
+                            .field("stale_count", stale.len() as i64).build()?);
+
+                        //energy accounting
+                        if let Some(interval) = energy.update(now, &params, self.tariff.as_ref()) {
+                            let today = energy.today(&now);
+                            let mut point = influxdb2::models::DataPoint::builder("energy")
+                                .field("house_power", interval.house_power)
+                                .field("production", interval.totals.production)
+                                .field("consumption", interval.totals.consumption)
+                                .field("import", interval.totals.import)
+                                .field("export", interval.totals.export)
+                                .field("self_consumed", interval.totals.self_consumed)
+                                .field("today_production", today.production)
+                                .field("today_consumption", today.consumption)
+                                .field("today_import", today.import)
+                                .field("today_export", today.export);
+                            if let Some(ratio) = today.self_consumption_ratio() {
+                                point = point.field("today_self_consumption_ratio", ratio);
+                            }
+                            if let Some(autarky) = today.autarky() {
+                                point = point.field("today_autarky", autarky);
+                            }
+                            if self.tariff.is_some() {
+                                point = point
+                                    .field("import_cost", interval.totals.import_cost)
+                                    .field("export_revenue", interval.totals.export_revenue)
+                                    .field("savings", interval.totals.savings)
+                                    .field("today_import_cost", today.import_cost)
+                                    .field("today_export_revenue", today.export_revenue)
+                                    .field("today_savings", today.savings);
+                            }
+                            points.push(point.build()?);
+
+                            let mut shared = self.shared.write().unwrap();
+                            shared.house_power = Some(interval.house_power);
+                            shared.energy_today = today;
+                            shared.energy_month = energy.this_month(&now);
+                        }
+                        energy.save_periodically(ENERGY_LEDGER_PATH, &now);
+
+                        //export limit control loop
+                        if let Some(limiter) = &mut export_limiter {
+                            let meter_power = get_param_value(&params, "power_meter_active_power");
+                            if let Some(cmd) = limiter.update(Instant::now(), get_param_value(&params, "active_power"), meter_power) {
+                                if limiter.config.dry_run {
+                                    info!("<i>{}</>: export limit (dry run): would set active power limit to <b>{} W</> ({:?}), meter: {:?} W", self.name, cmd.limit, cmd.reason, meter_power);
+                                } else {
+                                    info!("<i>{}</>: export limit: setting active power limit to <b>{} W</> ({:?}), meter: {:?} W", self.name, cmd.limit, cmd.reason, meter_power);
+                                    let data = [(cmd.limit >> 16) as u16, (cmd.limit & 0xffff) as u16];
+                                    if let Err(e) = self.write_registers(&mut ctx, EXPORT_LIMIT_REGISTER, &data).await {
+                                        error!("<i>{}</>: export limit: write error, register: <green><i>{}</>, error: <b>{}</>", self.name, EXPORT_LIMIT_REGISTER, e);
+                                        limiter.command_failed();
+                                    }
+                                }
+                            }
+                            if let Some(limit) = limiter.limit() {
+                                points.push(influxdb2::models::DataPoint::builder("export_limit")
+                                    .field("limit", limit)
+                                    .field("stale", limiter.is_stale())
+                                    .field("dry_run", limiter.config.dry_run)
+                                    .build()?);
+                            }
+                        }
+
+                        //battery time-of-use scheduler
+                        if let Some(scheduler) = &mut battery_scheduler {
+                            let soc = match self.read_registers(&mut ctx, BATTERY_SOC_REGISTER, 1).await {
+                                Ok(data) => data.first().map(|v| *v as f64 / 10.0),
+                                Err(e) => {
+                                    warn!("<i>{}</>: battery: SOC read error, register: <green><i>{}</>, error: <b>{}</>", self.name, BATTERY_SOC_REGISTER, e);
+                                    None
+                                }
+                            };
+                            if let Some(decision) = scheduler.update(now, soc, self.tariff.as_ref()) {
+                                info!(
+                                    "<i>{}</>: 🔋 battery: <b>{}</> for {} min, SOC: {}, reason: {}",
+                                    self.name, decision.action, decision.minutes,
+                                    soc.map(|s| format!("{:.1}%", s)).unwrap_or_else(|| "unknown".into()), decision.reason
+                                );
+                                if let Err(e) = self.write_battery_decision(&mut ctx, &decision, &scheduler.config).await {
+                                    error!("<i>{}</>: battery: write error: <b>{}</>", self.name, e);
+                                    scheduler.reset();
+                                }
+                                let mut point = influxdb2::models::DataPoint::builder("battery_schedule")
+                                    .field("action", decision.action.to_string())
+                                    .field("power", decision.forced_power() as i64)
+                                    .field("minutes", decision.minutes as i64)
+                                    .field("reason", decision.reason.clone());
+                                if let Some(soc) = soc {
+                                    point = point.field("soc", soc);
+                                }
+                                points.push(point.build()?);
+                                let _ = self.events.send(Sun2000Event::Battery {
+                                    time: now,
+                                    action: decision.action,
+                                    minutes: decision.minutes,
+                                    soc,
+                                    reason: decision.reason,
+                                });
+                            }
+                        }
                                         
 
+                        //inverter clock drift
+                        let clock_due = match clock_checked {
+                            Some(t) => t.elapsed() >= self.clock.check_interval,
+                            None => true,
+                        };
+                        if clock_supported && !night && clock_due {
+                            clock_checked = Some(Instant::now());
+                            let last_sync = self.shared.read().unwrap().clock.as_ref().and_then(|c| c.last_sync);
+                            match self.check_clock(&mut ctx, last_sync).await {
+                                Ok(status) => {
+                                    let point = self.identity_tags(influxdb2::models::DataPoint::builder("inverter_clock"))
+                                        .field("drift", status.drift)
+                                        .field("time_zone", status.inverter_time_zone as i64)
+                                        .field("host_synchronized", status.host_synchronized)
+                                        .field("synced", matches!(status.last_sync, Some(t) if t >= status.time));
+                                    points.push(point.build()?);
+                                    self.shared.write().unwrap().clock = Some(status);
+                                }
+                                Err(e) => warn!("<i>{}</>: clock check error: <b>{}</>", self.name, e),
+                            }
+                        }
+
+                        //optimizer real-time data
+                        let optimizers_due = match optimizers_uploaded {
+                            Some(t) => t.elapsed() >= Duration::from_secs(OPTIMIZER_UPLOAD_INTERVAL_SECS),
+                            None => true,
+                        };
+                        if self.optimizers && !night && optimizers_due {
+                            optimizers_uploaded = Some(Instant::now());
+                            let samples = self.upload(&mut ctx, FILE_OPTIMIZER_REAL_TIME_DATA).await.map(|data| parse_optimizer_real_time_data(&data));
+                            match samples {
+                                Some(Ok(samples)) => {
+                                    if let Some(sample) = samples.into_iter().max_by_key(|s| s.time) {
+                                        let mut shared = self.shared.write().unwrap();
+                                        for o in &sample.optimizers {
+                                            let mut point = influxdb2::models::DataPoint::builder("optimizer")
+                                                .tag("address", o.address.to_string())
+                                                .field("output_power", o.output_power)
+                                                .field("voltage_to_ground", o.voltage_to_ground)
+                                                .field("output_voltage", o.output_voltage)
+                                                .field("output_current", o.output_current)
+                                                .field("input_voltage", o.input_voltage)
+                                                .field("input_current", o.input_current)
+                                                .field("temperature", o.temperature)
+                                                .field("running_status", o.running_status as i64)
+                                                .field("energy_yield", o.energy_yield)
+                                                .timestamp(sample.time.timestamp_nanos());
+                                            if let Some(info) = shared.optimizer_info.iter().find(|i| i.address == o.address) {
+                                                point = point.tag("serial_number", info.serial_number.clone());
+                                            }
+                                            points.push(point.build()?);
+                                        }
+                                        shared.optimizers = Some(sample);
+                                    }
+                                }
+                                Some(Err(e)) => warn!("<i>{}</>: invalid optimizer real-time data: <b>{}</>", self.name, e),
+                                None => {}
+                            }
+                        }
+
                         //setting new inverter state/alarm
                         let mut state_changes = HashMap::new();
                         state.set_new_status(
                             &self.name,
-                            device_status,
-                            storage_status,
-                            grid_code,
-                            state_1,
-                            state_2,
-                            state_3,
-                            alarm_1,
-                            alarm_2,
-                            alarm_3,
-                            fault_code,
+                            new_state.device_status,
+                            new_state.storage_status,
+                            new_state.grid_code,
+                            new_state.state_1,
+                            new_state.state_2,
+                            new_state.state_3,
+                            new_state.alarm_1,
+                            new_state.alarm_2,
+                            new_state.alarm_3,
+                            new_state.fault_code,
                             &mut state_changes
                         );
+                        for p in &params {
+                            match latest.iter_mut().find(|l| l.name == p.name) {
+                                Some(l) => *l = p.clone(),
+                                None => latest.push(p.clone()),
+                            }
+                        }
+                        latest.sort_by_key(|p| p.reg_address);
+                        let snapshot = PollSnapshot {
+                            time: now,
+                            params: latest.clone(),
+                            stale: stale.iter().map(|n| StaleParam { name: n.to_string(), last_read: last_read.get(n).copied() }).collect(),
+                        };
+                        //PV string analysis, reported the same way as the inverter alarms
+                        if let Some(analyzer) = &mut string_analyzer {
+                            for (key, event) in analyzer.update(now, &snapshot.fresh_params()) {
+                                warn!("<i>{}</>: {}: <b><red>{}</>", self.name, key, event);
+                                state_changes
+                                    .entry(key)
+                                    .and_modify(|e: &mut String| {
+                                        e.push_str("; ");
+                                        e.push_str(&event);
+                                    })
+                                    .or_insert_with(|| event.clone());
+                            }
+                            analyzer.history.save_periodically(STRING_HISTORY_PATH, &now);
+                        }
+                        {
+                            let mut shared = self.shared.write().unwrap();
+                            shared.snapshot = Some(snapshot.clone());
+                            shared.state = state.clone();
+                        }
+
+                        //publish for live subscribers, it's fine if there is nobody listening
+                        let _ = self.events.send(Sun2000Event::Snapshot(Arc::new(snapshot)));
+                        if !state_changes.is_empty() {
+                            let _ = self.events.send(Sun2000Event::StateChange {
+                                time: now,
+                                changes: state_changes.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+                            });
+                        }
 
                         if !state_changes.is_empty() {
-                            let mut point = influxdb2::models::DataPoint::builder("inverter_status").timestamp(now.timestamp_nanos());
+                            let mut point = self.identity_tags(influxdb2::models::DataPoint::builder("inverter_status")).timestamp(now.timestamp_nanos());
                             for (state_key, state_str) in state_changes.iter() {
                                 point = point.field((*state_key).clone(), (*state_str).clone());
                             }
@@ -659,59 +1029,38 @@ impl Sun2000 {
                             );
                         }
                     }
+
+                    if !terminated {
+                        let night = self.update_night(&mut was_night);
+                        tokio::select! {
+                            _ = tokio::time::sleep(self.reconnect_delay(night, &mut backoff)) => {}
+                            _ = cancel.cancelled() => {}
+                        }
+                    }
                 }
                 Err(e) => {
-                    error!("<i>{}</>: connection error: <b>{}</>", self.name, e);
-                    tokio::time::sleep(Duration::from_secs(2)).await;
+                    let what = if e.kind() == ErrorKind::TimedOut { "connect timeout" } else { "connection error" };
+                    if night || backoff.is_open() {
+                        debug!("<i>{}</>: {} at night: <b>{}</>", self.name, what, e);
+                    } else {
+                        error!("<i>{}</>: {}: <b>{}</>", self.name, what, e);
+                    }
+                    tokio::select! {
+                        _ = tokio::time::sleep(self.reconnect_delay(night, &mut backoff)) => {}
+                        _ = cancel.cancelled() => {}
+                    }
                 }
             }
         }
 
+        energy.save(ENERGY_LEDGER_PATH);
+        if let Some(analyzer) = &mut string_analyzer {
+            analyzer.history.save(STRING_HISTORY_PATH);
+        }
         info!("{}: task stopped", self.name);
         Ok(())
     }
 
 
-    fn attribute_parser(&self, mut a: Vec<u8>) -> Result<()> {
-        //search for 'Description about the first device' (0x88)
-        if let Some(index) = a.iter().position(|&x| x == 0x88) {
-            //strip beginning bytes up to descriptor start
-            a.drain(0..=index);
-
-            //next (first) byte is len
-            let len = a[0] as usize;
-
-            //leave only the relevant descriptor string
-            a = a.drain(1..=len).collect();
-
-            //convert it to string
-            let x = String::from_utf8(a)?;
-
-            //split by semicolons
-            let split = x.split(";");
-
-            //parse and dump all attributes
-            info!(
-                "<i>{}</i>: <blue>Device Description attributes:</>",
-                self.name
-            );
-            for s in split {
-                let mut sp = s.split("=");
-                let id = sp.next();
-                let val = sp.next();
-                if id.is_none() || val.is_none() {
-                    continue;
-                }
-                info!(
-                    "<i>{}</i>: <black>{}:</> {}: <b><cyan>{}</>",
-                    self.name,
-                    id.unwrap(),
-                    get_attribute_name(id.unwrap()),
-                    val.unwrap()
-                );
-            }
-        }
-        Ok(())
-    }
 
 }
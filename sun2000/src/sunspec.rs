@@ -0,0 +1,460 @@
+use futures::future::BoxFuture;
+use futures::prelude::*;
+use simplelog::*;
+use std::io;
+use std::io::ErrorKind;
+use std::time::Instant;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use super::defs::*;
+use super::device::*;
+use super::identity::*;
+use super::params::*;
+use super::retry::*;
+
+pub const SUNSPEC_BASE_ADDRESSES: [u16; 3] = [40000, 50000, 0]; //where the "SunS" marker is looked for
+pub const SUNSPEC_MARKER: [u16; 2] = [0x5375, 0x6e53]; //"SunS"
+pub const SUNSPEC_END_MODEL: u16 = 0xffff;
+pub const SUNSPEC_MAX_MODELS: usize = 64; //limit of the model list walk
+
+pub const MODEL_COMMON: u16 = 1;
+pub const MODEL_INVERTER: [u16; 3] = [101, 102, 103]; //single phase, split phase, three phase (integer + scale factor)
+pub const MODEL_NAMEPLATE: u16 = 120;
+pub const MODEL_METER: [u16; 4] = [201, 202, 203, 204]; //single phase, split phase, wye, delta (integer + scale factor)
+
+const NOT_IMPLEMENTED_I16: u16 = 0x8000;
+const NOT_IMPLEMENTED_U16: u16 = 0xffff;
+
+/// Parameters given by the inverter model, reported as unreadable when the model can't be read
+const INVERTER_PARAMETERS: &[&str] = &[
+    "active_power",
+    "input_power",
+    "reactive_power",
+    "power_factor",
+    "grid_frequency",
+    "accumulated_yield_energy",
+    "internal_temperature",
+    "device_status",
+];
+const METER_PARAMETERS: &[&str] = &["power_meter_active_power", "grid_exported_energy", "grid_accumulated_energy"];
+
+/// Model to read, the parameters it gives and its parser
+type ModelReader = (Option<SunSpecModel>, &'static [&'static str], fn(&[u16]) -> Vec<Parameter>);
+
+/// Location of a SunSpec information model
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SunSpecModel {
+    pub id: u16,
+    /// address of the first data register (after the model ID and length)
+    pub addr: u16,
+    pub len: u16,
+}
+
+/// Text of the registers (two chars each), padded with NULs
+fn sunspec_text(data: &[u16]) -> Option<String> {
+    let bytes: Vec<u8> = data.iter().flat_map(|v| v.to_be_bytes()).filter(|b| *b != 0).collect();
+    Some(String::from_utf8_lossy(&bytes).trim().to_string()).filter(|t| !t.is_empty())
+}
+
+fn value_u16(v: u16) -> Option<f64> {
+    Some(v).filter(|v| *v != NOT_IMPLEMENTED_U16).map(|v| v as f64)
+}
+
+fn value_i16(v: u16) -> Option<f64> {
+    Some(v).filter(|v| *v != NOT_IMPLEMENTED_I16).map(|v| v as i16 as f64)
+}
+
+/// Accumulator, 0 means not implemented
+fn value_acc32(hi: u16, lo: u16) -> Option<f64> {
+    Some(((hi as u32) << 16) | lo as u32).filter(|v| *v != 0).map(|v| v as f64)
+}
+
+/// Applies the scale factor (power of 10)
+fn scaled(value: Option<f64>, sf: u16) -> Option<f64> {
+    match sf {
+        NOT_IMPLEMENTED_I16 => None,
+        sf => value.map(|v| v * 10f64.powi(sf as i16 as i32)),
+    }
+}
+
+/// SunSpec operating state (`St`) as a SUN2000 device status
+pub fn device_status_from_sunspec(state: u16) -> Option<u16> {
+    match state {
+        1 => Some(0x0301), //off: shutdown by command
+        2 => Some(0xa000), //sleeping: no irradiation
+        3 => Some(0x0100), //starting
+        4 => Some(0x0200), //MPPT: on-grid
+        5 => Some(0x0201), //throttled: power limited
+        6 => Some(0x0301), //shutting down
+        7 => Some(0x0300), //fault
+        8 => Some(0x0000), //standby
+        _ => None,
+    }
+}
+
+/// The SUN2000 parameter `name` with the value given in its unit (eg. W or kWh)
+fn normalized(name: &str, value: Option<f64>) -> Option<Parameter> {
+    let p = find_parameter(name)?;
+    let raw = (value? * p.gain as f64).round();
+    let value = match p.value {
+        ParamKind::NumberU16(_) => ParamKind::NumberU16(Some(raw as u16)),
+        ParamKind::NumberI16(_) => ParamKind::NumberI16(Some(raw as i16)),
+        ParamKind::NumberU32(_) => ParamKind::NumberU32(Some(raw as u32)),
+        ParamKind::NumberI32(_) => ParamKind::NumberI32(Some(raw as i32)),
+        ParamKind::Text(_) => return None,
+    };
+    Some(Parameter { value, ..p.clone() })
+}
+
+fn normalized_text(name: &str, value: Option<String>) -> Option<Parameter> {
+    let p = find_parameter(name)?;
+    Some(Parameter { value: ParamKind::Text(Some(value?)), ..p.clone() })
+}
+
+/// Parameters of an inverter model (101-103) data
+pub fn parse_inverter_model(data: &[u16]) -> Vec<Parameter> {
+    if data.len() < 37 {
+        return vec![];
+    }
+    vec![
+        normalized("active_power", scaled(value_i16(data[12]), data[13])),
+        normalized("grid_frequency", scaled(value_u16(data[14]), data[15])),
+        normalized("reactive_power", scaled(value_i16(data[18]), data[19])),
+        //SunSpec power factor is in percent
+        normalized("power_factor", scaled(value_i16(data[20]), data[21]).map(|pf| pf / 100.0)),
+        //Wh
+        normalized("accumulated_yield_energy", scaled(value_acc32(data[22], data[23]), data[24]).map(|wh| wh / 1000.0)),
+        normalized("input_power", scaled(value_i16(data[29]), data[30])),
+        normalized("internal_temperature", scaled(value_i16(data[31]), data[35])),
+        normalized("device_status", value_u16(data[36]).and_then(|st| device_status_from_sunspec(st as u16)).map(|s| s as f64)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Parameters of a meter model (201-204) data. The power is positive when exporting,
+/// as with the SolarEdge meters and the SUN2000 power meter registers.
+pub fn parse_meter_model(data: &[u16]) -> Vec<Parameter> {
+    if data.len() < 53 {
+        return vec![];
+    }
+    vec![
+        normalized("power_meter_active_power", scaled(value_i16(data[16]), data[20])),
+        //Wh
+        normalized("grid_exported_energy", scaled(value_acc32(data[36], data[37]), data[52]).map(|wh| wh / 1000.0)),
+        normalized("grid_accumulated_energy", scaled(value_acc32(data[44], data[45]), data[52]).map(|wh| wh / 1000.0)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Identity given by the common model (1) data
+pub fn parse_common_model(data: &[u16]) -> Option<DeviceIdentity> {
+    if data.len() < 64 {
+        return None;
+    }
+    let manufacturer = sunspec_text(&data[0..16]);
+    let model = match (manufacturer, sunspec_text(&data[16..32])) {
+        (Some(manufacturer), Some(model)) => Some(format!("{} {}", manufacturer, model)),
+        (manufacturer, model) => model.or(manufacturer),
+    };
+    let software_version = sunspec_text(&data[40..48]);
+    let esn = sunspec_text(&data[48..64]);
+    let device_id = data.get(64).and_then(|v| value_u16(*v)).map(|v| v as u8);
+    //the attributes as if they were given by the device identification
+    let attributes = vec![
+        ("1", model.clone()),
+        ("2", software_version.clone()),
+        ("4", esn.clone()),
+        ("5", device_id.map(|id| id.to_string())),
+    ];
+    Some(DeviceIdentity {
+        model,
+        software_version,
+        esn,
+        device_id,
+        attributes: attributes.into_iter().filter_map(|(id, value)| value.map(|v| (id.to_string(), v))).collect(),
+        ..Default::default()
+    })
+}
+
+/// SunSpec backend: finds the information models of the device and reads the common,
+/// nameplate, inverter and meter models
+pub struct SunSpecDevice {
+    pub name: String,
+    pub host_port: String,
+    pub slave: Slave,
+    pub modbus: ModbusConfig,
+    models: Vec<SunSpecModel>,
+}
+
+impl SunSpecDevice {
+    pub fn new(name: String, host_port: String, slave: Slave, modbus: ModbusConfig) -> Self {
+        Self { name, host_port, slave, modbus, models: vec![] }
+    }
+
+    async fn read(&self, ctx: &mut Context, addr: u16, len: u16) -> io::Result<Vec<u16>> {
+        match timeout(self.modbus.read_timeout, ctx.read_holding_registers(addr, len)).await {
+            Ok(res) => res,
+            Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+        }
+    }
+
+    /// Walks the model list following the "SunS" marker
+    async fn discover_models(&self, ctx: &mut Context) -> io::Result<Vec<SunSpecModel>> {
+        for base in SUNSPEC_BASE_ADDRESSES {
+            match self.read(ctx, base, 2).await {
+                Ok(marker) if marker == SUNSPEC_MARKER => {}
+                Ok(_) => continue,
+                Err(e) if e.kind() == ErrorKind::TimedOut => return Err(e),
+                Err(_) => continue,
+            }
+            let mut models = vec![];
+            let mut addr = base + 2;
+            for _ in 0..SUNSPEC_MAX_MODELS {
+                let header = self.read(ctx, addr, 2).await?;
+                let (id, len) = match header[..] {
+                    [id, len] if id != SUNSPEC_END_MODEL => (id, len),
+                    _ => break,
+                };
+                models.push(SunSpecModel { id, addr: addr + 2, len });
+                addr = match (addr + 2).checked_add(len) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+            return Ok(models);
+        }
+        Err(io::Error::new(ErrorKind::InvalidData, "no SunSpec marker found"))
+    }
+
+    fn model(&self, ids: &[u16]) -> Option<SunSpecModel> {
+        self.models.iter().find(|m| ids.contains(&m.id)).copied()
+    }
+
+    /// Reads the data of a model, a single request is enough for the supported models
+    async fn read_model(&self, ctx: &mut Context, model: SunSpecModel) -> io::Result<Vec<u16>> {
+        self.read(ctx, model.addr, model.len.min(SPAN_MAX_REGISTERS)).await
+    }
+
+    /// Reads the models and parses them, the parameters of an unreadable model are reported as expected
+    async fn read_models(
+        &self,
+        ctx: &mut Context,
+        models: &[ModelReader],
+        quiet: bool,
+    ) -> io::Result<Snapshot> {
+        let started = Instant::now();
+        let mut snapshot = Snapshot::default();
+        for (model, names, parse) in models {
+            let model = match model {
+                Some(model) => *model,
+                None => continue,
+            };
+            match self.read_model(ctx, model).await {
+                Ok(data) => {
+                    let params = parse(&data);
+                    snapshot.expected.extend(params.iter().map(|p| p.name));
+                    snapshot.params.extend(params);
+                }
+                Err(e) => {
+                    let msg = format!("<i>{}</i>: read error, SunSpec model <green><i>{}</> at {}, error: <b>{}</>", self.name, model.id, model.addr, e);
+                    if quiet { debug!("{}", msg) } else { error!("{}", msg) };
+                    snapshot.expected.extend(names.iter());
+                    if matches!(
+                        e.kind(),
+                        ErrorKind::TimedOut | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted
+                            | ErrorKind::NotConnected | ErrorKind::UnexpectedEof
+                    ) {
+                        snapshot.disconnected = true;
+                        break;
+                    }
+                }
+            }
+        }
+        snapshot.ms = started.elapsed().as_millis() as u64;
+        info!("{}: read {} parameters [⏱️ {} ms]", self.name, snapshot.params.len(), snapshot.ms);
+        Ok(snapshot)
+    }
+}
+
+fn parse_info(data: &[u16]) -> Vec<Parameter> {
+    match parse_common_model(data) {
+        Some(identity) => vec![
+            normalized_text("model_name", identity.model),
+            normalized_text("serial_number", identity.esn),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        None => vec![],
+    }
+}
+
+fn parse_nameplate(data: &[u16]) -> Vec<Parameter> {
+    match data {
+        [_, rating, sf, ..] => normalized("rated_power", scaled(value_u16(*rating), *sf)).into_iter().collect(),
+        _ => vec![],
+    }
+}
+
+impl Device for SunSpecDevice {
+    fn connect(&mut self) -> BoxFuture<'_, io::Result<Context>> {
+        async move {
+            let addr = parse_host(&self.host_port)?;
+            let mut ctx = connect_modbus(addr, self.slave, self.modbus.connect_timeout).await?;
+            self.models = self.discover_models(&mut ctx).await?;
+            info!(
+                "<i>{}</>: SunSpec models: <b>{}</>",
+                self.name,
+                self.models.iter().map(|m| m.id.to_string()).collect::<Vec<_>>().join(", ")
+            );
+            if self.model(&MODEL_INVERTER).is_none() {
+                warn!("<i>{}</>: no supported SunSpec inverter model (101-103) found", self.name);
+            }
+            Ok(ctx)
+        }
+        .boxed()
+    }
+
+    fn read_info<'a>(&'a mut self, ctx: &'a mut Context, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>> {
+        async move {
+            let models: [ModelReader; 2] = [
+                (self.model(&[MODEL_COMMON]), &["model_name", "serial_number"], parse_info),
+                (self.model(&[MODEL_NAMEPLATE]), &["rated_power"], parse_nameplate),
+            ];
+            self.read_models(ctx, &models, quiet).await
+        }
+        .boxed()
+    }
+
+    fn read_snapshot<'a>(&'a mut self, ctx: &'a mut Context, _full: bool, quiet: bool) -> BoxFuture<'a, io::Result<Snapshot>> {
+        //all values of a model come in a single request, so everything is read in every poll
+        async move {
+            let models: [ModelReader; 2] = [
+                (self.model(&MODEL_INVERTER), INVERTER_PARAMETERS, parse_inverter_model),
+                (self.model(&MODEL_METER), METER_PARAMETERS, parse_meter_model),
+            ];
+            self.read_models(ctx, &models, quiet).await
+        }
+        .boxed()
+    }
+
+    fn read_identity<'a>(&'a mut self, ctx: &'a mut Context) -> BoxFuture<'a, Vec<DeviceIdentity>> {
+        async move {
+            let model = match self.model(&[MODEL_COMMON]) {
+                Some(model) => model,
+                None => return vec![],
+            };
+            match self.read_model(ctx, model).await {
+                Ok(data) => parse_common_model(&data).into_iter().collect(),
+                Err(e) => {
+                    warn!("<i>{}</i>: read error, SunSpec common model, error: <b>{}</>", self.name, e);
+                    vec![]
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn state(&self, params: &[Parameter]) -> Sun2000State {
+        let device_status = params.iter().find(|p| p.name == "device_status").and_then(|p| match p.value {
+            ParamKind::NumberU16(n) => n,
+            _ => None,
+        });
+        Sun2000State { device_status, ..Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(params: &[Parameter], name: &str) -> Option<ParamKind> {
+        params.iter().find(|p| p.name == name).map(|p| p.value.clone())
+    }
+
+    //model 103 block of a three phase inverter at 4.8 kW
+    #[rustfmt::skip]
+    const INVERTER_103: [u16; 50] = [
+        1234, 411, 412, 411, 0xfffe,            //A, AphA-C, A_SF -2
+        4000, 4001, 3999, 2309, 2310, 2308, 0xffff, //PPVphAB-CA, PhVphA-C, V_SF -1
+        4825, 0,                                //W, W_SF
+        5001, 0xfffe,                           //Hz, Hz_SF
+        4900, 0,                                //VA, VA_SF
+        0xff38, 0,                              //VAr -200, VAr_SF
+        9850, 0xfffe,                           //PF 98.5 %, PF_SF
+        0x0012, 0xd687, 0,                      //WH 1234567, WH_SF
+        1180, 0xfffe, 4195, 0xffff, 4950, 0,    //DCA, DCA_SF, DCV, DCV_SF, DCW, DCW_SF
+        452, 0x8000, 0x8000, 0x8000, 0xffff,    //TmpCab, TmpSnk, TmpTrns, TmpOt, Tmp_SF -1
+        4, 0,                                   //St MPPT, StVnd
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,     //Evt1, Evt2, EvtVnd1-4
+    ];
+
+    //model 203 block of a wye meter importing 2 kW
+    #[rustfmt::skip]
+    const METER_203: [u16; 105] = [
+        870, 290, 290, 290, 0xfffe,             //A, AphA-C, A_SF
+        2300, 2300, 2300, 2300, 3984, 3984, 3984, 3984, 0xffff, //PhV, PhVphA-C, PPV, PPVphAB-CA, V_SF
+        5000, 0xfffe,                           //Hz, Hz_SF
+        0xf830, 0xfd5a, 0xfd5a, 0xfd4c, 0,      //W -2000, WphA-C, W_SF
+        2010, 670, 670, 670, 0,                 //VA, VA_SF
+        0xffce, 0, 0, 0, 0,                     //VAR -50, VAR_SF
+        0xff9d, 0, 0, 0, 0xfffe,                //PF -0.99, PF_SF
+        0x0001, 0x86a0, 0, 0, 0, 0, 0, 0,       //TotWhExp 100000, TotWhExpPhA-C
+        0x0003, 0x0d40, 0, 0, 0, 0, 0, 0,       //TotWhImp 200000, TotWhImpPhA-C
+        0,                                      //TotWh_SF
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //TotVAh*
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //TotVArh*
+        0, 0,                                   //Evt
+    ];
+
+    #[test]
+    fn inverter_model_in_sun2000_gains() {
+        let params = parse_inverter_model(&INVERTER_103);
+        assert_eq!(value(&params, "active_power"), Some(ParamKind::NumberI32(Some(4825))));
+        assert_eq!(value(&params, "grid_frequency"), Some(ParamKind::NumberU16(Some(5001))));
+        assert_eq!(value(&params, "reactive_power"), Some(ParamKind::NumberI32(Some(-200))));
+        //percent to the SUN2000 gain of 1000
+        assert_eq!(value(&params, "power_factor"), Some(ParamKind::NumberI16(Some(985))));
+        //Wh to kWh with the gain of 100
+        assert_eq!(value(&params, "accumulated_yield_energy"), Some(ParamKind::NumberU32(Some(123457))));
+        assert_eq!(value(&params, "input_power"), Some(ParamKind::NumberI32(Some(4950))));
+        assert_eq!(value(&params, "internal_temperature"), Some(ParamKind::NumberI16(Some(452))));
+        assert_eq!(value(&params, "device_status"), Some(ParamKind::NumberU16(Some(0x0200))));
+    }
+
+    #[test]
+    fn inverter_model_not_implemented() {
+        let mut data = INVERTER_103;
+        data[20] = NOT_IMPLEMENTED_I16; //PF
+        data[24] = NOT_IMPLEMENTED_I16; //WH_SF
+        data[36] = 0; //unknown St
+        let params = parse_inverter_model(&data);
+        assert_eq!(value(&params, "power_factor"), None);
+        assert_eq!(value(&params, "accumulated_yield_energy"), None);
+        assert_eq!(value(&params, "device_status"), None);
+        assert_eq!(params.len(), 5);
+        assert!(parse_inverter_model(&INVERTER_103[..36]).is_empty());
+    }
+
+    #[test]
+    fn meter_model_sign_and_gains() {
+        let params = parse_meter_model(&METER_203);
+        //negative when importing, as the SUN2000 power meter
+        assert_eq!(value(&params, "power_meter_active_power"), Some(ParamKind::NumberI32(Some(-2000))));
+        assert_eq!(value(&params, "grid_exported_energy"), Some(ParamKind::NumberI32(Some(10000))));
+        assert_eq!(value(&params, "grid_accumulated_energy"), Some(ParamKind::NumberU32(Some(20000))));
+
+        let mut data = METER_203;
+        data[16] = 300; //exporting
+        data[20] = 1; //W_SF
+        let params = parse_meter_model(&data);
+        assert_eq!(value(&params, "power_meter_active_power"), Some(ParamKind::NumberI32(Some(3000))));
+        assert!(parse_meter_model(&METER_203[..52]).is_empty());
+    }
+}
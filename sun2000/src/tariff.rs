@@ -0,0 +1,232 @@
+use chrono::prelude::*;
+
+use super::options;
+use super::Result;
+
+/// Days of the week a time window applies to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TariffDays {
+    All,
+    Weekday,
+    Weekend,
+}
+
+impl TariffDays {
+    fn matches(&self, weekday: Weekday) -> bool {
+        let weekend = matches!(weekday, Weekday::Sat | Weekday::Sun);
+        match self {
+            TariffDays::All => true,
+            TariffDays::Weekday => !weekend,
+            TariffDays::Weekend => weekend,
+        }
+    }
+}
+
+/// Daily time window (local time, minutes since midnight).
+/// A window with `start > end` wraps around midnight.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TimeWindow {
+    pub days: TariffDays,
+    pub start: u32,
+    pub end: u32,
+}
+
+impl TimeWindow {
+    pub fn matches(&self, time: &DateTime<Local>) -> bool {
+        let minute = time.hour() * 60 + time.minute();
+        if self.start <= self.end {
+            self.days.matches(time.weekday()) && minute >= self.start && minute < self.end
+        } else if minute >= self.start {
+            self.days.matches(time.weekday())
+        } else {
+            //the part after midnight belongs to the window started on the previous day
+            minute < self.end && self.days.matches(time.weekday().pred())
+        }
+    }
+
+    /// Minutes from `time` until the end of the window
+    pub fn minutes_left(&self, time: &DateTime<Local>) -> u32 {
+        let minute = time.hour() * 60 + time.minute();
+        match (self.end + 24 * 60 - minute) % (24 * 60) {
+            //we are inside of a whole day window
+            0 => 24 * 60,
+            left => left,
+        }
+    }
+
+    /// Parses the `all|weekday|weekend` days and the `HH:MM-HH:MM` range
+    pub(crate) fn parse(days: &str, range: &str) -> Result<TimeWindow> {
+        let days = match days {
+            "all" => TariffDays::All,
+            "weekday" | "weekdays" => TariffDays::Weekday,
+            "weekend" | "weekends" => TariffDays::Weekend,
+            _ => return Err(format!("invalid days: {:?}", days).into()),
+        };
+        let (start, end) = range
+            .split_once('-')
+            .ok_or_else(|| format!("invalid time range: {:?}", range))?;
+        Ok(TimeWindow {
+            days,
+            start: parse_minutes(start)?,
+            end: parse_minutes(end)?,
+        })
+    }
+}
+
+/// Price valid in a time window
+#[derive(Clone, Debug, PartialEq)]
+pub struct TariffWindow {
+    pub window: TimeWindow,
+    pub price: f64,
+}
+
+/// Energy prices (per kWh) for import from and export to the grid.
+/// The first matching window wins, the flat price is used outside of all windows.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Tariff {
+    pub currency: String,
+    pub import_price: f64,
+    pub export_price: f64,
+    pub import_windows: Vec<TariffWindow>,
+    pub export_windows: Vec<TariffWindow>,
+}
+
+fn parse_minutes(s: &str) -> Result<u32> {
+    let (h, m) = s.split_once(':').ok_or_else(|| format!("invalid time: {:?}", s))?;
+    let minutes = h.trim().parse::<u32>()? * 60 + m.trim().parse::<u32>()?;
+    if minutes > 24 * 60 {
+        return Err(format!("invalid time: {:?}", s).into());
+    }
+    Ok(minutes)
+}
+
+/// Parses a comma separated list of windows, eg:
+/// `weekday 07:00-13:00 0.89, weekday 16:00-22:00 0.89, all 22:00-06:00 0.35`
+pub fn parse_tariff_windows(s: &str) -> Result<Vec<TariffWindow>> {
+    let mut res = vec![];
+    for window in s.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        let fields: Vec<&str> = window.split_whitespace().collect();
+        let (days, range, price) = match fields[..] {
+            [days, range, price] => (days, range, price),
+            [range, price] => ("all", range, price),
+            _ => return Err(format!("invalid tariff window: {:?}", window).into()),
+        };
+        res.push(TariffWindow {
+            window: TimeWindow::parse(days, range).map_err(|e| format!("{} in {:?}", e, window))?,
+            price: price.parse().map_err(|e| format!("invalid price {:?} in {:?}: {}", price, window, e))?,
+        });
+    }
+    Ok(res)
+}
+
+impl Tariff {
+    /// Builds the tariff from config options (`import_price`, `export_price`, `import_windows`,
+    /// `export_windows`, `currency`). Returns `None` when no price is configured at all.
+    pub fn from_options<F: Fn(&str) -> Option<String>>(get: F) -> Result<Option<Tariff>> {
+        //negative with dynamic tariffs
+        let price = |name: &str| options::number(&get, name, f64::MIN, f64::MAX);
+        let windows = |name: &str| -> Result<Vec<TariffWindow>> {
+            match get(name) {
+                Some(v) => parse_tariff_windows(&v).map_err(|e| format!("invalid {}: {}", name, e).into()),
+                None => Ok(vec![]),
+            }
+        };
+        let tariff = Tariff {
+            currency: get("currency").unwrap_or_default(),
+            import_price: price("import_price")?.unwrap_or_default(),
+            export_price: price("export_price")?.unwrap_or_default(),
+            import_windows: windows("import_windows")?,
+            export_windows: windows("export_windows")?,
+        };
+        if tariff == Tariff::default() {
+            return Ok(None);
+        }
+        Ok(Some(tariff))
+    }
+
+    fn price_at(windows: &[TariffWindow], flat: f64, time: &DateTime<Utc>) -> f64 {
+        let local = time.with_timezone(&Local);
+        windows
+            .iter()
+            .find(|w| w.window.matches(&local))
+            .map(|w| w.price)
+            .unwrap_or(flat)
+    }
+
+    pub fn import_price_at(&self, time: &DateTime<Utc>) -> f64 {
+        Tariff::price_at(&self.import_windows, self.import_price, time)
+    }
+
+    pub fn export_price_at(&self, time: &DateTime<Utc>) -> f64 {
+        Tariff::price_at(&self.export_windows, self.export_price, time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //2022-04-01 is a Friday
+    fn local(day: u32, h: u32, m: u32) -> DateTime<Local> {
+        Local.ymd(2022, 4, day).and_hms(h, m, 0)
+    }
+
+    #[test]
+    fn window_wraps_around_midnight() {
+        let w = TimeWindow::parse("all", "22:00-06:00").unwrap();
+        assert!(w.matches(&local(1, 22, 0)));
+        assert!(w.matches(&local(1, 23, 59)));
+        assert!(w.matches(&local(2, 0, 0)));
+        assert!(w.matches(&local(2, 5, 59)));
+        assert!(!w.matches(&local(2, 6, 0)));
+        assert!(!w.matches(&local(1, 21, 59)));
+        assert_eq!(w.minutes_left(&local(1, 23, 0)), 7 * 60);
+        assert_eq!(w.minutes_left(&local(2, 5, 30)), 30);
+    }
+
+    #[test]
+    fn window_until_midnight() {
+        let w = TimeWindow::parse("all", "18:00-24:00").unwrap();
+        assert!(w.matches(&local(1, 23, 59)));
+        assert!(!w.matches(&local(2, 0, 0)));
+        assert_eq!(w.minutes_left(&local(1, 23, 0)), 60);
+        let w = TimeWindow::parse("all", "00:00-24:00").unwrap();
+        assert!(w.matches(&local(1, 0, 0)) && w.matches(&local(1, 23, 59)));
+        assert_eq!(w.minutes_left(&local(1, 0, 0)), 24 * 60);
+        assert!(TimeWindow::parse("all", "18:00-24:01").is_err());
+    }
+
+    #[test]
+    fn window_days() {
+        let weekday = TimeWindow::parse("weekday", "22:00-06:00").unwrap();
+        let weekend = TimeWindow::parse("weekend", "22:00-06:00").unwrap();
+        //Friday night belongs to the Friday window until the morning
+        assert!(weekday.matches(&local(1, 23, 0)));
+        assert!(weekday.matches(&local(2, 3, 0)));
+        assert!(!weekend.matches(&local(2, 3, 0)));
+        //Saturday and Sunday nights
+        assert!(weekend.matches(&local(2, 23, 0)));
+        assert!(weekend.matches(&local(4, 3, 0)));
+        assert!(!weekday.matches(&local(3, 23, 0)));
+        //Sunday night belongs to the weekend, Monday night to the weekdays
+        assert!(!weekday.matches(&local(4, 3, 0)));
+        assert!(weekday.matches(&local(5, 3, 0)));
+    }
+
+    #[test]
+    fn parse_windows() {
+        let windows = parse_tariff_windows("weekday 07:00-13:00 0.89, 22:00-06:00 0.35").unwrap();
+        assert_eq!(
+            windows,
+            vec![
+                TariffWindow { window: TimeWindow { days: TariffDays::Weekday, start: 7 * 60, end: 13 * 60 }, price: 0.89 },
+                TariffWindow { window: TimeWindow { days: TariffDays::All, start: 22 * 60, end: 6 * 60 }, price: 0.35 },
+            ]
+        );
+        let e = parse_tariff_windows("weekday 07:00-13:00 0.89, all 22:00-06:00 cheap").unwrap_err().to_string();
+        assert!(e.contains("all 22:00-06:00 cheap"), "{}", e);
+        assert!(parse_tariff_windows("sometimes 07:00-13:00 0.89").is_err());
+        assert!(parse_tariff_windows("07:00 0.89").is_err());
+        assert!(parse_tariff_windows("07:00-25:00 0.89").is_err());
+    }
+}
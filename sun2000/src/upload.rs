@@ -0,0 +1,372 @@
+use chrono::prelude::*;
+use io::ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+use tokio::time::timeout;
+use tokio_modbus::client::Context;
+use tokio_modbus::prelude::*;
+
+use super::Result;
+
+pub const FILE_UPLOAD_FUNCTION: u8 = 0x41; //Huawei file upload
+const SUB_START_UPLOAD: u8 = 0x05;
+const SUB_DATA_UPLOAD: u8 = 0x06;
+const SUB_COMPLETE_UPLOAD: u8 = 0x0c;
+
+//only the optimizer files have a known layout and are parsed, the other files (eg. alarm history,
+//performance logs) are uploaded as they are into the files dump by `upload_files`
+pub const FILE_OPTIMIZER_REAL_TIME_DATA: u8 = 0x44; //optimizer real-time data, updated by the inverter every 5 min
+pub const FILE_OPTIMIZER_SYSTEM_INFO: u8 = 0x45; //optimizer addresses, positions and serial numbers
+pub const FILE_MAX_FRAMES: u32 = 4096; //sanity limit of a single upload
+pub const OPTIMIZER_UPLOAD_INTERVAL_SECS: u64 = 300; //secs between optimizer real-time data uploads
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+/// CRC-16/MODBUS of the uploaded file, as reported by the complete upload response
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xffff;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xa001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+pub fn start_upload_request(file_type: u8, custom: &[u8]) -> Vec<u8> {
+    let mut req = vec![SUB_START_UPLOAD, 1 + custom.len() as u8, file_type];
+    req.extend_from_slice(custom);
+    req
+}
+
+/// Returns the file length and the max data length of a frame
+pub fn parse_start_upload(rsp: &[u8], file_type: u8) -> io::Result<(u32, u8)> {
+    match rsp {
+        [SUB_START_UPLOAD, _, t, l0, l1, l2, l3, frame_len, ..] if *t == file_type && *frame_len > 0 => {
+            Ok((u32::from_be_bytes([*l0, *l1, *l2, *l3]), *frame_len))
+        }
+        _ => Err(invalid(format!("unexpected start upload response: {:02X?}", rsp))),
+    }
+}
+
+pub fn data_upload_request(file_type: u8, frame: u16) -> Vec<u8> {
+    let frame = frame.to_be_bytes();
+    vec![SUB_DATA_UPLOAD, 3, file_type, frame[0], frame[1]]
+}
+
+/// Returns the data of the frame
+pub fn parse_data_upload(rsp: &[u8], file_type: u8, frame: u16) -> io::Result<&[u8]> {
+    match rsp {
+        [SUB_DATA_UPLOAD, len, t, f0, f1, data @ ..]
+            if *t == file_type && u16::from_be_bytes([*f0, *f1]) == frame && *len as usize >= 3 =>
+        {
+            //the length covers the file type, the frame number and the data
+            let len = (*len as usize - 3).min(data.len());
+            Ok(&data[..len])
+        }
+        _ => Err(invalid(format!("unexpected data upload response for frame {}: {:02X?}", frame, rsp))),
+    }
+}
+
+pub fn complete_upload_request(file_type: u8) -> Vec<u8> {
+    vec![SUB_COMPLETE_UPLOAD, 1, file_type]
+}
+
+/// Returns the CRC of the whole file
+pub fn parse_complete_upload(rsp: &[u8], file_type: u8) -> io::Result<u16> {
+    match rsp {
+        [SUB_COMPLETE_UPLOAD, _, t, c0, c1, ..] if *t == file_type => Ok(u16::from_be_bytes([*c0, *c1])),
+        _ => Err(invalid(format!("unexpected complete upload response: {:02X?}", rsp))),
+    }
+}
+
+/// Number of the data frames of a file
+pub fn frame_count(file_len: u32, frame_len: u8) -> u32 {
+    let frame_len = frame_len.max(1) as u32;
+    file_len / frame_len + (file_len % frame_len != 0) as u32
+}
+
+async fn call(ctx: &mut Context, data: Vec<u8>, wait: Duration) -> io::Result<Vec<u8>> {
+    match timeout(wait, ctx.call(Request::Custom(FILE_UPLOAD_FUNCTION, data))).await {
+        Ok(Ok(Response::Custom(FILE_UPLOAD_FUNCTION, rsp))) => Ok(rsp),
+        Ok(Ok(rsp)) => Err(invalid(format!("unexpected file upload response: {:?}", rsp))),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(io::Error::new(ErrorKind::TimedOut, e)),
+    }
+}
+
+/// Uploads a file from the inverter: start, all data frames, complete with the CRC check.
+/// `wait` is the timeout of each single request.
+pub async fn upload_file(ctx: &mut Context, file_type: u8, wait: Duration) -> io::Result<Vec<u8>> {
+    let rsp = call(ctx, start_upload_request(file_type, &[]), wait).await?;
+    let (file_len, frame_len) = parse_start_upload(&rsp, file_type)?;
+    let frames = frame_count(file_len, frame_len);
+    if frames > FILE_MAX_FRAMES {
+        return Err(invalid(format!("file 0x{:02X} too long: {} bytes", file_type, file_len)));
+    }
+
+    let mut data = Vec::with_capacity(file_len as usize);
+    for frame in 0..frames as u16 {
+        let rsp = call(ctx, data_upload_request(file_type, frame), wait).await?;
+        data.extend_from_slice(parse_data_upload(&rsp, file_type, frame)?);
+    }
+    data.truncate(file_len as usize);
+
+    let rsp = call(ctx, complete_upload_request(file_type), wait).await?;
+    let crc = parse_complete_upload(&rsp, file_type)?;
+    if crc != crc16(&data) {
+        return Err(invalid(format!(
+            "file 0x{:02X} CRC mismatch: {:04X}, expected: {:04X}",
+            file_type, crc16(&data), crc
+        )));
+    }
+    Ok(data)
+}
+
+/// Parses a comma separated list of file types, eg: `0x44, 0x45`
+pub fn parse_file_types(s: &str) -> Result<Vec<u8>> {
+    let mut res = vec![];
+    for t in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let value = match t.strip_prefix("0x").or_else(|| t.strip_prefix("0X")) {
+            Some(hex) => u8::from_str_radix(hex, 16),
+            None => t.parse(),
+        };
+        res.push(value.map_err(|e| format!("invalid file type {:?}: {}", t, e))?);
+    }
+    Ok(res)
+}
+
+/// Big endian reader of the file contents
+struct FileReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> FileReader<'a> {
+    fn bytes(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        let end = self.pos + n;
+        if end > self.data.len() {
+            return Err(invalid(format!("file truncated at {} (length {})", self.pos, self.data.len())));
+        }
+        let res = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(res)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn i16(&mut self) -> io::Result<i16> {
+        Ok(self.u16()? as i16)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn text(&mut self, n: usize) -> io::Result<String> {
+        let b = self.bytes(n)?;
+        Ok(String::from_utf8_lossy(b).trim_end_matches('\0').trim().to_string())
+    }
+}
+
+/// Real-time data of a single optimizer
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OptimizerData {
+    pub address: u16,
+    /// W
+    pub output_power: f64,
+    /// V
+    pub voltage_to_ground: f64,
+    /// V
+    pub output_voltage: f64,
+    /// A
+    pub output_current: f64,
+    /// V
+    pub input_voltage: f64,
+    /// A
+    pub input_current: f64,
+    /// °C
+    pub temperature: f64,
+    pub running_status: u16,
+    /// kWh
+    pub energy_yield: f64,
+}
+
+/// Optimizer data sampled by the inverter at `time`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptimizerSample {
+    pub time: DateTime<Utc>,
+    pub optimizers: Vec<OptimizerData>,
+}
+
+/// Parses the optimizer real-time data file (0x44): a header (file version U32, data format U32,
+/// number of samples U16), then the samples (time U32, number of optimizers U16) each followed
+/// by the 22 byte optimizer records
+pub fn parse_optimizer_real_time_data(data: &[u8]) -> io::Result<Vec<OptimizerSample>> {
+    let mut r = FileReader { data, pos: 0 };
+    let _version = r.u32()?;
+    let _format = r.u32()?;
+    let samples = r.u16()?;
+    let mut res = vec![];
+    for _ in 0..samples {
+        let time = Utc.timestamp(r.u32()? as i64, 0);
+        let count = r.u16()?;
+        let mut optimizers = vec![];
+        for _ in 0..count {
+            optimizers.push(OptimizerData {
+                address: r.u16()?,
+                output_power: r.u16()? as f64 / 10.0,
+                voltage_to_ground: r.i16()? as f64 / 10.0,
+                output_voltage: r.u16()? as f64 / 10.0,
+                output_current: r.u16()? as f64 / 100.0,
+                input_voltage: r.u16()? as f64 / 10.0,
+                input_current: r.u16()? as f64 / 100.0,
+                temperature: r.i16()? as f64 / 10.0,
+                running_status: r.u16()?,
+                energy_yield: r.u32()? as f64 / 1000.0,
+            });
+        }
+        res.push(OptimizerSample { time, optimizers });
+    }
+    Ok(res)
+}
+
+/// Static information of a single optimizer
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OptimizerInfo {
+    pub address: u16,
+    /// PV string the optimizer is connected to
+    pub string: u16,
+    /// position in the string
+    pub position: u16,
+    pub serial_number: String,
+}
+
+/// Parses the optimizer system information file (0x45): a header (file version U32,
+/// number of optimizers U16), then the records (address U16, string U16, position U16,
+/// serial number STR20)
+pub fn parse_optimizer_system_info(data: &[u8]) -> io::Result<Vec<OptimizerInfo>> {
+    let mut r = FileReader { data, pos: 0 };
+    let _version = r.u32()?;
+    let count = r.u16()?;
+    let mut res = vec![];
+    for _ in 0..count {
+        res.push(OptimizerInfo {
+            address: r.u16()?,
+            string: r.u16()?,
+            position: r.u16()?,
+            serial_number: r.text(20)?,
+        });
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc16(b"123456789"), 0x4B37);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn frames() {
+        assert_eq!(frame_count(0, 200), 0);
+        assert_eq!(frame_count(200, 200), 1);
+        assert_eq!(frame_count(201, 200), 2);
+        //no overflow near the max file length
+        assert_eq!(frame_count(u32::MAX, 255), 16843009);
+    }
+
+    #[test]
+    fn upload_handshake() {
+        assert_eq!(start_upload_request(0x44, &[]), vec![0x05, 0x01, 0x44]);
+        let rsp = [0x05, 0x06, 0x44, 0x00, 0x00, 0x01, 0x2C, 0xC8];
+        assert_eq!(parse_start_upload(&rsp, 0x44).unwrap(), (300, 200));
+        assert!(parse_start_upload(&rsp, 0x45).is_err());
+        assert!(parse_start_upload(&[0x05, 0x06, 0x44, 0x00, 0x00, 0x01, 0x2C, 0x00], 0x44).is_err());
+
+        assert_eq!(data_upload_request(0x44, 0x0102), vec![0x06, 0x03, 0x44, 0x01, 0x02]);
+        let rsp = [0x06, 0x05, 0x44, 0x00, 0x01, 0xAA, 0xBB, 0xCC];
+        assert_eq!(parse_data_upload(&rsp, 0x44, 1).unwrap(), &[0xAA, 0xBB]);
+        assert!(parse_data_upload(&rsp, 0x44, 2).is_err());
+
+        assert_eq!(complete_upload_request(0x44), vec![0x0C, 0x01, 0x44]);
+        assert_eq!(parse_complete_upload(&[0x0C, 0x03, 0x44, 0x4B, 0x37], 0x44).unwrap(), 0x4B37);
+        assert!(parse_complete_upload(&[0x0C, 0x01, 0x44], 0x44).is_err());
+    }
+
+    #[test]
+    fn file_types() {
+        assert_eq!(parse_file_types("0x44, 0X45,70").unwrap(), vec![0x44, 0x45, 70]);
+        assert!(parse_file_types("0x144").is_err());
+    }
+
+    #[test]
+    fn optimizer_real_time_data() {
+        #[rustfmt::skip]
+        let data = [
+            0x00, 0x00, 0x00, 0x01, //version
+            0x00, 0x00, 0x00, 0x00, //format
+            0x00, 0x01, //samples
+            0x62, 0x6B, 0x46, 0xA0, //2022-04-29 02:00:00 UTC
+            0x00, 0x01, //optimizers
+            0x00, 0x05, //address
+            0x0B, 0xB8, //300.0 W
+            0xFF, 0x9C, //-10.0 V
+            0x01, 0x90, //40.0 V
+            0x03, 0x20, //8.00 A
+            0x01, 0x86, //39.0 V
+            0x03, 0x34, //8.20 A
+            0x01, 0x5E, //35.0 °C
+            0x00, 0x02, //status
+            0x00, 0x00, 0x30, 0x39, //12.345 kWh
+        ];
+        let samples = parse_optimizer_real_time_data(&data).unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].time, Utc.ymd(2022, 4, 29).and_hms(2, 0, 0));
+        assert_eq!(
+            samples[0].optimizers,
+            vec![OptimizerData {
+                address: 5,
+                output_power: 300.0,
+                voltage_to_ground: -10.0,
+                output_voltage: 40.0,
+                output_current: 8.0,
+                input_voltage: 39.0,
+                input_current: 8.2,
+                temperature: 35.0,
+                running_status: 2,
+                energy_yield: 12.345,
+            }]
+        );
+        //a truncated record is an error, not a panic
+        assert!(parse_optimizer_real_time_data(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn optimizer_system_info() {
+        let mut data = vec![0x00, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00, 0x05, 0x00, 0x01, 0x00, 0x03];
+        data.extend_from_slice(b"HV2070123456\0\0\0\0\0\0\0\0");
+        assert_eq!(
+            parse_optimizer_system_info(&data).unwrap(),
+            vec![OptimizerInfo {
+                address: 5,
+                string: 1,
+                position: 3,
+                serial_number: "HV2070123456".to_string(),
+            }]
+        );
+        assert!(parse_optimizer_system_info(&data[..20]).is_err());
+    }
+}